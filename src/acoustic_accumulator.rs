@@ -0,0 +1,434 @@
+use crate::acoustic_raytrace::{random_vector3, Arrival, SPEED_OF_SOUND};
+use crate::signals::fractional_delay::FractionalDelay;
+use nalgebra::Vector3;
+use rand::{random, thread_rng, Rng};
+
+/// Width of the energy histogram bins the decay of the traced arrivals is fitted to, in seconds
+const DECAY_FIT_BIN: f32 = 0.01;
+/// Length of the crossfade from the traced arrivals to the synthesized tail, in seconds
+const TAIL_CROSSFADE: f32 = 0.05;
+/// Width of the energy histogram bins that shape the Dirac sequence of the late tail, in seconds
+const ENVELOPE_BIN: f32 = 0.005;
+/// The highest density of the Dirac sequence, in arrivals per second
+const MAX_DIRAC_DENSITY: f32 = 10000.0;
+/// The number of arrival directions kept per envelope bin, for the Diracs that replace them
+const ENVELOPE_DIRECTIONS: usize = 64;
+
+/// The impulse response an arrival from a direction is convolved with in each channel
+pub(crate) type ChannelKernels = dyn Fn(&Vector3<f32>) -> Vec<Vec<f32>> + Send + Sync;
+
+/// The energy decay of a band fitted to the traced arrivals
+#[derive(Debug, Clone, Copy)]
+struct Decay {
+    /// level of the energy per second at time zero in dB, extrapolated from the fit
+    initial_level: f32,
+    /// decay rate in dB per second
+    rate: f32,
+    /// the time the traced arrivals start falling below the fitted decay
+    truncation: f32,
+}
+
+impl Decay {
+    /// Gains of the traced arrivals and of the synthesized tail at a time, an equal power
+    /// crossfade that ends at the truncation time
+    fn crossfade(&self, time: f32) -> (f32, f32) {
+        let x = ((time - self.truncation + TAIL_CROSSFADE) / TAIL_CROSSFADE).clamp(0.0, 1.0);
+        let angle = x * std::f32::consts::FRAC_PI_2;
+        (angle.cos(), angle.sin())
+    }
+
+    /// The time the fitted decay has fallen by 60 dB
+    fn end(&self) -> f32 {
+        60.0 / self.rate
+    }
+}
+
+/// Fits an exponential decay to the energy histogram of a band, after the strongest bin, of
+/// arrivals up to `latest`. `survival` gives the share of the energy the tracer still follows at
+/// a time, the energy is corrected for it where it is at least a half, and the traced arrivals
+/// count as truncated from where it falls below 0.9.
+fn fit_decay<F: Fn(f32) -> f32>(histogram: &[f32], latest: f32, survival: F) -> Option<Decay> {
+    let bins = histogram.len();
+    let time = |bin: usize| (bin as f32 + 0.5) * DECAY_FIT_BIN;
+    let peak = (0..bins).max_by(|a, b| histogram[*a].partial_cmp(&histogram[*b]).unwrap())?;
+
+    // level of the energy per second in each bin, corrected for the rays the tracer stopped following
+    let fit: Vec<(f32, f32)> = (peak + 1..bins)
+        .filter(|bin| histogram[*bin] > 0.0 && survival(time(*bin)) >= 0.5)
+        .map(|bin| (time(bin), 10.0 * f32::log10(histogram[bin] / DECAY_FIT_BIN / survival(time(bin)))))
+        .collect();
+    if fit.len() < 3 {
+        return None;
+    }
+    let n = fit.len() as f32;
+    let mean_time = fit.iter().map(|(t, _)| t).sum::<f32>() / n;
+    let mean_level = fit.iter().map(|(_, level)| level).sum::<f32>() / n;
+    let covariance: f32 = fit.iter().map(|(t, level)| (t - mean_time) * (level - mean_level)).sum();
+    let variance: f32 = fit.iter().map(|(t, _)| (t - mean_time).powi(2)).sum();
+    let rate = -covariance / variance;
+    if rate.is_nan() || rate <= 0.0 {
+        return None;
+    }
+    let initial_level = mean_level + rate * mean_time;
+
+    let truncation = (0..bins).map(time).find(|t| survival(*t) < 0.9).unwrap_or(latest).min(latest);
+    Some(Decay { initial_level, rate, truncation })
+}
+
+/// Adds `values` to the start of `target`, growing it to fit them
+fn add_values(target: &mut Vec<f32>, values: &[f32]) {
+    if target.len() < values.len() {
+        target.resize(values.len(), 0.0);
+    }
+    for (t, v) in target.iter_mut().zip(values.iter()) {
+        *t += v;
+    }
+}
+
+/// A uniform random sample of the directions of the arrivals in an envelope bin
+#[derive(Debug, Clone, Default)]
+struct DirectionSample {
+    /// the number of arrivals the directions were sampled from
+    seen: u64,
+    directions: Vec<Vector3<f32>>,
+}
+
+impl DirectionSample {
+    fn add(&mut self, direction: Vector3<f32>) {
+        self.seen += 1;
+        if self.directions.len() < ENVELOPE_DIRECTIONS {
+            self.directions.push(direction);
+        } else {
+            // reservoir sampling keeps every arrival with the same probability
+            let index = thread_rng().gen_range(0..self.seen);
+            if let Some(kept) = self.directions.get_mut(index as usize) {
+                *kept = direction;
+            }
+        }
+    }
+
+    /// Combines two samples, drawing from each in proportion to the arrivals it was sampled from
+    fn merge(&mut self, other: DirectionSample) {
+        let seen = self.seen + other.seen;
+        let count = usize::min(ENVELOPE_DIRECTIONS, self.directions.len() + other.directions.len());
+        let mut rng = thread_rng();
+        let mut own = std::mem::take(&mut self.directions).into_iter();
+        let mut others = other.directions.into_iter();
+        for _ in 0..count {
+            let direction = if rng.gen_range(0..seen) < self.seen {
+                own.next().or_else(|| others.next())
+            } else {
+                others.next().or_else(|| own.next())
+            };
+            self.directions.extend(direction);
+        }
+        self.seen = seen;
+    }
+}
+
+/// The impulse response of the arrivals added so far, as a sample buffer per band of every
+/// output channel, with the energy histograms its late tail is shaped by. It grows with the
+/// length of the impulse response but not with the number of arrivals, so ray paths can be
+/// added as they are traced instead of being stored.
+#[derive(Debug, Clone)]
+pub struct ArrivalAccumulator {
+    sample_rate: u32,
+    fractional_delay: FractionalDelay,
+    /// the sample buffer of each band of each channel
+    samples: Vec<Vec<Vec<f32>>>,
+    /// energy of the arrivals in the buffers per band, in bins of `DECAY_FIT_BIN`
+    decay_energy: Vec<Vec<f32>>,
+    /// the time from which arrivals are replaced by a Dirac sequence, `None` without one
+    dirac_start: Option<f32>,
+    /// energy per band of the replaced arrivals, in bins of `ENVELOPE_BIN` from `dirac_start`
+    envelope: Vec<Vec<f32>>,
+    envelope_directions: Vec<DirectionSample>,
+    /// the time of the latest arrival
+    latest: f32,
+}
+
+impl ArrivalAccumulator {
+    /// An empty impulse response. Arrivals from `dirac_start` on are only collected into an
+    /// energy envelope, which `add_dirac_sequence` turns into a Poisson distributed Dirac sequence.
+    pub(crate) fn new(channels: usize, bands: usize, sample_rate: u32, fractional_delay: FractionalDelay, dirac_start: Option<f32>) -> Self {
+        ArrivalAccumulator {
+            sample_rate,
+            fractional_delay,
+            samples: vec![vec![Vec::new(); bands]; channels],
+            decay_energy: vec![Vec::new(); bands],
+            // the envelope starts at a bin boundary
+            dirac_start: dirac_start.map(|start| (start / ENVELOPE_BIN).ceil() * ENVELOPE_BIN),
+            envelope: Vec::new(),
+            envelope_directions: Vec::new(),
+            latest: 0.0,
+        }
+    }
+
+    /// The number of output channels
+    pub fn channels(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Adds an arrival, convolved with the kernel of each channel
+    pub(crate) fn add(&mut self, arrival: &Arrival, kernels: &[Vec<f32>]) {
+        match self.dirac_start {
+            Some(start) if arrival.time >= start => {
+                let bin = ((arrival.time - start) / ENVELOPE_BIN) as usize;
+                if self.envelope.len() <= bin {
+                    self.envelope.resize(bin + 1, vec![0.0; self.decay_energy.len()]);
+                    self.envelope_directions.resize(bin + 1, DirectionSample::default());
+                }
+                for (e, pressure) in self.envelope[bin].iter_mut().zip(arrival.pressures.iter()) {
+                    *e += pressure * pressure;
+                }
+                self.envelope_directions[bin].add(arrival.direction);
+                self.latest = f32::max(self.latest, arrival.time);
+            }
+            _ => self.add_to_samples(arrival, kernels),
+        }
+    }
+
+    fn add_to_samples(&mut self, arrival: &Arrival, kernels: &[Vec<f32>]) {
+        let random_phase = if random() { 1.0 } else { -1.0 };
+        let position = arrival.time * (self.sample_rate as f32);
+        let rounded_sample = f32::floor(position) as usize;
+        let (offset, taps) = self.fractional_delay.taps(position - rounded_sample as f32);
+
+        for (channel, kernel) in self.samples.iter_mut().zip(kernels.iter()) {
+            for (band, pressure) in channel.iter_mut().zip(arrival.pressures.iter()) {
+                for (t, tap) in taps.iter().enumerate() {
+                    // taps before the start of the impulse response are dropped
+                    let start = match (rounded_sample + t).checked_add_signed(offset) {
+                        Some(start) => start,
+                        None => continue,
+                    };
+                    if band.len() < start + kernel.len() {
+                        band.resize(start + kernel.len(), 0.0);
+                    }
+                    for (j, k) in kernel.iter().enumerate() {
+                        band[start + j] += pressure * random_phase * k * tap;
+                    }
+                }
+            }
+        }
+
+        let bin = (arrival.time / DECAY_FIT_BIN) as usize;
+        for (histogram, pressure) in self.decay_energy.iter_mut().zip(arrival.pressures.iter()) {
+            if histogram.len() <= bin {
+                histogram.resize(bin + 1, 0.0);
+            }
+            histogram[bin] += pressure * pressure;
+        }
+        self.latest = f32::max(self.latest, arrival.time);
+    }
+
+    /// Adds the arrivals of another accumulator with the same layout, e.g. of another thread
+    pub(crate) fn merge(&mut self, other: ArrivalAccumulator) {
+        for (channel, other_channel) in self.samples.iter_mut().zip(other.samples.iter()) {
+            for (band, other_band) in channel.iter_mut().zip(other_channel.iter()) {
+                add_values(band, other_band);
+            }
+        }
+        for (histogram, other_histogram) in self.decay_energy.iter_mut().zip(other.decay_energy.iter()) {
+            add_values(histogram, other_histogram);
+        }
+        let bands = self.decay_energy.len();
+        if self.envelope.len() < other.envelope.len() {
+            self.envelope.resize(other.envelope.len(), vec![0.0; bands]);
+            self.envelope_directions.resize(other.envelope.len(), DirectionSample::default());
+        }
+        for (energy, other_energy) in self.envelope.iter_mut().zip(other.envelope.iter()) {
+            add_values(energy, other_energy);
+        }
+        for (directions, other_directions) in self.envelope_directions.iter_mut().zip(other.envelope_directions) {
+            directions.merge(other_directions);
+        }
+        self.latest = f32::max(self.latest, other.latest);
+    }
+
+    /// Replaces the arrivals from the start of the envelope with a Poisson distributed Dirac
+    /// sequence, whose density grows with the square of time like the density of reflections in
+    /// a room of `volume` m³. The Diracs in each bin of the envelope carry the energy of the
+    /// arrivals they replace, per band, and come from the directions of those arrivals.
+    pub(crate) fn add_dirac_sequence(&mut self, volume: f32, channel_kernels: &ChannelKernels) {
+        let start = match self.dirac_start {
+            Some(start) if !self.envelope.is_empty() => start,
+            _ => return,
+        };
+        let energy = std::mem::take(&mut self.envelope);
+        let directions = std::mem::take(&mut self.envelope_directions);
+        let bins = energy.len();
+        let bin_of = |time: f32| usize::min(((time - start) / ENVELOPE_BIN) as usize, bins - 1);
+
+        let mut rng = thread_rng();
+        let mut diracs: Vec<Vec<f32>> = vec![Vec::new(); bins];
+        let mut time = start;
+        loop {
+            let density = f32::min(4.0 * std::f32::consts::PI * SPEED_OF_SOUND.powi(3) * time * time / volume, MAX_DIRAC_DENSITY);
+            // exponentially distributed intervals between the Diracs
+            time -= f32::ln(1.0 - rng.gen::<f32>()) / density;
+            if time >= start + bins as f32 * ENVELOPE_BIN {
+                break;
+            }
+            diracs[bin_of(time)].push(time);
+        }
+
+        // energy of bins without a Dirac is carried over to the next bin
+        let mut carried = vec![0.0f32; self.decay_energy.len()];
+        for ((energy, directions), diracs) in energy.iter().zip(directions.iter()).zip(diracs.iter()) {
+            for (c, e) in carried.iter_mut().zip(energy.iter()) {
+                *c += e;
+            }
+            if diracs.is_empty() {
+                continue;
+            }
+            let pressures: Vec<f32> = carried.iter().map(|e| f32::sqrt(e / diracs.len() as f32)).collect();
+            for time in diracs.iter() {
+                let direction = if directions.directions.is_empty() {
+                    random_vector3()
+                } else {
+                    directions.directions[rng.gen_range(0..directions.directions.len())]
+                };
+                let arrival = Arrival { time: *time, pressures: pressures.clone(), direction };
+                self.add_to_samples(&arrival, &channel_kernels(&direction));
+            }
+            carried.iter_mut().for_each(|c| *c = 0.0);
+        }
+    }
+
+    /// Crossfades the arrivals into a synthesized diffuse tail that follows the decay fitted to
+    /// each band until it has fallen by 60 dB. The tail has one arrival per sample from random
+    /// directions. `survival` is the share of the energy the tracer still follows at a time.
+    pub(crate) fn add_decay_tail<F: Fn(f32) -> f32>(&mut self, survival: F, channel_kernels: &ChannelKernels) {
+        let decays: Vec<Option<Decay>> = self.decay_energy.iter().map(|histogram| fit_decay(histogram, self.latest, &survival)).collect();
+        if decays.iter().all(|decay| decay.is_none()) {
+            return;
+        }
+        let sample_rate = self.sample_rate as f32;
+        for channel in self.samples.iter_mut() {
+            for (band, decay) in channel.iter_mut().zip(decays.iter()) {
+                if let Some(decay) = decay {
+                    for (i, sample) in band.iter_mut().enumerate() {
+                        *sample *= decay.crossfade(i as f32 / sample_rate).0;
+                    }
+                }
+            }
+        }
+
+        let start = decays.iter().flatten().map(|decay| decay.truncation - TAIL_CROSSFADE).fold(f32::MAX, f32::min).max(0.0);
+        let end = decays.iter().flatten().map(|decay| decay.end()).fold(0.0, f32::max);
+        let first_sample = (start * sample_rate) as usize;
+        let last_sample = (end * sample_rate) as usize;
+        for sample in first_sample..last_sample {
+            let time = sample as f32 / sample_rate;
+            let pressures = decays
+                .iter()
+                .map(|decay| match decay {
+                    Some(decay) if time < decay.end() => {
+                        let energy = f32::powf(10.0, (decay.initial_level - decay.rate * time) / 10.0) / sample_rate;
+                        decay.crossfade(time).1 * energy.sqrt()
+                    }
+                    _ => 0.0,
+                })
+                .collect();
+            let direction = random_vector3();
+            self.add_to_samples(&Arrival { time, pressures, direction }, &channel_kernels(&direction));
+        }
+    }
+
+    /// The sample buffers per band of each channel, until 0.05 seconds after the latest arrival
+    /// has been convolved with a kernel of `kernel_length` samples
+    pub(crate) fn into_samples(self, kernel_length: usize) -> Vec<Vec<Vec<f32>>> {
+        let total_time = self.latest + 0.05 + kernel_length as f32 / self.sample_rate as f32;
+        let number_of_samples = f32::floor(self.sample_rate as f32 * total_time) as usize;
+        let mut samples = self.samples;
+        for band in samples.iter_mut().flatten() {
+            band.resize(number_of_samples, 0.0);
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::vector;
+
+    fn mono(_: &Vector3<f32>) -> Vec<Vec<f32>> {
+        vec![vec![1.0]]
+    }
+
+    /// Arrivals at distinct samples with pressures that differ per band
+    fn arrivals(count: usize, first: f32, interval: f32) -> Vec<Arrival> {
+        (0..count)
+            .map(|i| Arrival {
+                time: first + i as f32 * interval,
+                pressures: vec![1.0 / (i + 1) as f32, 0.5],
+                direction: vector![1.0, 0.0, 0.0],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn streamed_arrivals_match_stored_arrivals() {
+        let arrivals = arrivals(200, 0.001, 0.00123);
+        let empty = ArrivalAccumulator::new(1, 2, 44100, FractionalDelay::None, Some(0.1));
+
+        // all arrivals in one accumulator, as the stored paths are synthesized
+        let mut stored = empty.clone();
+        for arrival in arrivals.iter() {
+            stored.add(arrival, &mono(&arrival.direction));
+        }
+        // spread over accumulators that are merged, as the threads stream them
+        let mut streamed = empty.clone();
+        for chunk in arrivals.chunks(37) {
+            let mut thread = empty.clone();
+            for arrival in chunk.iter() {
+                thread.add(arrival, &mono(&arrival.direction));
+            }
+            streamed.merge(thread);
+        }
+
+        assert_eq!(streamed.latest, stored.latest);
+        let close = |a: &[f32], b: &[f32]| a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-5);
+        for (streamed, stored) in streamed.decay_energy.iter().zip(stored.decay_energy.iter()) {
+            assert!(close(streamed, stored));
+        }
+        for (streamed, stored) in streamed.envelope.iter().zip(stored.envelope.iter()) {
+            assert!(close(streamed, stored));
+        }
+        assert_eq!(streamed.envelope.len(), stored.envelope.len());
+        // the arrivals get a random sign, so only their magnitudes are compared
+        let magnitudes = |accumulator: ArrivalAccumulator| -> Vec<Vec<f32>> {
+            accumulator.into_samples(1).remove(0).iter().map(|band| band.iter().map(|x| x.abs()).collect()).collect()
+        };
+        for (streamed, stored) in magnitudes(streamed).iter().zip(magnitudes(stored).iter()) {
+            assert!(close(streamed, stored));
+        }
+    }
+
+    #[test]
+    fn dirac_sequence_keeps_the_envelope_energy() {
+        // the Diracs start at 0.1 s, a boundary of the decay fit bins the energy is counted in
+        let mut accumulator = ArrivalAccumulator::new(1, 2, 44100, FractionalDelay::None, Some(0.1));
+        for arrival in arrivals(40, 0.1003, 0.001).iter() {
+            accumulator.add(arrival, &mono(&arrival.direction));
+        }
+        let envelope = accumulator.envelope.clone();
+        assert_eq!(envelope.len(), 8);
+        assert!(accumulator.decay_energy.iter().all(|histogram| histogram.is_empty()));
+
+        // a small room, where the density of the Diracs reaches its maximum right away
+        accumulator.add_dirac_sequence(50.0, &mono);
+        for band in 0..2 {
+            let histogram = &accumulator.decay_energy[band];
+            let expected: Vec<f32> = envelope.chunks(2).map(|bins| bins[0][band] + bins[1][band]).collect();
+            let total: f32 = expected.iter().sum();
+            assert!((histogram.iter().sum::<f32>() / total - 1.0).abs() < 1e-4);
+            // each bin holds about 100 Diracs, one landing in the neighbouring bin moves 1% of its energy
+            for (energy, expected) in histogram[10..].iter().zip(expected.iter()) {
+                assert!((energy / expected - 1.0).abs() < 0.05, "{} instead of {}", energy, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use crate::acoustic_raytrace::{AcousticRaytracer, RayPath};
+use crate::geometry::Primitive;
+use crate::scene::SceneNode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+/// Version of the cache format, files of other versions are rejected
+const CACHE_VERSION: u32 = 4;
+
+/// A node the cached paths hit, to check that the paths still fit the scene they are loaded into
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedNode {
+    pub id: u32,
+    pub name: String,
+    /// the index of the node's material in the scene's material table, which the paths' hits
+    /// refer to
+    pub material_index: usize,
+    /// the names of the node's face materials, which the paths' material indexes refer to
+    pub materials: Vec<String>,
+}
+
+/// Traced ray paths with what they depend on, so an impulse response can be synthesized again
+/// after changing the absorption of materials or the output format, without tracing again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RayPathCache {
+    pub version: u32,
+    /// fingerprint of the scene's faces, see `AcousticRaytracer::geometry_fingerprint`
+    pub geometry: u64,
+    pub source: [f32; 3],
+    /// centre and radius of the receiver sphere
+    pub receiver: [f32; 4],
+    pub emitted_rays: u64,
+    pub nodes: Vec<CachedNode>,
+    pub ray_paths: Vec<RayPath>,
+}
+
+/// Whether a cache file is stored as JSON rather than in the compact binary format
+fn is_json(file_name: &str) -> bool {
+    Path::new(file_name)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+}
+
+/// The names of a node's face materials
+fn material_names(node: &SceneNode) -> Vec<String> {
+    match &node.primitive {
+        Primitive::Mesh(mesh) => mesh.material_names.clone(),
+        _ => Vec::new(),
+    }
+}
+
+impl AcousticRaytracer {
+    /// A 64 bit FNV-1a hash of the node, face and vertex positions of every face in the scene,
+    /// which changes whenever the geometry the paths were traced in changes
+    pub fn geometry_fingerprint(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut add = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+        for triangle in self.world_mesh().1.iter() {
+            add(&triangle.node.to_le_bytes());
+            add(&(triangle.face as u64).to_le_bytes());
+            for vertex in triangle.vertices.iter() {
+                for coordinate in vertex.iter() {
+                    add(&coordinate.to_le_bytes());
+                }
+            }
+        }
+        hash
+    }
+
+    /// Collects the traced paths with the nodes they hit and the scene they were traced in
+    pub fn ray_path_cache(&self) -> RayPathCache {
+        let (centre, radius) = self.receiver_sphere();
+        let node_ids: BTreeSet<u32> = self.ray_paths.iter().flat_map(|ray_path| ray_path.nodes()).collect();
+        let nodes = node_ids
+            .into_iter()
+            .filter_map(|id| self.root_node.find_child_by_id(id))
+            .map(|node| CachedNode {
+                id: node.id,
+                name: node.name.clone(),
+                material_index: node.material_index,
+                materials: material_names(node),
+            })
+            .collect();
+        RayPathCache {
+            version: CACHE_VERSION,
+            geometry: self.geometry_fingerprint(),
+            source: self.source.coords.into(),
+            receiver: [centre.x, centre.y, centre.z, radius],
+            emitted_rays: self.emitted_rays,
+            nodes,
+            ray_paths: self.ray_paths.clone(),
+        }
+    }
+
+    /// Replaces the traced paths with the ones of a cache, after checking that they were traced
+    /// in this scene with the same source and receiver
+    pub fn load_ray_path_cache(&mut self, cache: RayPathCache) -> Result<(), Box<dyn Error>> {
+        if cache.version != CACHE_VERSION {
+            return Err(format!("unsupported ray path cache version {}", cache.version).into());
+        }
+        if cache.geometry != self.geometry_fingerprint() {
+            return Err("the ray paths were traced in a different geometry".into());
+        }
+        let (centre, radius) = self.receiver_sphere();
+        if cache.source != <[f32; 3]>::from(self.source.coords) || cache.receiver != [centre.x, centre.y, centre.z, radius] {
+            return Err("the ray paths were traced with a different source or receiver".into());
+        }
+        self.update_material_table();
+        for cached in cache.nodes.iter() {
+            let node = self
+                .root_node
+                .find_child_by_id(cached.id)
+                .filter(|node| node.name == cached.name)
+                .ok_or(format!("the scene has no node {} named {}", cached.id, cached.name))?;
+            if node.materials.len() < cached.materials.len() {
+                return Err(format!("node {} has fewer materials than the ray paths refer to", cached.name).into());
+            }
+            let names = material_names(node);
+            if let Some((index, name)) = cached.materials.iter().enumerate().find(|(index, name)| names.get(*index) != Some(*name)) {
+                return Err(format!("face material {} of node {} is no longer {}", index, cached.name, name).into());
+            }
+            if node.material_index != cached.material_index {
+                return Err(format!("the materials of node {} moved in the material table since the ray paths were traced", cached.name).into());
+            }
+        }
+        self.ray_paths = cache.ray_paths;
+        self.emitted_rays = cache.emitted_rays;
+        Ok(())
+    }
+
+    /// Writes the traced paths to a file, as JSON if its extension is .json and in a compact
+    /// binary format otherwise
+    pub fn save_ray_paths(&self, file_name: &str) -> Result<(), Box<dyn Error>> {
+        let writer = BufWriter::new(File::create(file_name)?);
+        let cache = self.ray_path_cache();
+        if is_json(file_name) {
+            serde_json::to_writer(writer, &cache)?;
+        } else {
+            bincode::serialize_into(writer, &cache)?;
+        }
+        Ok(())
+    }
+
+    /// Reads traced paths written by `save_ray_paths`, so the impulse response can be synthesized
+    /// without tracing again. The scene must have the same geometry, source and receiver; the
+    /// materials' absorption may differ, but their scattering and transmission shaped the paths.
+    pub fn load_ray_paths(&mut self, file_name: &str) -> Result<(), Box<dyn Error>> {
+        let reader = BufReader::new(File::open(file_name)?);
+        let cache: RayPathCache = if is_json(file_name) {
+            serde_json::from_reader(reader)?
+        } else {
+            bincode::deserialize_from(reader)?
+        };
+        self.load_ray_path_cache(cache)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::acoustic_raytrace::tests::box_scene;
+    use serde_json::json;
+
+    fn arrival_times(ray_paths: &[crate::acoustic_raytrace::RayPath]) -> Vec<f32> {
+        ray_paths.iter().map(|ray_path| ray_path.get_total_time()).collect()
+    }
+
+    #[test]
+    fn saved_paths_load_into_the_same_scene() {
+        let settings = json!({"source": [1.0, 1.0, 1.5], "receiver": [4.0, 3.0, 1.5], "ray_count": 50, "max_order": 10});
+        let mut traced = box_scene([6.0, 4.0, 3.0], settings.clone());
+        traced.trace_rays();
+        for extension in ["bin", "json"] {
+            let file_name = std::env::temp_dir().join(format!("raya_cache_test_{}.{}", std::process::id(), extension));
+            let file_name = file_name.to_str().unwrap();
+            traced.save_ray_paths(file_name).unwrap();
+
+            let mut loaded = box_scene([6.0, 4.0, 3.0], settings.clone());
+            let result = loaded.load_ray_paths(file_name);
+            let moved_receiver = box_scene([6.0, 4.0, 3.0], json!({"source": [1.0, 1.0, 1.5], "receiver": [4.0, 2.0, 1.5]})).load_ray_paths(file_name);
+            let other_geometry = box_scene([6.0, 4.0, 3.5], settings.clone()).load_ray_paths(file_name);
+            std::fs::remove_file(file_name).unwrap();
+
+            result.unwrap();
+            assert_eq!(loaded.emitted_rays, traced.emitted_rays);
+            assert_eq!(arrival_times(&loaded.ray_paths), arrival_times(&traced.ray_paths));
+            assert!(moved_receiver.unwrap_err().to_string().contains("source or receiver"));
+            assert!(other_geometry.unwrap_err().to_string().contains("different geometry"));
+        }
+    }
+}
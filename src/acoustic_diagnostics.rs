@@ -0,0 +1,253 @@
+use crate::acoustic_raytrace::{AcousticRaytracer, random_vector3};
+use crate::geometry::{Mesh, MeshDiagnostics, MeshEdge, Ray};
+use crate::scene::WorldTriangle;
+use nalgebra::Point3;
+use rand::random;
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// Rays leaving without passing a face closer than this (in meters) are reported as one leak
+const LEAK_CELL_SIZE: f32 = 1.0;
+/// Hits closer than this to a ray's origin are the surface the ray starts from
+const HIT_EPSILON: f32 = 0.0001;
+
+/// The diagnostics of all faces in the scene, joined into one mesh in scene coordinates
+#[derive(Debug, Clone)]
+pub struct SceneDiagnostics {
+    /// the scene's faces, which the diagnostics' face and vertex indexes refer to
+    pub mesh: Mesh,
+    /// the node and face each face of `mesh` comes from
+    pub triangles: Vec<WorldTriangle>,
+    pub diagnostics: MeshDiagnostics,
+}
+
+/// Why rays escape the model at a leak
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeakCause {
+    /// rays pass through the back of a face, whose normal points out of the model
+    BackFace { node: u32, face: usize },
+    /// rays pass through an opening in the surface, boundary edges connected to each other
+    /// form one opening
+    Gap { opening: usize },
+    /// rays leave without passing near any face, e.g. when the source is outside the model
+    Open,
+}
+
+/// A place where rays escape the model
+#[derive(Debug, Clone, Copy)]
+pub struct Leak {
+    /// the mean point where the rays pass the model's surface
+    pub position: Point3<f32>,
+    /// the number of rays escaping here
+    pub rays: u64,
+    pub cause: LeakCause,
+}
+
+/// The result of a quick trace that counts the rays escaping the model
+#[derive(Debug, Clone)]
+pub struct LeakProbe {
+    pub rays: u64,
+    pub escaped: u64,
+    /// the leaks, the ones most rays escape through first
+    pub leaks: Vec<Leak>,
+}
+
+impl LeakProbe {
+    /// The share of the probe's rays that escaped the model
+    pub fn escaped_share(&self) -> f32 {
+        if self.rays == 0 { 0.0 } else { self.escaped as f32 / self.rays as f32 }
+    }
+}
+
+/// The distance along a ray to a triangle, hit from either side
+fn two_sided_hit(ray: &Ray, triangle: &WorldTriangle) -> Option<f32> {
+    let [v0, v1, v2] = triangle.vertices;
+    let (edge1, edge2) = (v1 - v0, v2 - v0);
+    let p = ray.dir.cross(&edge2);
+    let determinant = edge1.dot(&p);
+    if determinant.abs() < 1e-10 {
+        return None;
+    }
+    let s = ray.src - v0;
+    let u = s.dot(&p) / determinant;
+    let q = s.cross(&edge1);
+    let v = ray.dir.dot(&q) / determinant;
+    let t = edge2.dot(&q) / determinant;
+    if u < 0.0 || v < 0.0 || u + v > 1.0 || t <= HIT_EPSILON {
+        return None;
+    }
+    Some(t)
+}
+
+/// Numbers the openings of a surface: edges sharing a vertex belong to the same opening
+fn openings(boundary_edges: &[MeshEdge]) -> Vec<usize> {
+    let mut parent: Vec<usize> = (0..boundary_edges.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    let mut edge_at_vertex: HashMap<usize, usize> = HashMap::new();
+    for (index, edge) in boundary_edges.iter().enumerate() {
+        for vertex in edge.vertices.iter() {
+            if let Some(other) = edge_at_vertex.insert(*vertex, index) {
+                let (a, b) = (root(&mut parent, index), root(&mut parent, other));
+                parent[a] = b;
+            }
+        }
+    }
+    // number the openings in the order they are first found
+    let mut numbers: HashMap<usize, usize> = HashMap::new();
+    (0..boundary_edges.len())
+        .map(|index| {
+            let r = root(&mut parent, index);
+            let next = numbers.len();
+            *numbers.entry(r).or_insert(next)
+        })
+        .collect()
+}
+
+/// The point of a segment closest to a ray and its distance from the ray
+fn closest_point_to_ray(ray: &Ray, start: Point3<f32>, end: Point3<f32>) -> (Point3<f32>, f32) {
+    let distance = |point: Point3<f32>| {
+        let t = (point - ray.src).dot(&ray.dir).max(0.0);
+        (ray.src + ray.dir * t - point).magnitude()
+    };
+    // the distance is convex along the segment, so a ternary search finds its minimum
+    let (mut low, mut high) = (0.0f32, 1.0f32);
+    for _ in 0..40 {
+        let a = low + (high - low) / 3.0;
+        let b = high - (high - low) / 3.0;
+        if distance(start + (end - start) * a) < distance(start + (end - start) * b) {
+            high = b;
+        } else {
+            low = a;
+        }
+    }
+    let point = start + (end - start) * ((low + high) / 2.0);
+    (point, distance(point))
+}
+
+impl AcousticRaytracer {
+    /// Joins the faces of all meshes except the receiver into one mesh in scene coordinates,
+    /// with the node and face of each of its faces
+    pub fn world_mesh(&self) -> (Mesh, Vec<WorldTriangle>) {
+        let triangles: Vec<WorldTriangle> = self.root_node
+            .world_triangles()
+            .into_iter()
+            .filter(|triangle| triangle.node != self.receiver)
+            .collect();
+        let mesh = Mesh::new(
+            triangles.iter().flat_map(|triangle| triangle.vertices.iter().map(|v| v.coords)).collect(),
+            (0..triangles.len()).map(|i| [3 * i, 3 * i + 1, 3 * i + 2]).collect(),
+        );
+        (mesh, triangles)
+    }
+
+    /// Checks the scene's geometry as a whole, so edges shared by faces of different nodes are
+    /// joined
+    pub fn geometry_diagnostics(&self) -> SceneDiagnostics {
+        let (mesh, triangles) = self.world_mesh();
+        let diagnostics = mesh.diagnostics();
+        SceneDiagnostics { mesh, triangles, diagnostics }
+    }
+
+    /// Traces rays from the source with up to `max_order` reflections and counts the ones that
+    /// leave the model, grouped by where they pass its surface
+    pub fn probe_leaks(&mut self, rays: u64) -> LeakProbe {
+        // the scene may have been changed since it was last compiled
+        self.compile_scene();
+        let scene = self.geometry_diagnostics();
+        let boundary_edges: Vec<(Point3<f32>, Point3<f32>, usize)> = scene
+            .diagnostics
+            .boundary_edges
+            .iter()
+            .zip(openings(&scene.diagnostics.boundary_edges))
+            .map(|(edge, opening)| {
+                let vertex = |i: usize| Point3::from(scene.mesh.vertices[edge.vertices[i]]);
+                (vertex(0), vertex(1), opening)
+            })
+            .collect();
+
+        let escapes: Vec<(Point3<f32>, LeakCause)> = (0..rays)
+            .into_par_iter()
+            .filter_map(|_| {
+                let ray = self.probe_ray()?;
+                Some(self.locate_leak(&ray, &scene.triangles, &boundary_edges))
+            })
+            .collect();
+
+        // group the escapes by cause and position
+        // the leaks' positions hold the sum of their escape points until they are averaged
+        let mut groups: HashMap<(u32, usize, usize, [i64; 3]), Leak> = HashMap::new();
+        for (position, cause) in escapes.iter() {
+            let cell = [
+                (position.x / LEAK_CELL_SIZE).floor() as i64,
+                (position.y / LEAK_CELL_SIZE).floor() as i64,
+                (position.z / LEAK_CELL_SIZE).floor() as i64,
+            ];
+            let key = match cause {
+                LeakCause::BackFace { node, face } => (0, *node as usize, *face, [0; 3]),
+                LeakCause::Gap { opening } => (1, *opening, 0, [0; 3]),
+                LeakCause::Open => (2, 0, 0, cell),
+            };
+            let leak = groups.entry(key).or_insert(Leak { position: Point3::origin(), rays: 0, cause: *cause });
+            leak.position += position.coords;
+            leak.rays += 1;
+        }
+        let mut leaks: Vec<Leak> = groups
+            .into_values()
+            .map(|leak| Leak { position: leak.position / leak.rays as f32, ..leak })
+            .collect();
+        leaks.sort_by_key(|leak| std::cmp::Reverse(leak.rays));
+
+        LeakProbe { rays, escaped: escapes.len() as u64, leaks }
+    }
+
+    /// Follows a ray from the source, returning the ray it leaves the model on if it escapes
+    fn probe_ray(&self) -> Option<Ray> {
+        let mut ray = Ray::new(self.source, random_vector3());
+        let mut order = 0_u32;
+        while order < self.max_order {
+            let intersection = match self.flat_scene.intersects(&ray) {
+                Some(intersection) => intersection,
+                None => return Some(ray),
+            };
+            ray.src = intersection.point;
+            // rays pass through the receiver
+            if intersection.node == self.receiver {
+                continue;
+            }
+            ray.dir = ray.dir - intersection.normal.scale(ray.dir.dot(&intersection.normal) * 2.0);
+            ray.dir.normalize_mut();
+            if random::<f32>() <= self.material_table.materials[intersection.material_index].scattering {
+                ray.dir = random_vector3();
+                if intersection.normal.dot(&ray.dir) < 0.0 {
+                    ray.dir.scale_mut(-1.0);
+                }
+            }
+            order += 1;
+        }
+        None
+    }
+
+    /// Finds where an escaping ray leaves the model: the first face it passes from behind, or
+    /// else the closest boundary edge
+    fn locate_leak(&self, ray: &Ray, triangles: &[WorldTriangle], boundary_edges: &[(Point3<f32>, Point3<f32>, usize)]) -> (Point3<f32>, LeakCause) {
+        let back_face = triangles
+            .iter()
+            .filter_map(|triangle| two_sided_hit(ray, triangle).map(|t| (t, triangle)))
+            .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        if let Some((t, triangle)) = back_face {
+            return (ray.src + ray.dir * t, LeakCause::BackFace { node: triangle.node, face: triangle.face });
+        }
+        boundary_edges
+            .iter()
+            .map(|(start, end, opening)| (closest_point_to_ray(ray, *start, *end), *opening))
+            .min_by(|a, b| (a.0).1.partial_cmp(&(b.0).1).unwrap())
+            .map(|((point, _), opening)| (point, LeakCause::Gap { opening }))
+            .unwrap_or((ray.src, LeakCause::Open))
+    }
+}
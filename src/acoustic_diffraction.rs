@@ -0,0 +1,453 @@
+use crate::acoustic_raytrace::{AcousticRaytracer, RayPath, SPEED_OF_SOUND};
+use crate::scene::NonRefIntersection;
+use nalgebra::{Point3, Vector3};
+use rustfft::num_complex::Complex;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// Faces meeting at an open angle below this (in radians, measured through the air) are
+/// considered coplanar or concave and do not diffract
+const MIN_OPEN_ANGLE: f32 = std::f32::consts::PI + 0.01;
+/// Distance the end points of the diffraction point search stay away from the edge's ends
+const EDGE_MARGIN: f32 = 0.001;
+/// Offset from an edge into the open region used for visibility tests
+const VISIBILITY_OFFSET: f32 = 0.001;
+
+/// A convex or free-standing edge of a mesh, in scene coordinates
+#[derive(Debug, Clone, Copy)]
+pub struct DiffractingEdge {
+    pub start: Point3<f32>,
+    pub end: Point3<f32>,
+    /// unit vector perpendicular to the edge pointing into the reference face
+    pub face_direction: Vector3<f32>,
+    /// normal of the reference face, pointing into the open region
+    pub face_normal: Vector3<f32>,
+    /// angle of the open (air) region of the wedge in radians, 2π for a thin screen
+    pub open_angle: f32,
+    pub node: u32,
+}
+
+/// The geometry of a diffraction along a path, needed to evaluate the UTD coefficient per frequency
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Diffraction {
+    /// the index of the diffraction point in the path
+    pub index: usize,
+    pub open_angle: f32,
+    /// angle of the incoming path, measured from the reference face through the open region
+    pub incident_angle: f32,
+    /// angle of the outgoing path, measured from the reference face through the open region
+    pub diffracted_angle: f32,
+    /// angle between the incoming path and the edge
+    pub skew_angle: f32,
+    /// length of the path from the source to the edge
+    pub incoming_length: f32,
+    /// length of the segment from the edge to the next point
+    pub outgoing_length: f32,
+}
+
+impl DiffractingEdge {
+    fn direction(&self) -> Vector3<f32> {
+        (self.end - self.start).normalize()
+    }
+
+    fn point(&self, t: f32) -> Point3<f32> {
+        self.start + (self.end - self.start) * t
+    }
+
+    /// The angle of a point around the edge, measured from the reference face through the open region
+    fn angle(&self, point: &Point3<f32>) -> f32 {
+        let v = point - self.start;
+        let angle = f32::atan2(v.dot(&self.face_normal), v.dot(&self.face_direction));
+        if angle < 0.0 { angle + 2.0 * std::f32::consts::PI } else { angle }
+    }
+
+    /// A point next to the edge, slightly inside the open region
+    fn offset_point(&self, t: f32) -> Point3<f32> {
+        let half = self.open_angle / 2.0;
+        let bisector = self.face_direction * f32::cos(half) + self.face_normal * f32::sin(half);
+        self.point(t) + bisector * VISIBILITY_OFFSET
+    }
+
+    /// Whether a point is inside the open region of the wedge
+    fn sees(&self, point: &Point3<f32>) -> bool {
+        let angle = self.angle(point);
+        angle > 0.0 && angle < self.open_angle
+    }
+
+    fn diffraction(&self, index: usize, previous: &Point3<f32>, point: &Point3<f32>, next: &Point3<f32>, incoming_length: f32) -> Diffraction {
+        let incoming = point - previous;
+        Diffraction {
+            index,
+            open_angle: self.open_angle,
+            incident_angle: self.angle(previous),
+            diffracted_angle: self.angle(next),
+            skew_angle: f32::acos((incoming.dot(&self.direction()) / incoming.magnitude()).abs().min(1.0)),
+            incoming_length,
+            outgoing_length: (next - point).magnitude(),
+        }
+    }
+}
+
+/// Fresnel integrals C(x) and S(x)
+fn fresnel(x: f64) -> (f64, f64) {
+    const EPS: f64 = 1e-12;
+    const MAX_ITERATIONS: usize = 100;
+    const FP_MIN: f64 = 1e-300;
+    let ax = x.abs();
+    let (c, s) = if ax < FP_MIN.sqrt() {
+        (ax, 0.0)
+    } else if ax <= 1.5 {
+        // power series
+        let fact = PI / 2.0 * ax * ax;
+        let (mut sum, mut sum_s, mut sum_c) = (0.0, 0.0, ax);
+        let mut sign = 1.0;
+        let mut odd = true;
+        let mut term = ax;
+        let mut n = 3.0;
+        for k in 1..MAX_ITERATIONS {
+            term *= fact / k as f64;
+            sum += sign * term / n;
+            let test = sum.abs() * EPS;
+            if odd {
+                sign = -sign;
+                sum_s = sum;
+                sum = sum_c;
+            } else {
+                sum_c = sum;
+                sum = sum_s;
+            }
+            if term < test {
+                break;
+            }
+            odd = !odd;
+            n += 2.0;
+        }
+        (sum_c, sum_s)
+    } else {
+        // continued fraction (modified Lentz's method)
+        let pix2 = PI * ax * ax;
+        let mut b = Complex::new(1.0, -pix2);
+        let mut cc = Complex::new(1.0 / FP_MIN, 0.0);
+        let mut d = Complex::new(1.0, 0.0) / b;
+        let mut h = d;
+        let mut n = -1.0;
+        for _ in 2..MAX_ITERATIONS {
+            n += 2.0;
+            let a = -n * (n + 1.0);
+            b += 4.0;
+            d = Complex::new(1.0, 0.0) / (d * a + b);
+            cc = b + Complex::new(a, 0.0) / cc;
+            let del = cc * d;
+            h *= del;
+            if (del.re - 1.0).abs() + del.im.abs() < EPS {
+                break;
+            }
+        }
+        h *= Complex::new(ax, -ax);
+        let cs = Complex::new(0.5, 0.5) * (Complex::new(1.0, 0.0) - Complex::new(f64::cos(0.5 * pix2), f64::sin(0.5 * pix2)) * h);
+        (cs.re, cs.im)
+    };
+    if x < 0.0 { (-c, -s) } else { (c, s) }
+}
+
+/// The UTD transition function F(X) = 2j√X e^(jX) ∫_√X^∞ e^(-jτ²) dτ
+fn transition_function(x: f64) -> Complex<f64> {
+    if x <= 0.0 {
+        return Complex::new(0.0, 0.0);
+    }
+    let (c, s) = fresnel(f64::sqrt(2.0 * x / PI));
+    let integral = Complex::new(0.5 - c, -(0.5 - s)) * f64::sqrt(PI / 2.0);
+    Complex::new(0.0, 2.0 * x.sqrt()) * Complex::new(f64::cos(x), f64::sin(x)) * integral
+}
+
+/// cot((π ± β) / 2n) F(kL a±(β)) for one of the four terms of the UTD coefficient
+fn utd_term(n: f64, beta: f64, sign: f64, kl: f64) -> Complex<f64> {
+    let mut beta = beta;
+    // the term is finite on the shadow and reflection boundaries, where the cotangent is
+    // singular, so evaluate it slightly off the boundary
+    if f64::sin((PI + sign * beta) / (2.0 * n)).abs() < 1e-6 {
+        beta += 1e-4;
+    }
+    // the integer that most nearly satisfies 2πnN - β = ±π
+    let big_n = ((beta + sign * PI) / (2.0 * PI * n)).round();
+    let a = 2.0 * f64::powi(f64::cos((2.0 * PI * n * big_n - beta) / 2.0), 2);
+    let angle = (PI + sign * beta) / (2.0 * n);
+    transition_function(kl * a) * (angle.cos() / angle.sin())
+}
+
+impl Diffraction {
+    /// The energy factor of the diffraction at a frequency, combining the squared magnitude of the
+    /// UTD diffraction coefficient for a rigid wedge with the spreading of the diffracted wave
+    pub fn energy_factor(&self, frequency: f32) -> f32 {
+        let k = 2.0 * PI * frequency as f64 / SPEED_OF_SOUND as f64;
+        let n = self.open_angle as f64 / PI;
+        let rho = self.incoming_length as f64;
+        let s = self.outgoing_length as f64;
+        let sin_beta = f64::max(1e-3, (self.skew_angle as f64).sin());
+        let kl = k * rho * s * sin_beta * sin_beta / (rho + s);
+        let difference = (self.diffracted_angle - self.incident_angle) as f64;
+        let sum = (self.diffracted_angle + self.incident_angle) as f64;
+
+        let terms = utd_term(n, difference, 1.0, kl)
+            + utd_term(n, difference, -1.0, kl)
+            + utd_term(n, sum, 1.0, kl)
+            + utd_term(n, sum, -1.0, kl);
+        let coefficient = terms / (2.0 * n * f64::sqrt(2.0 * PI * k) * sin_beta);
+        let spreading = rho / (s * (rho + s));
+        (coefficient.norm_sqr() * spreading) as f32
+    }
+}
+
+/// Finds the parameter in [0, 1] minimizing a unimodal function with a golden-section search
+fn golden_section<F: Fn(f32) -> f32>(f: F) -> f32 {
+    let ratio = (f32::sqrt(5.0) - 1.0) / 2.0;
+    let (mut a, mut b) = (0.0_f32, 1.0_f32);
+    for _ in 0..40 {
+        let c = b - ratio * (b - a);
+        let d = a + ratio * (b - a);
+        if f(c) < f(d) { b = d; } else { a = c; }
+    }
+    (a + b) / 2.0
+}
+
+fn edge_intersection(edge: &DiffractingEdge, point: Point3<f32>, incoming: &Point3<f32>, material_index: usize) -> NonRefIntersection {
+    NonRefIntersection {
+        t_value: (point - incoming).magnitude(),
+        point,
+        node: edge.node,
+        normal: edge.face_normal,
+        u_value: 0.0,
+        v_value: 0.0,
+        material: None,
+        material_index,
+    }
+}
+
+impl AcousticRaytracer {
+    /// Finds the edges of the scene that diffract sound: edges between two faces that meet at a
+    /// convex angle, and free edges of single-sided surfaces such as thin barriers. Faces of
+    /// different nodes that share an edge (e.g. a floor and a wall) are joined.
+    pub fn diffracting_edges(&self) -> Vec<DiffractingEdge> {
+        // one mesh of all faces in scene coordinates, so edges are shared across nodes
+        let (mesh, triangles) = self.world_mesh();
+        let welded = mesh.welded_vertices();
+
+        let mut diffracting_edges = Vec::new();
+        for edge in mesh.edges() {
+            if edge.faces.len() > 2 {
+                continue;
+            }
+            let start = Point3::from(mesh.vertices[edge.vertices[0]]);
+            let end = Point3::from(mesh.vertices[edge.vertices[1]]);
+            let direction = (end - start).normalize();
+
+            // the unit vector from the edge into a face and the face's normal
+            let face_frame = |face_index: usize| {
+                let face = mesh.faces[face_index];
+                let opposite = face.iter().find(|i| !edge.vertices.contains(&welded[**i])).unwrap_or(&face[0]);
+                let v = mesh.vertices[*opposite] - start.coords;
+                let into_face = (v - direction * v.dot(&direction)).normalize();
+                (into_face, triangles[face_index].normal())
+            };
+
+            let (face_direction, face_normal) = face_frame(edge.faces[0]);
+            let open_angle = if edge.faces.len() == 1 {
+                2.0 * std::f32::consts::PI
+            } else {
+                let (other_direction, _) = face_frame(edge.faces[1]);
+                let angle = f32::atan2(other_direction.dot(&face_normal), other_direction.dot(&face_direction));
+                if angle < 0.0 { angle + 2.0 * std::f32::consts::PI } else { angle }
+            };
+            if open_angle < MIN_OPEN_ANGLE || face_direction.iter().any(|x| x.is_nan()) {
+                continue;
+            }
+            diffracting_edges.push(DiffractingEdge {
+                start,
+                end,
+                face_direction,
+                face_normal,
+                open_angle,
+                node: triangles[edge.faces[0]].node,
+            });
+        }
+        diffracting_edges
+    }
+
+    /// Builds a diffracted path through a sequence of edges, or `None` if the path is not valid:
+    /// the diffraction points must lie inside the edges and every segment must be unobstructed
+    fn diffraction_path(&self, edges: &[&DiffractingEdge], parameters: &[f32], receiver: Point3<f32>, weight: f32) -> Option<RayPath> {
+        if parameters.iter().any(|t| *t <= EDGE_MARGIN || *t >= 1.0 - EDGE_MARGIN) {
+            return None;
+        }
+        let mut points = vec![self.source];
+        points.extend(edges.iter().zip(parameters.iter()).map(|(edge, t)| edge.point(*t)));
+        points.push(receiver);
+
+        let mut path = Vec::new();
+        let mut diffractions = Vec::new();
+        let mut length = 0_f32;
+        for (i, edge) in edges.iter().enumerate() {
+            let (previous, point, next) = (points[i], points[i + 1], points[i + 2]);
+            if !edge.sees(&previous) || !edge.sees(&next) {
+                return None;
+            }
+            let offset = edge.offset_point(parameters[i]);
+            if !self.is_visible(previous, offset) || !self.is_visible(offset, next) {
+                return None;
+            }
+            length += (point - previous).magnitude();
+            diffractions.push(edge.diffraction(i, &previous, &point, &next, length));
+            let material_index = self.material_table.index(edge.node, None).expect("edge node exists in scene");
+            path.push(edge_intersection(edge, point, &previous, material_index));
+        }
+        path.push(NonRefIntersection {
+            t_value: (receiver - points[points.len() - 2]).magnitude(),
+            point: receiver,
+            node: self.receiver,
+            normal: Vector3::zeros(),
+            u_value: 0.0,
+            v_value: 0.0,
+            material: None,
+            material_index: self.material_table.index(self.receiver, None).expect("receiver exists in scene"),
+        });
+        // the incident intensity at the first edge falls off with the square of its distance
+        let weight = weight / (diffractions[0].incoming_length * diffractions[0].incoming_length);
+        Some(RayPath::diffracted(self.source, path, diffractions, weight))
+    }
+
+    /// Calculates the first (and, for `order` 2, second) order edge-diffracted paths from the
+    /// source to the receiver using the uniform theory of diffraction (UTD)
+    pub fn calculate_diffraction_paths(&mut self, order: u32) -> Vec<RayPath> {
+        // the scene may have been changed since it was last compiled
+        self.compile_scene();
+        let edges = self.diffracting_edges();
+        let (receiver, radius) = self.receiver_sphere();
+        // a ray-traced arrival of weight 1 carries 1 / (number of emitted rays) of the source's
+        // energy, and the receiver intercepts πr² / 4π of a point source's energy at unit distance
+        let weight = u64::max(1, self.emitted_rays) as f32 * radius * radius / 4.0;
+        let source = self.source;
+        let mut paths = Vec::new();
+
+        if order >= 1 {
+            for edge in edges.iter() {
+                let t = golden_section(|t| (edge.point(t) - source).magnitude() + (receiver - edge.point(t)).magnitude());
+                if let Some(path) = self.diffraction_path(&[edge], &[t], receiver, weight) {
+                    paths.push(path);
+                }
+            }
+        }
+        if order >= 2 {
+            for (i, first) in edges.iter().enumerate() {
+                for (j, second) in edges.iter().enumerate() {
+                    if i == j {
+                        continue;
+                    }
+                    // minimize the path length by alternating between the two edges
+                    let (mut t1, mut t2) = (0.5_f32, 0.5_f32);
+                    for _ in 0..8 {
+                        let p2 = second.point(t2);
+                        t1 = golden_section(|t| (first.point(t) - source).magnitude() + (p2 - first.point(t)).magnitude());
+                        let p1 = first.point(t1);
+                        t2 = golden_section(|t| (second.point(t) - p1).magnitude() + (receiver - second.point(t)).magnitude());
+                    }
+                    if let Some(path) = self.diffraction_path(&[first, second], &[t1, t2], receiver, weight) {
+                        paths.push(path);
+                    }
+                }
+            }
+        }
+        paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Mesh, Primitive};
+    use crate::scene::SceneNode;
+    use nalgebra::{point, vector};
+
+    #[test]
+    fn fresnel_integrals_match_tables() {
+        // both sides of the switch from the power series to the continued fraction at 1.5
+        let table = [
+            (0.5, 0.4923442, 0.0647324),
+            (1.0, 0.7798934, 0.4382591),
+            (1.4, 0.5430958, 0.7135251),
+            (1.6, 0.3654617, 0.6388877),
+            (2.0, 0.4882534, 0.3434157),
+            (3.0, 0.6057208, 0.4963130),
+        ];
+        for (x, c, s) in table.iter() {
+            let (fc, fs) = fresnel(*x);
+            assert!((fc - c).abs() < 1e-6 && (fs - s).abs() < 1e-6, "C({}) = {}, S({}) = {}", x, fc, x, fs);
+            assert_eq!(fresnel(-x), (-fc, -fs));
+        }
+        let (below, above) = (fresnel(1.5), fresnel(1.5 + 1e-9));
+        assert!((below.0 - above.0).abs() < 1e-8 && (below.1 - above.1).abs() < 1e-8);
+    }
+
+    #[test]
+    fn transition_function_tends_to_one() {
+        for x in [100.0, 1000.0, 10000.0].iter() {
+            let f = transition_function(*x);
+            // F(X) ≈ 1 + j / 2X for large X
+            assert!((f - Complex::new(1.0, 0.5 / x)).norm() < 1.0 / (x * x), "F({}) = {}", x, f);
+        }
+        // F(X) ≈ √(πX) for small X
+        let x = 1e-4;
+        assert!((transition_function(x).norm() / f64::sqrt(PI * x) - 1.0).abs() < 0.05);
+        assert_eq!(transition_function(0.0), Complex::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn half_plane_matches_keller() {
+        let (incident, diffracted) = (PI / 3.0, 1.5 * PI);
+        let (rho, s, frequency) = (20.0, 20.0, 8000.0);
+        let diffraction = Diffraction {
+            index: 0,
+            open_angle: 2.0 * std::f32::consts::PI,
+            incident_angle: incident as f32,
+            diffracted_angle: diffracted as f32,
+            skew_angle: std::f32::consts::FRAC_PI_2,
+            incoming_length: rho as f32,
+            outgoing_length: s as f32,
+        };
+        // away from the shadow and reflection boundaries, the coefficient of a rigid half-plane is
+        // (sec((φ - φ') / 2) + sec((φ + φ') / 2)) / (2√(2πk))
+        let k = 2.0 * PI * frequency / SPEED_OF_SOUND as f64;
+        let secants = 1.0 / f64::cos((diffracted - incident) / 2.0) + 1.0 / f64::cos((diffracted + incident) / 2.0);
+        let expected = secants * secants / (8.0 * PI * k) * rho / (s * (rho + s));
+        let energy = diffraction.energy_factor(frequency as f32) as f64;
+        assert!((energy / expected - 1.0).abs() < 0.02, "energy factor {}, expected {}", energy, expected);
+        // lower frequencies bend further into the shadow
+        assert!(diffraction.energy_factor(125.0) > diffraction.energy_factor(1000.0));
+    }
+
+    #[test]
+    fn thin_screen_diffracts_around_its_edges() {
+        // a screen in the plane x = 0, 10 m wide and 2 m high, between the source and the receiver
+        let mut root = SceneNode::new(0, "root".to_string());
+        let mut screen = SceneNode::new(1, "screen".to_string());
+        let vertices = vec![vector![0.0, -5.0, 0.0], vector![0.0, 5.0, 0.0], vector![0.0, 5.0, 2.0], vector![0.0, -5.0, 2.0]];
+        screen.primitive = Primitive::Mesh(Mesh::new(vertices, vec![[0, 1, 2], [0, 2, 3]]));
+        root.add_child(screen);
+        let mut receiver = SceneNode::new(2, "receiver".to_string());
+        receiver.primitive = Primitive::Sphere;
+        receiver.scale(0.2, 0.2, 0.2);
+        receiver.translate(3.0, 0.0, 1.0);
+        root.add_child(receiver);
+        let mut raytracer = AcousticRaytracer::new(root, point![-3.0, 0.0, 1.0], 2, 10, 0);
+
+        // the free edges diffract, the diagonal between the screen's two faces does not
+        assert_eq!(raytracer.diffracting_edges().len(), 4);
+        let mut lengths: Vec<f32> = raytracer.calculate_diffraction_paths(1).iter().map(|path| path.get_total_distance()).collect();
+        lengths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        // over the top and under the bottom edge, and around both sides
+        let expected = [2.0 * f32::sqrt(10.0), 2.0 * f32::sqrt(10.0), 2.0 * f32::sqrt(34.0), 2.0 * f32::sqrt(34.0)];
+        assert_eq!(lengths.len(), expected.len());
+        for (length, expected) in lengths.iter().zip(expected.iter()) {
+            assert!((length - expected).abs() < 1e-3, "path length {}, expected {}", length, expected);
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use crate::acoustic_raytrace::{AcousticRaytracer, SPEED_OF_SOUND};
+use crate::geometry::Ray;
+use crate::scene::{SceneNode, WorldTriangle};
+use crate::utils::attenuation::air_attenuation;
+use nalgebra::{Point3, Vector3};
+use rand::{Rng, thread_rng};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+/// Above this number of faces, linking every pair of faces takes long
+const MANY_FACES: usize = 5000;
+
+#[derive(Debug, Clone)]
+pub struct RadiositySettings {
+    /// width of the energy-time curve bins in seconds
+    pub time_step: f32,
+    /// length of the energy-time curve in seconds
+    pub duration: f32,
+    /// patches are not subdivided below this area (m²)
+    pub min_patch_area: f32,
+    /// a link is refined by subdividing one of its patches while the estimated form factor in
+    /// either direction is larger than this
+    pub refinement_threshold: f32,
+    /// links with a smaller form factor than this are dropped
+    pub min_form_factor: f32,
+    /// number of random points, with a visibility test each, used to estimate each form factor
+    pub form_factor_samples: u32,
+}
+
+impl Default for RadiositySettings {
+    fn default() -> RadiositySettings {
+        RadiositySettings {
+            time_step: 0.001,
+            duration: 2.0,
+            min_patch_area: 1.0,
+            refinement_threshold: 0.05,
+            min_form_factor: 1e-4,
+            form_factor_samples: 16,
+        }
+    }
+}
+
+/// Energy arriving at the receiver over time, per frequency band
+#[derive(Debug, Clone)]
+pub struct EnergyTimeCurve {
+    pub time_step: f32,
+    pub frequencies: Vec<f32>,
+    /// energy per band and time bin, as a fraction of the energy emitted by the source
+    pub energy: Vec<Vec<f32>>,
+}
+
+/// A patch of a surface exchanging energy with the other patches. Every face is the root of a
+/// binary tree of patches, which is only subdivided where the links need a finer resolution.
+#[derive(Debug, Clone)]
+struct Patch {
+    triangle: WorldTriangle,
+    /// index of the face the patch is part of
+    face: usize,
+    parent: Option<usize>,
+    children: Option<[usize; 2]>,
+}
+
+/// Energy transfer from a patch to another patch or the receiver
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    target: usize,
+    factor: f32,
+    distance: f32,
+}
+
+/// The patch trees of all faces, where parents always come before their children
+struct PatchTree {
+    patches: Vec<Patch>,
+    min_area: f32,
+}
+
+impl PatchTree {
+    fn new(faces: Vec<WorldTriangle>, min_area: f32) -> PatchTree {
+        let patches = faces
+            .into_iter()
+            .enumerate()
+            .map(|(face, triangle)| Patch { triangle, face, parent: None, children: None })
+            .collect();
+        PatchTree { patches, min_area }
+    }
+
+    /// The two halves of a patch, which are created the first time they are needed. Patches
+    /// no larger than the minimum area are not subdivided.
+    fn children(&mut self, index: usize) -> Option<[usize; 2]> {
+        if self.patches[index].children.is_some() {
+            return self.patches[index].children;
+        }
+        let patch = &self.patches[index];
+        if patch.triangle.area() <= self.min_area {
+            return None;
+        }
+        let halves = split(&patch.triangle);
+        let face = patch.face;
+        let first = self.patches.len();
+        for triangle in halves.iter() {
+            self.patches.push(Patch { triangle: *triangle, face, parent: Some(index), children: None });
+        }
+        self.patches[index].children = Some([first, first + 1]);
+        self.patches[index].children
+    }
+
+    /// Collects the pairs of patches linked from patch `from` to patch `to`, subdividing the
+    /// patches until the estimated form factors between them are below the threshold
+    fn refine(&mut self, from: usize, to: usize, threshold: f32, pairs: &mut Vec<(usize, usize)>) {
+        let (a, b) = (&self.patches[from].triangle, &self.patches[to].triangle);
+        if !faces_towards(a, b) || !faces_towards(b, a) {
+            return;
+        }
+        let forward = estimate_form_factor(a, b);
+        let backward = estimate_form_factor(b, a);
+        // subdivide the patch that looks larger from the other one first
+        let candidates = if forward >= backward {
+            [(forward, to), (backward, from)]
+        } else {
+            [(backward, from), (forward, to)]
+        };
+        for (factor, patch) in candidates.iter() {
+            if *factor <= threshold {
+                continue;
+            }
+            if let Some(children) = self.children(*patch) {
+                for child in children.iter() {
+                    if *patch == to {
+                        self.refine(from, *child, threshold, pairs);
+                    } else {
+                        self.refine(*child, to, threshold, pairs);
+                    }
+                }
+                return;
+            }
+        }
+        pairs.push((from, to));
+    }
+
+    /// Collects the patches of a face linked to a point (the source or the receiver),
+    /// subdividing the patches until each covers less than the threshold of the point's sphere
+    fn refine_point(&mut self, patch: usize, point: Point3<f32>, threshold: f32, patches: &mut Vec<usize>) {
+        let triangle = &self.patches[patch].triangle;
+        let to_point = point - triangle.centroid();
+        let distance = to_point.magnitude();
+        let cos_theta = triangle.normal().dot(&to_point) / distance;
+        if cos_theta <= 0.0 {
+            return;
+        }
+        if triangle.area() * cos_theta / (4.0 * PI * distance * distance) > threshold {
+            if let Some(children) = self.children(patch) {
+                for child in children.iter() {
+                    self.refine_point(*child, point, threshold, patches);
+                }
+                return;
+            }
+        }
+        patches.push(patch);
+    }
+}
+
+/// Splits a triangle in half at the middle of its longest edge
+fn split(triangle: &WorldTriangle) -> [WorldTriangle; 2] {
+    let [a, b, c] = triangle.vertices;
+    let edges = [(b - a).magnitude(), (c - b).magnitude(), (a - c).magnitude()];
+    let (p, q, r) = if edges[0] >= edges[1] && edges[0] >= edges[2] {
+        (a, b, c)
+    } else if edges[1] >= edges[2] {
+        (b, c, a)
+    } else {
+        (c, a, b)
+    };
+    let m = Point3::from((p.coords + q.coords) / 2.0);
+    [
+        WorldTriangle { vertices: [p, m, r], ..*triangle },
+        WorldTriangle { vertices: [m, q, r], ..*triangle },
+    ]
+}
+
+/// Whether some part of triangle `to` lies in front of triangle `from`
+fn faces_towards(from: &WorldTriangle, to: &WorldTriangle) -> bool {
+    let normal = from.normal();
+    to.vertices.iter().any(|vertex| normal.dot(&(vertex - from.vertices[0])) > 1e-4)
+}
+
+/// The form factor from a differential area at a point to a triangle, without occlusion, with
+/// Lambert's contour integral over the part of the triangle in front of the point
+fn point_form_factor(point: Point3<f32>, normal: &Vector3<f32>, triangle: &WorldTriangle) -> f32 {
+    if triangle.normal().dot(&(point - triangle.vertices[0])) <= 0.0 {
+        return 0.0;
+    }
+    // clip the triangle to the half space in front of the point
+    let mut polygon: Vec<Vector3<f32>> = Vec::with_capacity(4);
+    for (i, vertex) in triangle.vertices.iter().enumerate() {
+        let next = triangle.vertices[(i + 1) % 3];
+        let (r, r_next) = (vertex - point, next - point);
+        let (h, h_next) = (normal.dot(&r), normal.dot(&r_next));
+        if h >= 0.0 {
+            polygon.push(r);
+        }
+        if (h >= 0.0) != (h_next >= 0.0) {
+            polygon.push(r + (r_next - r) * (h / (h - h_next)));
+        }
+    }
+    let mut sum = 0_f32;
+    for (i, r) in polygon.iter().enumerate() {
+        let r_next = polygon[(i + 1) % polygon.len()];
+        let cross = r.cross(&r_next);
+        let length = cross.magnitude();
+        if length < 1e-12 {
+            continue;
+        }
+        let angle = f32::atan2(length, r.dot(&r_next));
+        sum += angle * normal.dot(&cross) / length;
+    }
+    f32::min(1.0, sum.abs() / (2.0 * PI))
+}
+
+/// Estimate of the form factor from one patch to another from the source's centroid, without
+/// occlusion
+fn estimate_form_factor(from: &WorldTriangle, to: &WorldTriangle) -> f32 {
+    point_form_factor(from.centroid(), &from.normal(), to)
+}
+
+fn random_point(triangle: &WorldTriangle) -> Point3<f32> {
+    let mut rng = thread_rng();
+    let r1: f32 = rng.gen::<f32>().sqrt();
+    let r2: f32 = rng.gen();
+    let [a, b, c] = triangle.vertices;
+    Point3::from(a.coords * (1.0 - r1) + b.coords * (r1 * (1.0 - r2)) + c.coords * (r1 * r2))
+}
+
+impl AcousticRaytracer {
+    /// Tests whether the straight line between two points is unobstructed in the compiled scene,
+    /// so callers compile the scene first. The receiver is transparent and never blocks the line
+    /// of sight.
+    pub(crate) fn is_visible(&self, from: Point3<f32>, to: Point3<f32>) -> bool {
+        let mut ray = Ray::new_from_points(from, to);
+        let mut remaining = (to - from).magnitude();
+        loop {
+            match self.flat_scene.intersects(&ray) {
+                None => return true,
+                Some(hit) => {
+                    let distance = (hit.point - ray.src).magnitude();
+                    if distance >= remaining - 1e-3 {
+                        return true;
+                    }
+                    if hit.node != self.receiver {
+                        return false;
+                    }
+                    remaining -= distance;
+                    ray.src = hit.point;
+                }
+            }
+        }
+    }
+
+    /// The reflection coefficients of every face per band
+    fn reflections(&self, faces: &[WorldTriangle], frequencies: &[f32]) -> Vec<Vec<f32>> {
+        let nodes: HashMap<u32, &SceneNode> = self.root_node.world_nodes().into_iter().map(|(node, _)| (node.id, node)).collect();
+        faces
+            .iter()
+            .map(|triangle| {
+                let material = nodes[&triangle.node].face_material(triangle.face);
+                frequencies
+                    .iter()
+                    .map(|f| 1.0 - material.absorption_function(f32::min(*f, 8000.0)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Estimates the form factor from one patch to another, including visibility, as the mean of
+    /// the form factors from random points of the source patch, which are visible from the points
+    /// when a random point of the target patch is
+    fn form_factor(&self, source: &WorldTriangle, target: &WorldTriangle, samples: u32) -> f32 {
+        let normal = source.normal();
+        let mut sum = 0_f32;
+        for _ in 0..samples {
+            let p = random_point(source);
+            let factor = point_form_factor(p, &normal, target);
+            if factor > 0.0 && self.is_visible(p, random_point(target)) {
+                sum += factor;
+            }
+        }
+        sum / samples as f32
+    }
+
+    /// Calculates the energy-time curve at the receiver with a time-dependent acoustic radiosity
+    /// (radiance transfer) model, where every surface reflects diffusely.
+    ///
+    /// The faces are the roots of hierarchies of patches, which are only subdivided where the
+    /// form factor between two patches is too large to treat either as uniform, so the number of
+    /// links grows with the detail of the scene rather than with the square of its area. The form
+    /// factors are estimated with visibility through the scene, links that carry almost no energy
+    /// are dropped, and the energy emitted by the source is propagated between the patches over
+    /// time, per octave band. Energy arriving at a patch is shared by all patches above and below
+    /// it in its hierarchy.
+    pub fn calculate_energy_time_curve(&mut self, settings: &RadiositySettings) -> EnergyTimeCurve {
+        // the scene may have been changed since it was last compiled
+        self.compile_scene();
+        let frequencies = self.frequencies.clone();
+        let air = air_attenuation(&frequencies, 20.0, 40.0, 101325.0);
+        let bins = f32::ceil(settings.duration / settings.time_step) as usize;
+        let delay = |distance: f32| (distance / SPEED_OF_SOUND / settings.time_step) as usize;
+        // energy factor of the air absorption (given in dB/m) over a distance
+        let air_factor = |band: usize, distance: f32| f32::powf(10.0, -air[band] * distance / 10.0);
+        let threshold = settings.refinement_threshold;
+
+        let faces = self.root_node.world_triangles();
+        let reflections = self.reflections(&faces, &frequencies);
+        let face_count = faces.len();
+        if face_count > MANY_FACES {
+            println!("warning: the radiosity solver links each of the {} faces with every other face, which is slow above {} faces, simplify the model", face_count, MANY_FACES);
+        }
+        let mut tree = PatchTree::new(faces, settings.min_patch_area);
+        let (centre, radius) = self.receiver_sphere();
+
+        let mut pairs = Vec::new();
+        let mut source_patches = Vec::new();
+        let mut receiver_patches = Vec::new();
+        for from in 0..face_count {
+            for to in 0..face_count {
+                if from != to {
+                    tree.refine(from, to, threshold, &mut pairs);
+                }
+            }
+            tree.refine_point(from, self.source, threshold, &mut source_patches);
+            tree.refine_point(from, centre, threshold, &mut receiver_patches);
+        }
+        let patches = &tree.patches;
+
+        let mut links: Vec<Vec<Link>> = vec![Vec::new(); patches.len()];
+        let estimated: Vec<(usize, Link)> = pairs
+            .into_par_iter()
+            .filter_map(|(from, to)| {
+                let (source, target) = (&patches[from].triangle, &patches[to].triangle);
+                let factor = self.form_factor(source, target, settings.form_factor_samples);
+                if factor < settings.min_form_factor {
+                    return None;
+                }
+                let distance = (target.centroid() - source.centroid()).magnitude();
+                Some((from, Link { target: to, factor, distance }))
+            })
+            .collect();
+        let link_count = estimated.len();
+        for (from, link) in estimated {
+            links[from].push(link);
+        }
+        println!("radiosity patches: {}, links: {}", patches.len(), link_count);
+
+        // fraction of the energy emitted by each patch that reaches the receiver
+        let mut receiver_links: Vec<Option<Link>> = vec![None; patches.len()];
+        for i in receiver_patches {
+            let triangle = &patches[i].triangle;
+            let point = triangle.centroid();
+            let to_receiver = centre - point;
+            let distance = to_receiver.magnitude();
+            let cos_theta = triangle.normal().dot(&to_receiver) / distance;
+            if distance <= radius || cos_theta <= 0.0 || !self.is_visible(point, centre) {
+                continue;
+            }
+            let cos_gamma = f32::sqrt(1.0 - (radius / distance) * (radius / distance));
+            receiver_links[i] = Some(Link { target: 0, factor: 2.0 * cos_theta * (1.0 - cos_gamma), distance });
+        }
+
+        // the energy reflected by a leaf patch is sent out through its links and the links of the
+        // patches above it, and a face cannot send out more energy than it reflects. The links
+        // treat their patches as uniform, so the totals are only bounded on average over a face.
+        // The receiver is transparent and does not take energy from the other links.
+        let mut totals: Vec<f32> = links.iter().map(|links| links.iter().map(|link| link.factor).sum()).collect();
+        let mut face_totals = vec![(0_f32, 0_f32); face_count];
+        for i in 0..patches.len() {
+            if let Some(parent) = patches[i].parent {
+                totals[i] += totals[parent];
+            }
+            if patches[i].children.is_none() {
+                let area = patches[i].triangle.area();
+                face_totals[patches[i].face].0 += area * totals[i];
+                face_totals[patches[i].face].1 += area;
+            }
+        }
+        let scales: Vec<f32> = face_totals.iter().map(|(total, area)| 1.0 / f32::max(1.0, total / area)).collect();
+
+        // fraction of the energy emitted by the source that reaches each patch
+        let source_links: Vec<(usize, Link)> = source_patches
+            .into_iter()
+            .filter_map(|i| {
+                let triangle = &patches[i].triangle;
+                let point = triangle.centroid();
+                let from_source = point - self.source;
+                let distance = from_source.magnitude();
+                let cos_theta = -triangle.normal().dot(&from_source) / distance;
+                if cos_theta <= 0.0 || !self.is_visible(self.source, point) {
+                    return None;
+                }
+                let solid_angle = triangle.area() * cos_theta / (distance * distance);
+                Some((i, Link { target: i, factor: f32::min(1.0, solid_angle / (4.0 * PI)), distance }))
+            })
+            .collect();
+
+        // the share of its parent's area each patch covers
+        let shares: Vec<f32> = patches
+            .iter()
+            .map(|patch| patch.parent.map_or(0.0, |parent| patch.triangle.area() / patches[parent].triangle.area()))
+            .collect();
+
+        // the longest delay of a link, after which the energy arriving at the patches is known
+        let horizon = 1 + links
+            .iter()
+            .flatten()
+            .chain(source_links.iter().map(|(_, link)| link))
+            .map(|link| usize::max(1, delay(link.distance)))
+            .max()
+            .unwrap_or(0);
+
+        let direct_distance = (centre - self.source).magnitude();
+        let direct = if direct_distance > radius && self.is_visible(self.source, centre) {
+            let cos_gamma = f32::sqrt(1.0 - (radius / direct_distance) * (radius / direct_distance));
+            Some((1.0 - cos_gamma) / 2.0)
+        } else {
+            None
+        };
+
+        let energy = (0..frequencies.len())
+            .into_par_iter()
+            .map(|band| {
+                let mut curve = vec![0_f32; bins];
+                // energy arriving at each patch over the next time bins, as a ring buffer
+                let mut incoming = vec![vec![0_f32; horizon]; patches.len()];
+                // energy arriving at the patches above each patch, shared by area
+                let mut above = vec![0_f32; patches.len()];
+                // energy reflected by each patch or the patches below it
+                let mut reflected = vec![0_f32; patches.len()];
+
+                if let Some(direct) = direct {
+                    let n = delay(direct_distance);
+                    if n < bins {
+                        curve[n] += direct * air_factor(band, direct_distance);
+                    }
+                }
+                for (i, link) in source_links.iter() {
+                    let n = delay(link.distance);
+                    if n < bins {
+                        incoming[*i][n % horizon] += link.factor * air_factor(band, link.distance);
+                    }
+                }
+
+                for n in 0..bins {
+                    let slot = n % horizon;
+                    // the energy arriving at a patch is spread over the leaves below it, which
+                    // reflect it, and then gathered again for the links of the patches above them
+                    for i in 0..patches.len() {
+                        above[i] = match patches[i].parent {
+                            Some(parent) => (above[parent] + incoming[parent][slot]) * shares[i],
+                            None => 0.0,
+                        };
+                        reflected[i] = match patches[i].children {
+                            Some(_) => 0.0,
+                            None => (above[i] + incoming[i][slot]) * reflections[patches[i].face][band] * scales[patches[i].face],
+                        };
+                    }
+                    for energy in incoming.iter_mut() {
+                        energy[slot] = 0.0;
+                    }
+                    for i in (0..patches.len()).rev() {
+                        if let Some(parent) = patches[i].parent {
+                            reflected[parent] += reflected[i];
+                        }
+                    }
+                    for i in 0..patches.len() {
+                        let reflected = reflected[i];
+                        if reflected <= 0.0 {
+                            continue;
+                        }
+                        if let Some(link) = receiver_links[i] {
+                            let m = n + delay(link.distance);
+                            if m < bins {
+                                curve[m] += reflected * link.factor * air_factor(band, link.distance);
+                            }
+                        }
+                        for link in links[i].iter() {
+                            let m = n + usize::max(1, delay(link.distance));
+                            if m < bins {
+                                incoming[link.target][m % horizon] += reflected * link.factor * air_factor(band, link.distance);
+                            }
+                        }
+                    }
+                }
+                curve
+            })
+            .collect();
+
+        EnergyTimeCurve {
+            time_step: settings.time_step,
+            frequencies,
+            energy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acoustic_raytrace::tests::box_scene;
+    use serde_json::json;
+
+    #[test]
+    fn box_decays_like_eyring() {
+        let material = json!({
+            "abs63": 0.2, "abs125": 0.2, "abs250": 0.2, "abs500": 0.2,
+            "abs1000": 0.2, "abs2000": 0.2, "abs4000": 0.2, "abs8000": 0.2,
+        });
+        let mut raytracer = box_scene([6.0, 4.0, 3.0], json!({
+            "source": [1.5, 1.5, 1.2],
+            "receiver": [4.0, 2.5, 1.7],
+            "materials": {"floor": material, "ceiling": material, "wall": material},
+        }));
+        raytracer.frequencies = vec![1000.0];
+        let settings = RadiositySettings {
+            time_step: 0.002,
+            duration: 1.0,
+            min_patch_area: 2.0,
+            form_factor_samples: 8,
+            ..RadiositySettings::default()
+        };
+        let energy_time_curve = raytracer.calculate_energy_time_curve(&settings);
+
+        // fit the decay of the backward integrated curve between -5 and -25 dB
+        let curve = &energy_time_curve.energy[0];
+        let mut remaining: Vec<f32> = curve.iter().rev().scan(0.0, |sum, energy| { *sum += energy; Some(*sum) }).collect();
+        remaining.reverse();
+        let levels: Vec<f32> = remaining.iter().map(|energy| 10.0 * f32::log10(energy / remaining[0])).collect();
+        let start = levels.iter().position(|level| *level < -5.0).unwrap();
+        let end = levels.iter().position(|level| *level < -25.0).unwrap();
+        let reverberation_time = 60.0 / 20.0 * (end - start) as f32 * settings.time_step;
+
+        let statistics = raytracer.room_statistics();
+        let eyring = statistics.eyring[statistics.frequencies.iter().position(|f| *f == 1000.0).unwrap()];
+        assert!((reverberation_time / eyring - 1.0).abs() < 0.1, "T20 {} s, Eyring {} s", reverberation_time, eyring);
+    }
+}
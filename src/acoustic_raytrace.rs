@@ -1,8 +1,7 @@
-use crate::geometry::{Ray, Primitive, Mesh};
-use crate::scene::{Intersect, SceneNode, NonRefIntersection, AcousticMaterial};
-use crate::scene::acoustic_material::AbsorptionData;
+use crate::geometry::{Ray, Primitive, Mesh, MeshObject, MtlMaterial, ObjFile, read_mtl, read_ply, read_stl};
+use crate::scene::{SceneNode, NonRefIntersection, AcousticMaterial, MaterialLibrary, MaterialTable, FlatScene};
 use crate::utils;
-use nalgebra::{Point3, Vector3};
+use nalgebra::{Affine3, Point3, Quaternion, UnitQuaternion, Vector3};
 use nalgebra::{point, vector};
 use pbr::ProgressBar;
 use rand::{Rng, random, thread_rng};
@@ -13,8 +12,16 @@ use std::thread;
 use std::time::Duration;
 use crate::utils::convert::{p_2_i, lp_2_p, p_2_lp, i_2_p};
 use crate::utils::attenuation::air_attenuation;
-use crate::signals::reconstruction_filter;
+use crate::signals::ambisonics;
+use crate::signals::reconstruction_filter::{BandFilters, FilterBank};
+use crate::signals::fractional_delay::FractionalDelay;
+use crate::signals::binaural::{HrirInterpolation, HrirSet};
+use crate::acoustic_radiosity::{EnergyTimeCurve, RadiositySettings};
+use crate::acoustic_diffraction::Diffraction;
+use crate::acoustic_accumulator::{ArrivalAccumulator, ChannelKernels};
+use crate::acoustic_statistics::RoomStatistics;
 use hound;
+use serde::{Deserialize, Serialize};
 use gltf::{json};
 use gltf::buffer::Data;
 use std::error::Error;
@@ -25,7 +32,22 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 
 const USE_RAYON: bool = true;
-const SPEED_OF_SOUND: f32 = 343.0;
+pub(crate) const SPEED_OF_SOUND: f32 = 343.0;
+/// The sound pressure level of the source in every band, in dB
+pub(crate) const INITIAL_SPL: f32 = 100.0;
+
+/// The channel layout of the rendered impulse response
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    /// A single omnidirectional channel
+    #[default]
+    Mono,
+    /// Ambisonic B-format (ACN channel order, SN3D normalization) of the given order
+    Ambisonic(u32),
+    /// Two channels rendered with the raytracer's HRIR set
+    Binaural(HrirInterpolation),
+}
+
 pub struct AcousticRaytracer {
     pub root_node: SceneNode,
 
@@ -33,10 +55,56 @@ pub struct AcousticRaytracer {
     pub receiver: u32,
 
     pub ray_paths: Vec<RayPath>,
+    /// Add the arrivals of the traced paths to `streamed_arrivals` instead of storing the paths,
+    /// so memory does not grow with the ray count. The output format has to be set before
+    /// tracing, and the arrivals cannot be synthesized again with other materials.
+    pub stream_arrivals: bool,
+    /// The impulse response of the arrivals streamed while tracing
+    pub streamed_arrivals: Option<ArrivalAccumulator>,
+    /// The materials of the scene's nodes, which the hits of the ray paths refer to by index
+    pub material_table: MaterialTable,
+    /// The scene in scene coordinates that rays are traced against, compiled from `root_node` by
+    /// `compile_scene`
+    pub flat_scene: FlatScene,
+    /// The energy-time curve calculated by the radiosity solver, synthesized along with the ray
+    /// paths, which should be empty as the curve already carries their energy
+    pub energy_time_curve: Option<EnergyTimeCurve>,
+    /// The number of rays sent from the source, including the ones that never reached the receiver
+    pub emitted_rays: u64,
+    /// Whether the compiled scene is closed, checked once after `compile_scene` when the decay
+    /// tail needs it
+    closed: Option<bool>,
 
     // Settings
     pub max_order: u32,
     pub ray_count: u64,
+    pub output_format: OutputFormat,
+    pub hrir_set: Option<HrirSet>,
+    /// At every reflection, test the visibility of the receiver and add the energy the scattered
+    /// part of the reflection sends towards it
+    pub diffuse_rain: bool,
+    /// Calculate an energy-time curve with the radiosity solver when rendering. The curve carries
+    /// the direct sound and all reflections, so it replaces the traced rays: `render` refuses to
+    /// combine it with a `ray_count` above 0 or with loaded or streamed paths.
+    pub radiosity: Option<RadiositySettings>,
+    /// The highest order of edge diffraction added to the traced paths, 0 disables diffraction.
+    /// Diffraction is not added to the radiosity energy-time curve.
+    pub diffraction_order: u32,
+    /// Extend the traced arrivals, which `max_order` truncates, with a diffuse tail following the
+    /// decay fitted to them, so the impulse response decays by 60 dB. Off by default.
+    pub decay_tail: bool,
+    /// Replace the arrivals after the mixing time with a Poisson distributed Dirac sequence that
+    /// carries their energy, which gives a dense tail from fewer rays
+    pub poisson_tail: bool,
+    /// The phase of the filters that band limit the arrivals in each band
+    pub filter_bank: FilterBank,
+    /// How arrivals are placed between the samples of the impulse response
+    pub fractional_delay: FractionalDelay,
+    /// The sample rate of the impulse response in Hz
+    pub sample_rate: u32,
+    /// The centre frequencies of the bands the arrivals are synthesized in, which can be changed
+    /// before synthesizing loaded ray paths
+    pub frequencies: Vec<f32>,
 }
 
 impl Default for AcousticRaytracer {
@@ -46,118 +114,139 @@ impl Default for AcousticRaytracer {
         receiver.primitive = Primitive::Sphere;
         root.add_child(receiver);
         point![0.0,0.0,0.0].coords[4];
+        let material_table = MaterialTable::new(&mut root);
+        let flat_scene = FlatScene::new(&root, 1);
         AcousticRaytracer {
             root_node: root,
             source: point![0.0, 0.0, 0.0],
             receiver: 1,
             ray_paths: Vec::new(),
+            stream_arrivals: false,
+            streamed_arrivals: None,
+            material_table,
+            flat_scene,
+            energy_time_curve: None,
+            emitted_rays: 0,
+            closed: None,
             max_order: 100,
-            ray_count: 10000
+            ray_count: 10000,
+            output_format: OutputFormat::default(),
+            hrir_set: None,
+            diffuse_rain: false,
+            radiosity: None,
+            diffraction_order: 0,
+            decay_tail: false,
+            poisson_tail: false,
+            filter_bank: FilterBank::default(),
+            fractional_delay: FractionalDelay::default(),
+            sample_rate: 44100,
+            frequencies: utils::bands::octave(63.0, 8000.0),
         }
     }
 }
 
-fn get_node_from_mesh(mesh: &gltf::Mesh, buffers: &Vec<Data>) -> Result<SceneNode, Box<dyn Error>> {
+/// The settings of an OBJ, STL or PLY scene, read from a JSON file next to the model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SceneSettings {
+    pub source: [f32; 3],
+    pub receiver: [f32; 3],
+    #[serde(default = "SceneSettings::default_receiver_radius")]
+    pub receiver_radius: f32,
+    /// orientation of the listener as a quaternion (x, y, z, w)
+    #[serde(default)]
+    pub receiver_rotation: Option<[f32; 4]>,
+    #[serde(default = "SceneSettings::default_max_order")]
+    pub max_order: u32,
+    #[serde(default = "SceneSettings::default_ray_count")]
+    pub ray_count: u64,
+    /// acoustic materials by material name (or PLY material ID), either the name of a library
+    /// material or an object with the same keys as glTF material extras
+    #[serde(default)]
+    pub materials: serde_json::Map<String, json::Value>,
+}
+
+impl SceneSettings {
+    fn default_receiver_radius() -> f32 {
+        0.5
+    }
+
+    fn default_max_order() -> u32 {
+        100
+    }
+
+    fn default_ray_count() -> u64 {
+        10000
+    }
+
+    pub fn from_file(file_name: &str) -> Result<SceneSettings, Box<dyn Error>> {
+        let contents = std::fs::read_to_string(file_name)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+}
+
+/// Builds the acoustic material of a glTF material from its extras
+fn acoustic_material(material: &gltf::Material, library: &MaterialLibrary) -> AcousticMaterial {
+    let name = material.name().unwrap_or("unnamed");
+    let parsed = material.extras().as_ref().and_then(|extras| json::Value::from_str(extras.get()).ok());
+    let no_extras = serde_json::Map::new();
+    let extras_object = parsed.as_ref().and_then(|value| value.as_object()).unwrap_or(&no_extras);
+    library.material_from_extras(name, extras_object)
+}
+
+fn get_node_from_mesh(id: u32, mesh: &gltf::Mesh, buffers: &Vec<Data>, library: &MaterialLibrary) -> Result<SceneNode, Box<dyn Error>> {
     let mesh_name = mesh.name().unwrap();
-    let mut scene_node = SceneNode::new(rand::random::<u32>(), mesh_name.to_string());
+    let mut scene_node = SceneNode::new(id, mesh_name.to_string());
+
+    // the primitives are merged into one mesh, with a material per primitive
+    let mut vertices: Vec<Vector3<f32>> = vec![];
+    let mut faces: Vec<[usize; 3]> = vec![];
+    let mut face_materials: Vec<usize> = vec![];
+    let mut materials: Vec<AcousticMaterial> = vec![];
     for primitive in mesh.primitives() {
-        let primitive_index = primitive.index();
-        
         let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
 
-        let mut vertices: Vec<Vector3<f32>> = vec![];
+        let offset = vertices.len();
         for vert in reader.read_positions().expect("has positions") {
             let [x, y, z] = vert;
             vertices.push(vector![x, y, z]);    
         } 
 
-        let mut faces: Vec<[usize; 3]> = vec![];
-        let mut face = [0_usize; 3];
-        let mut i = 0_usize;
-        match reader.read_indices().expect("has indices") {
-            ReadIndices::U8(val) => {
-                for index in val {
-                    face[i] = index as usize;
-                    i = (i+1) % 3;
-                    if i == 0 {
-                        faces.push(face.clone());
-                    }
-                }
-            },
-            ReadIndices::U16(val) => {
-                for index in val {
-                    face[i] = index as usize;
-                    i = (i+1) % 3;
-                    if i == 0 {
-                        faces.push(face.clone());
-                    }
-                }
-            },
-            ReadIndices::U32(val) => {
-                for index in val {
-                    face[i] = index as usize;
-                    i = (i+1) % 3;
-                    if i == 0 {
-                        faces.push(face.clone());
-                    }
-                }
-            },
+        let indices: Vec<usize> = match reader.read_indices().expect("has indices") {
+            ReadIndices::U8(val) => val.map(|index| index as usize).collect(),
+            ReadIndices::U16(val) => val.map(|index| index as usize).collect(),
+            ReadIndices::U32(val) => val.map(|index| index as usize).collect(),
+        };
+        for face in indices.chunks_exact(3) {
+            faces.push([face[0] + offset, face[1] + offset, face[2] + offset]);
+            face_materials.push(materials.len());
         }
 
-        
-
-        let m = Mesh::new(vertices, faces);
-
-        let node_name = format!("{}-{}", mesh_name, primitive_index);
+        materials.push(if primitive.material().index().is_some() {
+            acoustic_material(&primitive.material(), library)
+        } else {
+            AcousticMaterial::default()
+        });
+    }
 
-        let mut mesh_node = SceneNode::new(rand::random::<u32>(), node_name);
-        // TODO: Better error handling
-        mesh_node.primitive = Primitive::Mesh(m);
+    let mut m = Mesh::new(vertices, faces);
+    m.face_materials = face_materials;
 
-        if primitive.material().index().is_some() {
-            let raw_val = primitive
-                .material()
-                .extras()
-                .as_ref()
-                .expect("has extras")
-                .get();
-            
-            let parsed = json::Value::from_str(raw_val).unwrap();
-            let extras_object = parsed.as_object().unwrap();
-            let abs63 = &extras_object["abs63"];
-            let abs125 = &extras_object["abs125"];
-            let abs250 = &extras_object["abs250"];
-            let abs500 = &extras_object["abs500"];
-            let abs1000 = &extras_object["abs1000"];
-            let abs2000 = &extras_object["abs2000"];
-            let abs4000 = &extras_object["abs4000"];
-            let abs8000 = &extras_object["abs8000"];
-            let abs16000 = &extras_object["abs16000"];
-            let absorption_data: AbsorptionData = AbsorptionData::new(vec![
-                [63_f32, abs63.as_f64().unwrap_or(0.1_f64) as f32],
-                [125_f32, abs125.as_f64().unwrap_or(0.1_f64) as f32],
-                [250_f32, abs250.as_f64().unwrap_or(0.1_f64) as f32],
-                [500_f32, abs500.as_f64().unwrap_or(0.1_f64) as f32],
-                [1000_f32, abs1000.as_f64().unwrap_or(0.1_f64) as f32],
-                [2000_f32, abs2000.as_f64().unwrap_or(0.1_f64) as f32],
-                [4000_f32, abs4000.as_f64().unwrap_or(0.1_f64) as f32],
-                [8000_f32, abs8000.as_f64().unwrap_or(0.1_f64) as f32],
-                [16000_f32, abs16000.as_f64().unwrap_or(0.1_f64) as f32],
-            ]);
-
-            // let absorption_json_value = parsed.as_object().unwrap().get("absorption").unwrap();
-            let acoustic_material = AcousticMaterial::from_absorption_data(absorption_data);
-
-            mesh_node.acoustic_material = acoustic_material;
-        }
-
-        scene_node.add_child(mesh_node);
+    let mut mesh_node = SceneNode::new(id, mesh_name.to_string());
+    // TODO: Better error handling
+    mesh_node.primitive = Primitive::Mesh(m);
+    if let Some(material) = materials.first() {
+        mesh_node.acoustic_material = material.clone();
     }
+    mesh_node.materials = materials;
+
+    scene_node.add_child(mesh_node);
     Ok(scene_node)
 }
 
 impl AcousticRaytracer {
-    pub fn new(root_node: SceneNode, source: Point3<f32>, receiver: u32, max_order: u32, ray_count: u64) -> Self {
+    pub fn new(mut root_node: SceneNode, source: Point3<f32>, receiver: u32, max_order: u32, ray_count: u64) -> Self {
+        let material_table = MaterialTable::new(&mut root_node);
+        let flat_scene = FlatScene::new(&root_node, receiver);
         Self {
             root_node,
             source,
@@ -165,11 +254,34 @@ impl AcousticRaytracer {
             max_order,
             ray_count,
             ray_paths: Vec::new(),
+            stream_arrivals: false,
+            streamed_arrivals: None,
+            material_table,
+            flat_scene,
+            energy_time_curve: None,
+            emitted_rays: 0,
+            closed: None,
+            output_format: OutputFormat::default(),
+            hrir_set: None,
+            diffuse_rain: false,
+            radiosity: None,
+            diffraction_order: 0,
+            decay_tail: false,
+            poisson_tail: false,
+            filter_bank: FilterBank::default(),
+            fractional_delay: FractionalDelay::default(),
+            sample_rate: 44100,
+            frequencies: utils::bands::octave(63.0, 8000.0),
         }
     }
     pub fn from_gltf(file_name: &str) -> Result<AcousticRaytracer, Box<dyn Error>> {
+        AcousticRaytracer::from_gltf_with_library(file_name, &MaterialLibrary::builtin())
+    }
+    /// Loads a scene from a glTF file, resolving material references with a material library
+    pub fn from_gltf_with_library(file_name: &str, library: &MaterialLibrary) -> Result<AcousticRaytracer, Box<dyn Error>> {
         let (gltf, buffers, _) = gltf::import(file_name)?;
-        let mut root_node = SceneNode::new(rand::random::<u32>(), file_name.to_string());
+        // node IDs follow the glTF node indices, so they are the same every time the file is loaded
+        let mut root_node = SceneNode::new(0, file_name.to_string());
         let mut source: Option<Point3<f32>> = None;
         let mut receiver: Option<u32> = None;
 
@@ -210,7 +322,7 @@ impl AcousticRaytracer {
                         Some(mesh) => mesh,
                         None => continue
                     };
-                    match get_node_from_mesh(&mesh, &buffers) {
+                    match get_node_from_mesh(node.index() as u32 + 1, &mesh, &buffers, library) {
                         Ok(mesh_node) => {
                             for child in mesh_node.children {
                                 root_node.add_child(child);
@@ -230,11 +342,14 @@ impl AcousticRaytracer {
                 Some(3) => {
                     println!("type is receiver");
                     let radius = &extras_object["radius"].as_f64().unwrap_or(0.5);
-                    let mut receiver_node = SceneNode::new(rand::random::<u32>(), "receiver".to_string());
+                    let mut receiver_node = SceneNode::new(node.index() as u32 + 1, "receiver".to_string());
                     receiver_node.primitive = Primitive::Sphere;
-                    let translation = node.transform().decomposed().0;
+                    let (translation, rotation, _) = node.transform().decomposed();
                     println!("{:?}", translation);
                     receiver_node.scale(*radius as f32, *radius as f32, *radius as f32);
+                    // the receiver's rotation orients the listener for spatial output formats
+                    let [x, y, z, w] = rotation;
+                    receiver_node.rotate_quaternion(UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z)));
                     receiver_node.translate(translation[0], translation[1], translation[2]);
                     receiver = Some(receiver_node.id);
                     root_node.add_child(receiver_node);
@@ -242,7 +357,7 @@ impl AcousticRaytracer {
                     //     Some(mesh) => mesh,
                     //     None => continue
                     // };
-                    // match get_node_from_mesh(&mesh, &buffers) {
+                    // match get_node_from_mesh(&mesh, &buffers, library) {
                     //     Ok(mesh_node) => {
                     //         for child in mesh_node.children {
 
@@ -264,6 +379,110 @@ impl AcousticRaytracer {
         Ok(acoustic_raytracer)
     }
 
+    pub fn from_obj(file_name: &str, settings_file: &str) -> Result<AcousticRaytracer, Box<dyn Error>> {
+        AcousticRaytracer::from_obj_with_library(file_name, settings_file, &MaterialLibrary::builtin())
+    }
+
+    /// Loads a scene from an OBJ file, with a node for each object or group, and the source and
+    /// receiver from a settings file. Materials are read from the acoustic keys of the OBJ's MTL
+    /// files, materials without them are looked up in the material library by name.
+    pub fn from_obj_with_library(file_name: &str, settings_file: &str, library: &MaterialLibrary) -> Result<AcousticRaytracer, Box<dyn Error>> {
+        let obj = ObjFile::from_file(file_name)?;
+        let settings = SceneSettings::from_file(settings_file)?;
+
+        // mtllib paths are relative to the OBJ file
+        let directory = std::path::Path::new(file_name).parent().unwrap_or_else(|| std::path::Path::new(""));
+        let mut mtl_materials = Vec::new();
+        for mtl_file in obj.material_libraries.iter() {
+            let path = directory.join(mtl_file);
+            match read_mtl(&path.to_string_lossy()) {
+                Ok(mut materials) => mtl_materials.append(&mut materials),
+                Err(e) => println!("warning: could not read {}: {}", path.display(), e),
+            }
+        }
+
+        AcousticRaytracer::from_objects(file_name, obj.objects, &mtl_materials, &settings, library)
+    }
+
+    pub fn from_mesh_file(file_name: &str, settings_file: &str) -> Result<AcousticRaytracer, Box<dyn Error>> {
+        AcousticRaytracer::from_mesh_file_with_library(file_name, settings_file, &MaterialLibrary::builtin())
+    }
+
+    /// Loads a scene from an OBJ, STL or PLY file with the source and receiver from a settings
+    /// file, choosing the format by the file's extension
+    pub fn from_mesh_file_with_library(file_name: &str, settings_file: &str, library: &MaterialLibrary) -> Result<AcousticRaytracer, Box<dyn Error>> {
+        let extension = std::path::Path::new(file_name)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let objects = match extension.as_str() {
+            "obj" => return AcousticRaytracer::from_obj_with_library(file_name, settings_file, library),
+            "stl" => read_stl(file_name)?,
+            "ply" => read_ply(file_name)?,
+            _ => return Err(format!("unsupported model format {}", file_name).into()),
+        };
+        let settings = SceneSettings::from_file(settings_file)?;
+        AcousticRaytracer::from_objects(file_name, objects, &[], &settings, library)
+    }
+
+    /// Builds a scene with a node for each object. The materials named by the objects' meshes
+    /// are built from the settings' materials, then the MTL materials, and otherwise looked up
+    /// in the material library.
+    fn from_objects(file_name: &str, objects: Vec<MeshObject>, mtl_materials: &[MtlMaterial], settings: &SceneSettings, library: &MaterialLibrary) -> Result<AcousticRaytracer, Box<dyn Error>> {
+        // node IDs follow the order of the objects, so they are the same every time the file is loaded
+        let mut root_node = SceneNode::new(0, file_name.to_string());
+
+        let no_extras = serde_json::Map::new();
+        let object_count = objects.len() as u32;
+        for (index, object) in objects.into_iter().enumerate() {
+            let mut node = SceneNode::new(index as u32 + 1, object.name.clone());
+            node.materials = object
+                .mesh
+                .material_names
+                .iter()
+                .map(|name| {
+                    if name.is_empty() {
+                        // faces without a usemtl statement
+                        return AcousticMaterial::default();
+                    }
+                    match settings.materials.get(name) {
+                        // a name refers to a library material
+                        Some(json::Value::String(key)) => {
+                            let mut extras = serde_json::Map::new();
+                            extras.insert("material".to_string(), json::Value::String(key.clone()));
+                            library.material_from_extras(name, &extras)
+                        }
+                        Some(json::Value::Object(extras)) => library.material_from_extras(name, extras),
+                        _ => {
+                            let extras = mtl_materials.iter().find(|(n, _)| n == name).map(|(_, extras)| extras).unwrap_or(&no_extras);
+                            library.material_from_extras(name, extras)
+                        }
+                    }
+                })
+                .collect();
+            if let Some(material) = node.materials.first() {
+                node.acoustic_material = material.clone();
+            }
+            node.primitive = Primitive::Mesh(object.mesh);
+            root_node.add_child(node);
+        }
+
+        let mut receiver_node = SceneNode::new(object_count + 1, "receiver".to_string());
+        receiver_node.primitive = Primitive::Sphere;
+        let radius = settings.receiver_radius;
+        receiver_node.scale(radius, radius, radius);
+        if let Some([x, y, z, w]) = settings.receiver_rotation {
+            receiver_node.rotate_quaternion(UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z)));
+        }
+        let [x, y, z] = settings.receiver;
+        receiver_node.translate(x, y, z);
+        let receiver = receiver_node.id;
+        root_node.add_child(receiver_node);
+
+        let [x, y, z] = settings.source;
+        Ok(AcousticRaytracer::new(root_node, point![x, y, z], receiver, settings.max_order, settings.ray_count))
+    }
+
     pub fn render(&mut self, file_name: String) -> Result<(), &str> {
         println!("Rendering");
         let t0 = SystemTime::now()
@@ -271,7 +490,26 @@ impl AcousticRaytracer {
             .expect("Time went backwards")
             .as_millis();
 
-        self.trace_rays();
+        // the radiosity solver already carries the energy of the reflections, which traced paths
+        // would add a second time
+        if self.radiosity.is_some() && (self.ray_count > 0 || !self.ray_paths.is_empty() || self.streamed_arrivals.is_some()) {
+            return Err("the radiosity solver replaces the traced rays, the ray count must be 0 and no paths can be loaded");
+        }
+
+        // paths loaded from a cache or streamed before are not traced again
+        if self.ray_paths.is_empty() && self.streamed_arrivals.is_none() {
+            self.trace_rays();
+            // the radiosity solver has no paths to diffract, and its energy-time curve already
+            // carries the energy that reaches the receiver
+            if self.diffraction_order > 0 && self.radiosity.is_none() {
+                let mut diffraction_paths = self.calculate_diffraction_paths(self.diffraction_order);
+                println!("diffraction paths: {}", diffraction_paths.len());
+                self.ray_paths.append(&mut diffraction_paths);
+            }
+        }
+        if let Some(settings) = self.radiosity.clone() {
+            self.energy_time_curve = Some(self.calculate_energy_time_curve(&settings));
+        }
 
         let t1 = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -284,7 +522,7 @@ impl AcousticRaytracer {
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
             .as_millis();
-        println!("trace_rays                = {}", t1-t0);
+        println!("propagation               = {}", t1-t0);
         println!("download_impulse_response = {}", t2-t1);
         println!("render                    = {}", t2-t0);
         Ok(())
@@ -301,16 +539,113 @@ pub fn random_vector3() -> Vector3<f32> {
     vector![x - 0.5, y - 0.5, z - 0.5].normalize()
 }
 
+/// Converts a direction in scene coordinates into the listener frame used for spatial encoding
+/// (x forward, y left, z up).
+///
+/// The direction is first brought into the receiver's local space, where the listener follows the
+/// glTF convention of facing +Z with +Y up.
+pub fn listener_direction(receiver_inv_transform: &Affine3<f32>, direction: &Vector3<f32>) -> Vector3<f32> {
+    let local = (receiver_inv_transform * direction).normalize();
+    vector![local.z, local.x, local.y]
+}
+
 pub fn probability(prob: f32) -> bool {
     let r: f32 = random();
     return r <= prob;
 }
 
-#[derive(Debug, Clone)]
+/// A contribution to the impulse response
+pub(crate) struct Arrival {
+    pub(crate) time: f32,
+    /// pressure per frequency band
+    pub(crate) pressures: Vec<f32>,
+    /// the direction the sound is coming from, in scene coordinates
+    pub(crate) direction: Vector3<f32>,
+}
+
+/// Converts an energy-time curve into a dense sequence of arrivals, one per sample, coming from
+/// random directions. `scale` is the number of rays the curve's energy fractions are relative to.
+fn energy_time_curve_arrivals(energy_time_curve: &EnergyTimeCurve, initial_spl: &[f32], sample_rate: u32, scale: f32) -> Vec<Arrival> {
+    let initial_intensities = p_2_i(lp_2_p(initial_spl.to_vec()), 400.0);
+    let samples_per_bin = usize::max(1, (energy_time_curve.time_step * sample_rate as f32).round() as usize);
+    let bins = energy_time_curve.energy[0].len();
+    let mut arrivals = Vec::with_capacity(bins * samples_per_bin);
+    for bin in 0..bins {
+        if energy_time_curve.energy.iter().all(|band| band[bin] <= 0.0) {
+            continue;
+        }
+        let intensities: Vec<f32> = initial_intensities
+            .iter()
+            .zip(energy_time_curve.energy.iter())
+            .map(|(i, band)| i * band[bin] * scale / samples_per_bin as f32)
+            .collect();
+        let pressures = i_2_p(intensities, 400.0);
+        for sample in 0..samples_per_bin {
+            arrivals.push(Arrival {
+                time: bin as f32 * energy_time_curve.time_step + sample as f32 / sample_rate as f32,
+                pressures: pressures.clone(),
+                direction: random_vector3(),
+            });
+        }
+    }
+    arrivals
+}
+
+/// The share of the rays that have been reflected at most `max_order` times after a time, for
+/// a number of reflections that is Poisson distributed with `reflection_rate` per second
+fn order_survival(time: f32, max_order: u32, reflection_rate: f32) -> f32 {
+    let mean = (time * reflection_rate) as f64;
+    if mean <= 0.0 {
+        return 1.0;
+    }
+    // the terms are summed in log space, as e^-mean underflows for long times
+    let mut log_term = -mean;
+    let mut sum = log_term.exp();
+    for k in 1..=max_order {
+        log_term += mean.ln() - (k as f64).ln();
+        sum += log_term.exp();
+    }
+    sum.min(1.0) as f32
+}
+
+/// The number of bins the cosine of the angle of incidence of `MaterialHits` is divided into
+pub const COS_THETA_BINS: u32 = 10;
+
+/// The number of times a path is reflected by and transmitted through a material, at angles of
+/// incidence in one bin, as the absorption of materials with a surface impedance depends on it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MaterialHits {
+    /// the index of the material in the scene's material table
+    pub material: usize,
+    /// the bin of the cosine of the angle of incidence, of `COS_THETA_BINS` equal bins
+    pub cos_theta_bin: u32,
+    pub reflections: u32,
+    pub transmissions: u32,
+}
+
+impl MaterialHits {
+    /// The cosine of the angle of incidence in the middle of the hits' bin
+    pub fn cos_theta(&self) -> f32 {
+        (self.cos_theta_bin as f32 + 0.5) / COS_THETA_BINS as f32
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RayPath {
     path: Vec<NonRefIntersection>,
     source: Point3<f32>,
     distance: f32,
+    /// energy of the arrival relative to a ray detected by the receiver
+    weight: f32,
+    /// edge diffractions along the path, empty for traced rays
+    diffractions: Vec<Diffraction>,
+    /// indices of the hits in `path` where the ray was transmitted through the surface
+    transmissions: Vec<usize>,
+    /// how often the path is reflected by and transmitted through each material
+    hits: Vec<MaterialHits>,
+    /// the factor that divides the energy by the probability of the path's reflections and
+    /// transmissions, from the materials it was traced with
+    sampling_weight: f32,
 }
 
 
@@ -328,6 +663,21 @@ impl fmt::Display for RayPath {
 
 
 impl RayPath {
+    /// Creates a path that is diffracted around edges at the given indices of `path`
+    pub fn diffracted(source: Point3<f32>, path: Vec<NonRefIntersection>, diffractions: Vec<Diffraction>, weight: f32) -> RayPath {
+        let mut ray_path = RayPath {
+            path,
+            source,
+            distance: 0_f32,
+            weight,
+            diffractions,
+            transmissions: Vec::new(),
+            hits: Vec::new(),
+            sampling_weight: 1.0,
+        };
+        ray_path.distance = ray_path.get_total_distance();
+        ray_path
+    }
     pub fn get_total_distance(&self) -> f32 {
         let mut distance = 0_f32;
         for i in 0..self.path.len() {
@@ -342,118 +692,325 @@ impl RayPath {
     pub fn get_total_time(&self) -> f32 {
         self.get_total_distance() / SPEED_OF_SOUND
     }
+    /// How often the path is reflected by and transmitted through each material
+    pub fn material_hits(&self) -> &[MaterialHits] {
+        &self.hits
+    }
+
+    /// Counts the reflections and transmissions at each material along the path, and sets the
+    /// weight of the tracer's choices between them, with the scene's current materials
+    fn count_hits(&mut self, materials: &MaterialTable) {
+        self.hits.clear();
+        self.sampling_weight = 1.0;
+        for (i, hit) in self.path.iter().enumerate().take(self.path.len() - 1) {
+            if self.diffractions.iter().any(|d| d.index == i) {
+                continue;
+            }
+            let probability = materials.transmission_probability(hit.material_index);
+            let transmitted = self.transmissions.contains(&i);
+            self.sampling_weight /= if transmitted { probability } else { 1.0 - probability };
+
+            let cos_theta_bin = u32::min((self.cos_theta(i) * COS_THETA_BINS as f32) as u32, COS_THETA_BINS - 1);
+            let index = match self.hits.iter().position(|hits| hits.material == hit.material_index && hits.cos_theta_bin == cos_theta_bin) {
+                Some(index) => index,
+                None => {
+                    self.hits.push(MaterialHits { material: hit.material_index, cos_theta_bin, reflections: 0, transmissions: 0 });
+                    self.hits.len() - 1
+                }
+            };
+            if transmitted {
+                self.hits[index].transmissions += 1;
+            } else {
+                self.hits[index].reflections += 1;
+            }
+        }
+    }
+
+    /// The cosine of the angle between the segment arriving at the `i`th hit and the normal
+    fn cos_theta(&self, i: usize) -> f32 {
+        let hit = &self.path[i];
+        let previous = if i == 0 { self.source } else { self.path[i - 1].point };
+        let incoming = hit.point - previous;
+        hit.normal.dot(&incoming).abs() / incoming.magnitude()
+    }
+
+    /// The IDs of the nodes the path hits, ending with the receiver
+    pub fn nodes(&self) -> impl Iterator<Item = u32> + '_ {
+        self.path.iter().map(|hit| hit.node)
+    }
+
+    /// The unit vector pointing from the arrival point back along the last segment of the path,
+    /// i.e. the direction the sound is coming from
+    pub fn arrival_direction(&self) -> Vector3<f32> {
+        let n = self.path.len();
+        let arrival = self.path[n - 1].point;
+        let previous = if n > 1 { self.path[n - 2].point } else { self.source };
+        (previous - arrival).normalize()
+    }
 }
 
-fn arrival_pressure(root_node: &SceneNode, initial_spl: &Vec<f32>, freqs: &Vec<f32>, ray_path: &RayPath) -> Vec<f32> {
+fn arrival_pressure(materials: &MaterialTable, initial_spl: &Vec<f32>, freqs: &[f32], ray_path: &RayPath) -> Vec<f32> {
 
-    let mut intensities = p_2_i(lp_2_p(initial_spl.to_vec()), 400.0);
+    let mut intensities: Vec<f32> = p_2_i(lp_2_p(initial_spl.to_vec()), 400.0).iter().map(|i| i * ray_path.weight).collect();
 
     // for each surface that the ray intersected
     for i in 0..(ray_path.path.len()-1) {
-        
-        // get the reflecting surface
-        let surface = root_node.find_child_by_id(ray_path.path[i].node).expect("node id exists in scene");
-        
-        // multiply intensities by the frequency dependant reflection coefficient
+
+        // diffracting edges attenuate according to the UTD coefficient instead of reflecting
+        if let Some(diffraction) = ray_path.diffractions.iter().find(|d| d.index == i) {
+            for index in 0..intensities.len() {
+                intensities[index] *= diffraction.energy_factor(freqs[index]);
+            }
+            continue;
+        }
+
+        // get the material of the reflecting surface
+        let material = &materials.materials[ray_path.path[i].material_index];
+        // rays reaching a transmissive surface were sent on with the transmission probability,
+        // so the energy is divided by the probability of the branch that was taken
+        let probability = materials.transmission_probability(ray_path.path[i].material_index);
+        let transmitted = ray_path.transmissions.contains(&i);
+
+        // the cosine of the angle between the incoming ray and the surface normal
+        let cos_theta = ray_path.cos_theta(i);
+
+        // multiply intensities by the frequency dependant reflection (or transmission) coefficient
         for index in 0..intensities.len() {
-            let r = if freqs[index] > 8000.0 {
-                1.0 - surface.acoustic_material.absorption_function(8000.0)
+            let frequency = f32::min(freqs[index], 8000.0);
+            let r = if transmitted {
+                material.transmission_at_angle(frequency, cos_theta) / probability
             } else {
-                1.0 - surface.acoustic_material.absorption_function(freqs[index])
+                (1.0 - material.absorption_at_angle(frequency, cos_theta)) / (1.0 - probability)
             };
             intensities[index] = intensities[index] * r; // multiply the intensity by the reflection coefficient
         }
 
     }
 
+    air_absorbed_pressure(intensities, freqs, ray_path.distance)
+}
+
+/// Converts the intensities of an arrival to pressures, after the air absorbs them along the path
+fn air_absorbed_pressure(intensities: Vec<f32>, freqs: &[f32], distance: f32) -> Vec<f32> {
     // convert back to SPL 
     let mut arrival_lp = p_2_lp(i_2_p(intensities, 400.0));
 
     // apply air absorption (dB/m)
-    let air_attenuation_db = air_attenuation(&freqs, 20.0, 40.0, 101325.0);
-    for freq in 0..freqs.len() {
-        arrival_lp[freq] -= air_attenuation_db[freq] * ray_path.distance;
+    let air_attenuation_db = air_attenuation(&freqs.to_vec(), 20.0, 40.0, 101325.0);
+    for (lp, attenuation) in arrival_lp.iter_mut().zip(air_attenuation_db.iter()) {
+        *lp -= attenuation * distance;
     }
 
     // convert back to pressure
     lp_2_p(arrival_lp)
 }
 
+/// The pressure of an arrival from the number of times its path hits each material, without
+/// walking the path. Materials with a surface impedance are evaluated at the middle of the bins
+/// of the angles of incidence.
+pub(crate) fn hit_count_pressure(materials: &[AcousticMaterial], initial_spl: &[f32], freqs: &[f32], ray_path: &RayPath) -> Vec<f32> {
+    let weight = ray_path.weight * ray_path.sampling_weight;
+    let mut intensities: Vec<f32> = p_2_i(lp_2_p(initial_spl.to_vec()), 400.0).iter().map(|i| i * weight).collect();
+    for diffraction in ray_path.diffractions.iter() {
+        for (intensity, frequency) in intensities.iter_mut().zip(freqs.iter()) {
+            *intensity *= diffraction.energy_factor(*frequency);
+        }
+    }
+    for hits in ray_path.hits.iter() {
+        let material = &materials[hits.material];
+        let cos_theta = hits.cos_theta();
+        for (intensity, frequency) in intensities.iter_mut().zip(freqs.iter()) {
+            let frequency = f32::min(*frequency, 8000.0);
+            *intensity *= (1.0 - material.absorption_at_angle(frequency, cos_theta)).powi(hits.reflections as i32)
+                * material.transmission_at_angle(frequency, cos_theta).powi(hits.transmissions as i32);
+        }
+    }
+    air_absorbed_pressure(intensities, freqs, ray_path.distance)
+}
+
 impl AcousticRaytracer {
 
     pub fn download_impulse_response(&mut self, path: String) {
 
-        let impulse_response = self.calculate_impulse_response();
+        let pressures = self.path_pressures();
+        let impulse_response = self.impulse_response_in_format(pressures, self.output_format);
 
         let spec = hound::WavSpec {
-            channels: 1,
-            sample_rate: 44100,
+            channels: impulse_response.len() as u16,
+            sample_rate: self.sample_rate,
             bits_per_sample: 16,
             sample_format: hound::SampleFormat::Int,
         };
         let mut writer = hound::WavWriter::create(path, spec).unwrap();
-        for i in 0..impulse_response.len() {
-            let amplitude = i16::MAX as f32;
-            writer.write_sample((impulse_response[i] * amplitude) as i16).unwrap();
+        let amplitude = i16::MAX as f32;
+        for i in 0..impulse_response[0].len() {
+            for channel in impulse_response.iter() {
+                writer.write_sample((channel[i] * amplitude) as i16).unwrap();
+            }
         }
     }
 
     pub fn calculate_impulse_response(&mut self) -> Vec<f32> {
-        let initial_spl = 100_f32; 
-        let frequencies = utils::bands::octave(63.0, 8000.0);
-        let sample_rate = 44100_u32;
-        
+        let pressures = self.path_pressures();
+        self.impulse_response_in_format(pressures, OutputFormat::Mono).remove(0)
+    }
 
-    
-        self.ray_paths.sort_by(|a,b| a.distance.partial_cmp(&b.distance).unwrap());
-    
-         // end time is latest time of arrival plus 0.1 seconds for safety
-        let total_time = self.ray_paths[self.ray_paths.len() -1].get_total_time() + 0.05;
-        println!("total_time: {}", total_time);
-        let spls = vec![initial_spl; frequencies.len()];
-    
-        // doubled the number of samples to mitigate the signal reversing
-        let number_of_samples = (f32::floor(sample_rate as f32 * total_time) * 2.0) as u32;
-        println!("number_of_samples: {}", number_of_samples);
-        let mut samples: Vec<Vec<f32>> = Vec::new();
-        for _ in 0..frequencies.len() {
-            samples.push(vec![0_f32; number_of_samples as usize]);
-        }
-      
-        // add in raytracer paths 
-        for i in 0..self.ray_paths.len() {
-          let random_phase = if random() { 1.0 } else { -1.0 };
-          let t = self.ray_paths[i].get_total_time();
-          let p: Vec<f32> = arrival_pressure(&self.root_node, &spls, &frequencies, &self.ray_paths[i]).iter().map(|x| x * random_phase).collect(); 
-          let rounded_sample = f32::floor(t * (sample_rate as f32)) as usize;
-    
-          for f in 0..frequencies.len() {
-              samples[f][rounded_sample] += p[f];
-          }
+    /// Calculates an ambisonic impulse response (ACN channel order, SN3D normalization)
+    /// by encoding each arrival with the direction it is coming from.
+    pub fn calculate_ambisonic_impulse_response(&mut self, order: u32) -> Vec<Vec<f32>> {
+        let pressures = self.path_pressures();
+        self.impulse_response_in_format(pressures, OutputFormat::Ambisonic(order))
+    }
+
+    /// Calculates a binaural (left, right) impulse response by convolving each arrival's
+    /// band-filtered contribution with the HRIR for the direction it is coming from.
+    pub fn calculate_binaural_impulse_response(&mut self, interpolation: HrirInterpolation) -> Vec<Vec<f32>> {
+        let pressures = self.path_pressures();
+        self.impulse_response_in_format(pressures, OutputFormat::Binaural(interpolation))
+    }
+
+    /// The pressure per band of the arrival of each ray path, with the scene's materials. The
+    /// material table is collected again, as the materials may have been edited since tracing.
+    fn path_pressures(&mut self) -> Vec<Vec<f32>> {
+        self.update_material_table();
+        let spls = vec![INITIAL_SPL; self.frequencies.len()];
+        self.ray_paths.iter().map(|ray_path| arrival_pressure(&self.material_table, &spls, &self.frequencies, ray_path)).collect()
+    }
+
+    /// Synthesizes the impulse response of the ray paths, whose arrivals have the given pressures,
+    /// in an output format
+    pub(crate) fn impulse_response_in_format(&mut self, path_pressures: Vec<Vec<f32>>, format: OutputFormat) -> Vec<Vec<f32>> {
+        let (channels, kernel_length, channel_kernels) = self.channel_kernels(format);
+        self.synthesize_impulse_response(path_pressures, channels, kernel_length, &*channel_kernels)
+    }
+
+    /// The number of channels of an output format, the length of the kernels arrivals are
+    /// convolved with in each channel, and the function that returns the kernels for the
+    /// direction an arrival is coming from; a plain gain is a kernel of length one
+    fn channel_kernels(&self, format: OutputFormat) -> (usize, usize, Box<ChannelKernels>) {
+        match format {
+            OutputFormat::Mono => (1, 1, Box::new(|_| vec![vec![1.0]])),
+            OutputFormat::Ambisonic(order) => {
+                let receiver_inv_transform = self.receiver_inv_transform();
+                (ambisonics::channel_count(order), 1, Box::new(move |arrival_direction| {
+                    let direction = listener_direction(&receiver_inv_transform, arrival_direction);
+                    ambisonics::encode(&direction, order).iter().map(|gain| vec![*gain]).collect()
+                }))
+            }
+            OutputFormat::Binaural(interpolation) => {
+                let hrir_set = self.hrir_set.as_ref().expect("binaural output requires an HRIR set").resampled(self.sample_rate);
+                let receiver_inv_transform = self.receiver_inv_transform();
+                let taps = hrir_set.left.iter().map(|ir| ir.len()).max().unwrap_or(1);
+                (2, taps, Box::new(move |arrival_direction| {
+                    let direction = listener_direction(&receiver_inv_transform, arrival_direction);
+                    hrir_set.hrir(&direction, interpolation).to_vec()
+                }))
+            }
         }
+    }
+
+    fn receiver_inv_transform(&self) -> Affine3<f32> {
+        self.root_node.find_child_by_id(self.receiver).expect("receiver exists in scene").inv_transform
+    }
+
+    /// An empty impulse response with a number of channels, whose arrivals after the mixing time
+    /// are replaced by a Dirac sequence if `poisson_tail` is set. The mixing time comes from the
+    /// room's statistics, which are only needed with `poisson_tail`.
+    fn arrival_accumulator(&self, channels: usize, statistics: Option<&RoomStatistics>) -> ArrivalAccumulator {
+        let dirac_start = match statistics {
+            // the mixing time predicted from the mean free path, after which the sound field is diffuse
+            Some(statistics) if self.poisson_tail && statistics.volume > 0.0 => Some((20.0 * statistics.volume / statistics.surface_area + 12.0) / 1000.0),
+            _ => None,
+        };
+        ArrivalAccumulator::new(channels, self.frequencies.len(), self.sample_rate, self.fractional_delay, dirac_start)
+    }
+
+    /// Synthesizes a multichannel impulse response from the traced ray paths, whose arrivals have
+    /// the pressures per band in `path_pressures`, the arrivals streamed while tracing and the
+    /// radiosity energy-time curve.
+    ///
+    /// `channel_kernels` returns, for the direction an arrival is coming from, the impulse response
+    /// (at most `kernel_length` samples long) it is convolved with in each channel; a plain gain is a
+    /// kernel of length one. Because the kernels are added to every band before band filtering, each
+    /// channel receives the band-filtered contribution of the arrival convolved with its kernel. All
+    /// channels are normalized by the same factor so that their relative levels are preserved.
+    fn synthesize_impulse_response(&mut self, path_pressures: Vec<Vec<f32>>, channels: usize, kernel_length: usize, channel_kernels: &ChannelKernels) -> Vec<Vec<f32>> {
+        let frequencies = self.frequencies.clone();
+        let sample_rate = self.sample_rate;
+        let spls = vec![INITIAL_SPL; frequencies.len()];
+
+        // the tails are shaped by the room's volume and surface area
+        let statistics = if self.poisson_tail || self.decay_tail { Some(self.room_statistics()) } else { None };
+        let mut accumulator = match &self.streamed_arrivals {
+            Some(streamed) => {
+                assert_eq!(streamed.channels(), channels, "the arrivals were streamed in another output format");
+                streamed.clone()
+            }
+            None => self.arrival_accumulator(channels, statistics.as_ref()),
+        };
+        for (ray_path, pressures) in self.ray_paths.iter().zip(path_pressures) {
+            let direction = ray_path.arrival_direction();
+            let arrival = Arrival { time: ray_path.get_total_time(), pressures, direction };
+            accumulator.add(&arrival, &channel_kernels(&direction));
+        }
+        if let Some(energy_time_curve) = &self.energy_time_curve {
+            let scale = u64::max(1, self.emitted_rays) as f32;
+            for arrival in energy_time_curve_arrivals(energy_time_curve, &spls, sample_rate, scale) {
+                accumulator.add(&arrival, &channel_kernels(&arrival.direction));
+            }
+        }
+        if let Some(statistics) = &statistics {
+            accumulator.add_dirac_sequence(statistics.volume, channel_kernels);
+        }
+        if let Some(statistics) = statistics.as_ref().filter(|_| self.decay_tail) {
+            // the mean free path of a closed room gives the rate at which rays are reflected, the
+            // volume of an open model is not meaningful
+            let closed = self.is_closed();
+            let reflection_rate = SPEED_OF_SOUND * statistics.surface_area / (4.0 * statistics.volume);
+            let max_order = self.max_order;
+            accumulator.add_decay_tail(|time| {
+                if closed && reflection_rate.is_finite() && self.radiosity.is_none() {
+                    order_survival(time, max_order, reflection_rate)
+                } else {
+                    1.0
+                }
+            }, channel_kernels);
+        }
+        let samples = accumulator.into_samples(kernel_length);
+        // every channel has the same length, so the band filters are designed once
+        let band_filters = BandFilters::new(&frequencies, sample_rate as f32, self.filter_bank, samples[0][0].len());
         
-        let filtered_samples = reconstruction_filter::filter_signals(samples);
-        // let filtered_samples = samples;
-    
-            // make the new signal's length half as long, we dont need the reversed part
-        let mut signal: Vec<f32> = vec![0.0; filtered_samples[0].len() / 2];
-        
+        let mut signals: Vec<Vec<f32>> = Vec::new();
         let mut max = 0.0;
-        for i in 0..filtered_samples.len() {
-            for j in 0..signal.len() {
-                signal[j] += filtered_samples[i][j];
-                if f32::abs(signal[j]) > max {
-                  max = f32::abs(signal[j]);
+        for channel_samples in samples {
+            let filtered_samples = band_filters.filter(&channel_samples);
+            // let filtered_samples = samples;
+    
+            let mut signal: Vec<f32> = vec![0.0; filtered_samples[0].len()];
+
+            for band in filtered_samples.iter() {
+                for j in 0..signal.len() {
+                    signal[j] += band[j];
+                }
+            }
+            for x in signal.iter() {
+                if f32::abs(*x) > max {
+                  max = f32::abs(*x);
                 }
             }
+            signals.push(signal);
         }
         println!("max: {}", max);
-        for i in 0..signal.len() {
-            signal[i] /= max;
+        for signal in signals.iter_mut() {
+            for x in signal.iter_mut() {
+                *x /= max;
+            }
         }
-        signal
+        signals
     }
     pub fn trace_rays(&mut self){
+        // rays are traced against the compiled scene, which must match the scene tree
+        self.compile_scene();
         let count = self.ray_count;
         let valid_ray_count = Arc::new(AtomicUsize::new(0));
         let t_pr = valid_ray_count.clone();
@@ -472,19 +1029,20 @@ impl AcousticRaytracer {
             }
             progress_bar.finish_print(&completion_string);
         });
-        if USE_RAYON {
+        if self.stream_arrivals {
+            self.stream_rays(&valid_ray_count);
+        } else if USE_RAYON {
             println!("USING RAYON");
             while (valid_ray_count.load(Ordering::Relaxed) as u64) < count {
+                self.emitted_rays += count;
                 let valid_ray_paths: Vec<RayPath> = (0..count)
                     .into_par_iter()
-                    .filter_map(|_| {
-                        let rp = self.trace_ray();
-                        if rp.is_some() {
+                    .flat_map(|_| {
+                        let arrivals = self.trace_ray();
+                        if !arrivals.is_empty() {
                             valid_ray_count.fetch_add(1, Ordering::Relaxed);
-                            Some(rp.unwrap())
-                        } else {
-                            None
                         }
+                        arrivals
                     }).collect();
                 for rp in valid_ray_paths.iter() {
                     self.ray_paths.push(rp.clone());
@@ -492,16 +1050,15 @@ impl AcousticRaytracer {
             }
         } else {
             while (valid_ray_count.load(Ordering::Relaxed) as u64) < count {
+                self.emitted_rays += count;
                 let valid_ray_paths: Vec<RayPath> = (0..count)
                     .into_iter()
-                    .filter_map(|_| {
-                        let rp = self.trace_ray();
-                        if rp.is_some() {
+                    .flat_map(|_| {
+                        let arrivals = self.trace_ray();
+                        if !arrivals.is_empty() {
                             valid_ray_count.fetch_add(1, Ordering::Relaxed);
-                            Some(rp.unwrap())
-                        } else {
-                            None
                         }
+                        arrivals
                     }).collect();
                 for rp in valid_ray_paths.iter() {
                     self.ray_paths.push(rp.clone());
@@ -511,46 +1068,234 @@ impl AcousticRaytracer {
         progress_thread.join().unwrap();
     }
 
-    pub fn trace_ray(&self) -> Option<RayPath> {
+    /// Traces rays until `ray_count` of them reached the receiver like `trace_rays`, but adds each
+    /// path's arrival to `streamed_arrivals` in the output format as soon as it is traced instead
+    /// of storing the path. Each thread adds to its own accumulator, which are merged at the end.
+    fn stream_rays(&mut self, valid_ray_count: &AtomicUsize) {
+        let count = self.ray_count;
+        let frequencies = self.frequencies.clone();
+        let spls = vec![INITIAL_SPL; frequencies.len()];
+        let (channels, _, channel_kernels) = self.channel_kernels(self.output_format);
+        let statistics = if self.poisson_tail { Some(self.room_statistics()) } else { None };
+        let empty = self.arrival_accumulator(channels, statistics.as_ref());
+        let mut streamed = self.streamed_arrivals.take().unwrap_or_else(|| empty.clone());
+        while (valid_ray_count.load(Ordering::Relaxed) as u64) < count {
+            self.emitted_rays += count;
+            let add_ray = |mut accumulator: ArrivalAccumulator, _| {
+                let ray_paths = self.trace_ray();
+                if !ray_paths.is_empty() {
+                    valid_ray_count.fetch_add(1, Ordering::Relaxed);
+                }
+                for ray_path in ray_paths.iter() {
+                    let direction = ray_path.arrival_direction();
+                    let pressures = arrival_pressure(&self.material_table, &spls, &frequencies, ray_path);
+                    let arrival = Arrival { time: ray_path.get_total_time(), pressures, direction };
+                    accumulator.add(&arrival, &channel_kernels(&direction));
+                }
+                accumulator
+            };
+            let traced = if USE_RAYON {
+                (0..count)
+                    .into_par_iter()
+                    .fold(|| empty.clone(), add_ray)
+                    .reduce(|| empty.clone(), |mut accumulator, other| {
+                        accumulator.merge(other);
+                        accumulator
+                    })
+            } else {
+                (0..count).fold(empty.clone(), add_ray)
+            };
+            streamed.merge(traced);
+        }
+        self.streamed_arrivals = Some(streamed);
+    }
+
+    /// Collects the scene's materials into the material table again, after the scene's nodes or
+    /// materials were changed
+    pub fn update_material_table(&mut self) {
+        self.material_table = MaterialTable::new(&mut self.root_node);
+    }
+
+    /// Compiles the scene tree into the material table and the flat scene that rays are traced
+    /// against, after the scene's nodes were changed
+    pub fn compile_scene(&mut self) {
+        self.update_material_table();
+        self.flat_scene = FlatScene::new(&self.root_node, self.receiver);
+        self.closed = None;
+    }
+
+    /// Whether the compiled scene is watertight, which is only checked once per compiled scene
+    fn is_closed(&mut self) -> bool {
+        match self.closed {
+            Some(closed) => closed,
+            None => {
+                let closed = self.geometry_diagnostics().diagnostics.is_watertight();
+                if !closed {
+                    println!("warning: the model is not closed, the decay tail is not corrected for the rays stopped at the maximum order (see raya check)");
+                }
+                self.closed = Some(closed);
+                closed
+            }
+        }
+    }
+
+    /// The centre and radius of the receiver sphere in scene coordinates
+    pub(crate) fn receiver_sphere(&self) -> (Point3<f32>, f32) {
+        let receiver = self.root_node.find_child_by_id(self.receiver).expect("receiver exists in scene");
+        let centre = receiver.transform * Point3::origin();
+        let radius = (receiver.transform * Vector3::x()).magnitude();
+        (centre, radius)
+    }
+
+    /// Sends a shadow ray from a reflection towards the receiver. If the receiver is visible,
+    /// returns the arrival carrying the part of the scattered energy the receiver intercepts,
+    /// assuming the scattered energy is radiated with a Lambert distribution.
+    fn diffuse_rain(&self, path: &[NonRefIntersection], transmissions: &[usize], reflection: &NonRefIntersection, scattering: f32, receiver: (Point3<f32>, f32)) -> Option<RayPath> {
+        let (centre, radius) = receiver;
+        let to_receiver = centre - reflection.point;
+        let distance = to_receiver.magnitude();
+        let cos_theta = reflection.normal.dot(&to_receiver) / distance;
+        if distance <= radius || cos_theta <= 0.0 {
+            return None;
+        }
+
+        let shadow_ray = Ray::new(reflection.point, to_receiver);
+        let visible = self.flat_scene.intersects(&shadow_ray).filter(|hit| hit.node == self.receiver)?;
+
+        // fraction of a Lambert distribution falling into the cone subtended by the receiver
+        let cos_gamma = f32::sqrt(1.0 - (radius / distance) * (radius / distance));
         let mut ray_path = RayPath {
             source: self.source,
-            path: Vec::new(),
-            distance: 0_f32
+            path: path.to_vec(),
+            distance: 0_f32,
+            weight: scattering * 2.0 * cos_theta * (1.0 - cos_gamma),
+            diffractions: Vec::new(),
+            transmissions: transmissions.to_vec(),
+            hits: Vec::new(),
+            sampling_weight: 1.0,
         };
+        ray_path.path.push(visible);
+        ray_path.distance = ray_path.get_total_distance();
+        ray_path.count_hits(&self.material_table);
+        Some(ray_path)
+    }
+
+    /// Traces a single ray from the source and returns every arrival it makes at the receiver.
+    ///
+    /// The receiver is a detector volume rather than a reflector: a ray passing through it
+    /// records an arrival and keeps propagating, so one ray can contribute many arrivals.
+    pub fn trace_ray(&self) -> Vec<RayPath> {
+        let mut arrivals: Vec<RayPath> = Vec::new();
+        let mut path: Vec<NonRefIntersection> = Vec::new();
+        let mut transmissions: Vec<usize> = Vec::new();
+        let receiver = self.flat_scene.receiver_sphere();
 
         let mut ray = Ray::new(self.source, random_vector3());
-        let mut collision = self.root_node.intersects(&ray);
+        let mut collision = self.flat_scene.intersects(&ray);
         let mut order = 0_u32;
-        let mut intersected_receiver = false;
+        // whether the current segment started with a diffuse reflection
+        let mut scattered = false;
 
-        while order < self.max_order && collision.is_some() && !intersected_receiver {
+        while order < self.max_order && collision.is_some() {
             let intersection = collision.unwrap();
-            intersected_receiver = intersection.node.id == self.receiver;
             // move the ray to the intersection point
             ray.src = intersection.point;
-            // reflect the ray
-            ray.dir = ray.dir-(intersection.normal.scale(ray.dir.dot(&intersection.normal)).scale(2.0_f32));
-            ray.dir.normalize_mut();
-            let scattering = 0.1;
-            
-            if probability(scattering) {
-                ray.dir = random_vector3();
-                if intersection.normal.dot(&ray.dir) < 0.0 {
-                    ray.dir.scale_mut(-1.0);
+
+            if intersection.node == self.receiver {
+                // with diffuse rain, energy scattered straight to the receiver was already accounted for
+                if !(self.diffuse_rain && scattered) {
+                    // record the arrival and let the ray continue in the same direction
+                    let mut ray_path = RayPath {
+                        source: self.source,
+                        path: path.clone(),
+                        distance: 0_f32,
+                        weight: 1.0,
+                        diffractions: Vec::new(),
+                        transmissions: transmissions.clone(),
+                        hits: Vec::new(),
+                        sampling_weight: 1.0,
+                    };
+                    ray_path.path.push(intersection);
+                    ray_path.distance = ray_path.get_total_distance();
+                    ray_path.count_hits(&self.material_table);
+                    arrivals.push(ray_path);
+                }
+            } else {
+                let material = &self.material_table.materials[intersection.material_index];
+                let scattering = material.scattering;
+                let transmission_probability = self.material_table.transmission_probability(intersection.material_index);
+                // add the intersection to the path
+                path.push(intersection);
+
+                if self.diffuse_rain {
+                    let reflection = path[path.len() - 1];
+                    // the reflected energy is divided by the reflection probability later, the
+                    // shadow ray is sent at every hit so it takes that factor back out
+                    let weight = scattering * (1.0 - transmission_probability);
+                    if let Some(ray_path) = self.diffuse_rain(&path, &transmissions, &reflection, weight, receiver) {
+                        arrivals.push(ray_path);
+                    }
                 }
+
+                if transmission_probability > 0.0 && probability(transmission_probability) {
+                    // pass through the surface in the same direction
+                    transmissions.push(path.len() - 1);
+                    scattered = false;
+                } else {
+                    // reflect the ray
+                    ray.dir = ray.dir-(intersection.normal.scale(ray.dir.dot(&intersection.normal)).scale(2.0_f32));
+                    ray.dir.normalize_mut();
+
+                    scattered = probability(scattering);
+                    if scattered {
+                        ray.dir = random_vector3();
+                        if intersection.normal.dot(&ray.dir) < 0.0 {
+                            ray.dir.scale_mut(-1.0);
+                        }
+                    }
+                }
+                // increment the order
+                order += 1;
             }
-            // add the intersection to the ray_path
-            ray_path.path.push(intersection.get_non_ref());
-            // increment the order
-            order += 1;
-            collision = self.root_node.intersects(&ray);
+            collision = self.flat_scene.intersects(&ray);
         }
 
-        if intersected_receiver {
-            ray_path.distance = ray_path.get_total_distance();
-            Some(ray_path)
-        } else {
-            None
-        }
+        arrivals
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A closed box from the origin to `size` whose faces face inwards, with a floor, a ceiling
+    /// and walls using the materials "floor", "ceiling" and "wall", loaded with the settings of
+    /// a mesh file
+    pub(crate) fn box_scene(size: [f32; 3], settings: json::Value) -> AcousticRaytracer {
+        let [x, y, z] = size;
+        let vertices = vec![
+            vector![0.0, 0.0, 0.0],
+            vector![x, 0.0, 0.0],
+            vector![x, y, 0.0],
+            vector![0.0, y, 0.0],
+            vector![0.0, 0.0, z],
+            vector![x, 0.0, z],
+            vector![x, y, z],
+            vector![0.0, y, z],
+        ];
+        let object = |name: &str, material: &str, quads: &[[usize; 4]]| {
+            let faces = quads.iter().flat_map(|[a, b, c, d]| [[*a, *b, *c], [*a, *c, *d]]).collect();
+            let mut mesh = Mesh::new(vertices.clone(), faces);
+            mesh.face_materials = vec![0; mesh.faces.len()];
+            mesh.material_names = vec![material.to_string()];
+            MeshObject { name: name.to_string(), mesh }
+        };
+        let objects = vec![
+            object("floor", "floor", &[[0, 1, 2, 3]]),
+            object("ceiling", "ceiling", &[[4, 7, 6, 5]]),
+            object("walls", "wall", &[[0, 4, 5, 1], [1, 5, 6, 2], [2, 6, 7, 3], [3, 7, 4, 0]]),
+        ];
+        let settings: SceneSettings = serde_json::from_value(settings).unwrap();
+        AcousticRaytracer::from_objects("box", objects, &[], &settings, &MaterialLibrary::builtin()).unwrap()
+    }
+}
@@ -0,0 +1,91 @@
+use crate::acoustic_raytrace::{hit_count_pressure, AcousticRaytracer, INITIAL_SPL};
+use crate::geometry::Primitive;
+use crate::scene::AcousticMaterial;
+use rayon::prelude::*;
+
+/// A material of the scene: the material of a node, or one of the face materials of its mesh
+#[derive(Debug, Clone)]
+pub struct SceneMaterial {
+    pub node: u32,
+    /// the index in the node's face materials, `None` for the node's material
+    pub material: Option<usize>,
+    /// the index in the scene's material table
+    pub index: usize,
+    /// the face material's name, or the node's name for the node's material
+    pub name: String,
+    pub acoustic_material: AcousticMaterial,
+}
+
+impl AcousticRaytracer {
+    /// Lists the materials of the scene's meshes, which can be changed and passed to
+    /// `calculate_impulse_response_with_materials`
+    pub fn scene_materials(&self) -> Vec<SceneMaterial> {
+        let mut materials = Vec::new();
+        for (node, _) in self.root_node.world_nodes() {
+            let mesh = match &node.primitive {
+                Primitive::Mesh(mesh) => mesh,
+                _ => continue,
+            };
+            materials.push(SceneMaterial {
+                node: node.id,
+                material: None,
+                index: node.material_table_index(None),
+                name: node.name.clone(),
+                acoustic_material: node.acoustic_material.clone(),
+            });
+            for (index, acoustic_material) in node.materials.iter().enumerate() {
+                let name = mesh.material_names.get(index).filter(|name| !name.is_empty()).unwrap_or(&node.name);
+                materials.push(SceneMaterial {
+                    node: node.id,
+                    material: Some(index),
+                    index: node.material_table_index(Some(index)),
+                    name: name.clone(),
+                    acoustic_material: acoustic_material.clone(),
+                });
+            }
+        }
+        materials
+    }
+
+    /// Replaces the scene's materials with the ones of a table, so they are used by later
+    /// traces too
+    pub fn set_scene_materials(&mut self, materials: &[SceneMaterial]) {
+        for material in materials.iter() {
+            if let Some(node) = self.root_node.find_child_by_id_mut(material.node) {
+                match material.material {
+                    None => node.acoustic_material = material.acoustic_material.clone(),
+                    Some(index) if index < node.materials.len() => node.materials[index] = material.acoustic_material.clone(),
+                    Some(_) => {}
+                }
+            }
+        }
+        self.update_material_table();
+    }
+
+    /// The pressure per band of each ray path's arrival with the materials of a table, from the
+    /// number of times the path hits each material. Materials missing from the table are the
+    /// scene's.
+    pub fn path_pressures_with_materials(&mut self, materials: &[SceneMaterial]) -> Vec<Vec<f32>> {
+        // the scene's materials may have been edited since tracing
+        self.update_material_table();
+        let mut table = self.material_table.materials.clone();
+        for material in materials.iter() {
+            if let Some(entry) = table.get_mut(material.index) {
+                *entry = material.acoustic_material.clone();
+            }
+        }
+        let spls = vec![INITIAL_SPL; self.frequencies.len()];
+        self.ray_paths
+            .par_iter()
+            .map(|ray_path| hit_count_pressure(&table, &spls, &self.frequencies, ray_path))
+            .collect()
+    }
+
+    /// Synthesizes the impulse response of the traced paths in the output format with the
+    /// materials of a table, without tracing again, e.g. to hear the room with a carpeted floor.
+    /// The radiosity energy-time curve is not recalculated.
+    pub fn calculate_impulse_response_with_materials(&mut self, materials: &[SceneMaterial]) -> Vec<Vec<f32>> {
+        let pressures = self.path_pressures_with_materials(materials);
+        self.impulse_response_in_format(pressures, self.output_format)
+    }
+}
@@ -0,0 +1,205 @@
+use crate::acoustic_raytrace::{AcousticRaytracer, SPEED_OF_SOUND};
+use crate::geometry::Primitive;
+use crate::scene::SceneNode;
+use crate::utils::attenuation::air_attenuation;
+use crate::utils::bands::octave;
+use std::collections::HashMap;
+
+/// Mean absorption coefficients are kept below 1, where Eyring's formula has no finite time
+const MAX_MEAN_ABSORPTION: f32 = 0.9999;
+
+/// The area of the faces of a node that share a material
+#[derive(Debug, Clone)]
+pub struct SurfaceArea {
+    pub node: u32,
+    /// the material's name, or the node's name for faces using the node's material
+    pub name: String,
+    /// area in m²
+    pub area: f32,
+    /// random incidence absorption coefficient per band
+    pub absorption: Vec<f32>,
+}
+
+/// The area of the faces of every node that share a material
+#[derive(Debug, Clone)]
+pub struct MaterialArea {
+    /// the material's name, or the node's name for faces using the node's material
+    pub name: String,
+    /// area in m²
+    pub area: f32,
+    /// random incidence absorption coefficient per band
+    pub absorption: Vec<f32>,
+}
+
+/// Statistical room acoustics of the scene, assuming a diffuse sound field
+#[derive(Debug, Clone)]
+pub struct RoomStatistics {
+    /// the volume enclosed by the faces in m³, only meaningful for closed models
+    pub volume: f32,
+    /// total area of the faces in m²
+    pub surface_area: f32,
+    pub surfaces: Vec<SurfaceArea>,
+    /// the areas of the surfaces summed per material name
+    pub materials: Vec<MaterialArea>,
+    /// octave band centre frequencies
+    pub frequencies: Vec<f32>,
+    /// area weighted absorption coefficient per band
+    pub mean_absorption: Vec<f32>,
+    /// reverberation times in seconds per band
+    pub sabine: Vec<f32>,
+    pub eyring: Vec<f32>,
+    pub arau_puchades: Vec<f32>,
+}
+
+impl AcousticRaytracer {
+    /// Calculates the room's volume and surface areas, and predicts its reverberation time with
+    /// Sabine's, Eyring's and Arau-Puchades' formulas, including the absorption of the air
+    pub fn room_statistics(&self) -> RoomStatistics {
+        let frequencies = octave(63.0, 8000.0);
+        // energy attenuation per meter, from dB per meter
+        let air: Vec<f32> = air_attenuation(&frequencies, 20.0, 40.0, 101325.0)
+            .iter()
+            .map(|db| db * f32::ln(10.0) / 10.0)
+            .collect();
+
+        let (_, triangles) = self.world_mesh();
+        let nodes: HashMap<u32, &SceneNode> = self.root_node.world_nodes().into_iter().map(|(node, _)| (node.id, node)).collect();
+        let mut volume = 0.0;
+        let mut surfaces: Vec<SurfaceArea> = Vec::new();
+        let mut surface_index: HashMap<(u32, Option<usize>), usize> = HashMap::new();
+        // area and absorption area per band of the faces facing along each axis
+        let mut axis_area = [0.0f32; 3];
+        let mut axis_absorption = vec![[0.0f32; 3]; frequencies.len()];
+        for triangle in triangles.iter() {
+            let [a, b, c] = triangle.vertices;
+            volume += a.coords.dot(&b.coords.cross(&c.coords)) / 6.0;
+
+            let node = nodes[&triangle.node];
+            let material = match &node.primitive {
+                Primitive::Mesh(mesh) => mesh.face_material(triangle.face),
+                _ => None,
+            };
+            let index = *surface_index.entry((triangle.node, material)).or_insert_with(|| {
+                let name = match (&node.primitive, material) {
+                    (Primitive::Mesh(mesh), Some(index)) if mesh.material_names.get(index).is_some_and(|name| !name.is_empty()) => {
+                        mesh.material_names[index].clone()
+                    }
+                    _ => node.name.clone(),
+                };
+                let acoustic_material = node.material(material);
+                surfaces.push(SurfaceArea {
+                    node: triangle.node,
+                    name,
+                    area: 0.0,
+                    absorption: frequencies.iter().map(|f| acoustic_material.absorption_function(*f)).collect(),
+                });
+                surfaces.len() - 1
+            });
+            let area = triangle.area();
+            surfaces[index].area += area;
+
+            let axis = triangle.normal().iamax();
+            axis_area[axis] += area;
+            for (band, absorption) in axis_absorption.iter_mut().enumerate() {
+                absorption[axis] += area * surfaces[index].absorption[band];
+            }
+        }
+        let mut materials: Vec<MaterialArea> = Vec::new();
+        for surface in surfaces.iter() {
+            match materials.iter_mut().find(|material| material.name == surface.name) {
+                Some(material) => material.area += surface.area,
+                None => materials.push(MaterialArea { name: surface.name.clone(), area: surface.area, absorption: surface.absorption.clone() }),
+            }
+        }
+        // the faces point into the room, which makes the signed volume negative
+        let volume = volume.abs();
+        let surface_area: f32 = axis_area.iter().sum();
+
+        let constant = 24.0 * f32::ln(10.0) / SPEED_OF_SOUND;
+        let mut mean_absorption = Vec::new();
+        let mut sabine = Vec::new();
+        let mut eyring = Vec::new();
+        let mut arau_puchades = Vec::new();
+        for (band, absorption) in axis_absorption.iter().enumerate() {
+            let air_absorption = 4.0 * air[band] * volume;
+            let absorption_area: f32 = absorption.iter().sum();
+            let mean = absorption_area / surface_area;
+            mean_absorption.push(mean);
+            let mean = mean.min(MAX_MEAN_ABSORPTION);
+            sabine.push(constant * volume / (absorption_area + air_absorption));
+            eyring.push(constant * volume / (-surface_area * f32::ln(1.0 - mean) + air_absorption));
+            // the geometric mean of Eyring's formula for each pair of opposite surfaces, weighted
+            // by their share of the area
+            let t: f32 = (0..3)
+                .filter(|axis| axis_area[*axis] > 0.0)
+                .map(|axis| {
+                    let mean = f32::min(absorption[axis] / axis_area[axis], MAX_MEAN_ABSORPTION);
+                    let t = constant * volume / (-surface_area * f32::ln(1.0 - mean) + air_absorption);
+                    t.powf(axis_area[axis] / surface_area)
+                })
+                .product();
+            arau_puchades.push(t);
+        }
+
+        RoomStatistics {
+            volume,
+            surface_area,
+            surfaces,
+            materials,
+            frequencies,
+            mean_absorption,
+            sabine,
+            eyring,
+            arau_puchades,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acoustic_raytrace::tests::box_scene;
+    use serde_json::json;
+
+    fn uniform_box(absorption: f32) -> RoomStatistics {
+        let bands = [63, 125, 250, 500, 1000, 2000, 4000, 8000];
+        let material: serde_json::Map<String, serde_json::Value> = bands.iter().map(|band| (format!("abs{}", band), json!(absorption))).collect();
+        box_scene([6.0, 4.0, 3.0], json!({
+            "source": [1.0, 1.0, 1.0],
+            "receiver": [4.0, 3.0, 1.5],
+            "materials": {"floor": material, "ceiling": material, "wall": material},
+        }))
+        .room_statistics()
+    }
+
+    #[test]
+    fn box_reverberation_times() {
+        let statistics = uniform_box(0.2);
+        assert!((statistics.volume - 72.0).abs() < 1e-3);
+        assert!((statistics.surface_area - 108.0).abs() < 1e-3);
+        let areas: Vec<(&str, f32)> = statistics.materials.iter().map(|material| (material.name.as_str(), material.area)).collect();
+        assert_eq!(areas, vec![("floor", 24.0), ("ceiling", 24.0), ("wall", 60.0)]);
+
+        let constant = 24.0 * f32::ln(10.0) / SPEED_OF_SOUND;
+        let air = air_attenuation(&statistics.frequencies, 20.0, 40.0, 101325.0);
+        for (band, air) in air.iter().enumerate() {
+            let air_absorption = 4.0 * air * f32::ln(10.0) / 10.0 * 72.0;
+            let sabine = constant * 72.0 / (108.0 * 0.2 + air_absorption);
+            let eyring = constant * 72.0 / (-108.0 * f32::ln(0.8) + air_absorption);
+            assert!((statistics.mean_absorption[band] - 0.2).abs() < 1e-5);
+            assert!((statistics.sabine[band] / sabine - 1.0).abs() < 1e-4);
+            assert!((statistics.eyring[band] / eyring - 1.0).abs() < 1e-4);
+            // with the same absorption on every surface Arau-Puchades' formula is Eyring's
+            assert!((statistics.arau_puchades[band] / eyring - 1.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn fully_absorbing_box_has_finite_times() {
+        let statistics = uniform_box(1.0);
+        for times in [&statistics.sabine, &statistics.eyring, &statistics.arau_puchades].iter() {
+            assert!(times.iter().all(|time| time.is_finite() && *time > 0.0), "{:?}", times);
+        }
+        assert!(statistics.eyring.iter().zip(statistics.sabine.iter()).all(|(eyring, sabine)| eyring < sabine));
+    }
+}
@@ -0,0 +1,226 @@
+use crate::signals::convolution::convolve;
+use crate::signals::resample::resample;
+use std::error::Error;
+
+/// A sample rate and de-interleaved channels of samples
+pub type Audio = (u32, Vec<Vec<f32>>);
+
+/// How the auralized signal is scaled before it is written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    /// write the convolution result as is
+    None,
+    /// scale the loudest sample of all channels to the given level in dBFS
+    Peak(f32),
+    /// scale the output so its RMS level matches the dry input
+    MatchInput,
+}
+
+impl Default for Normalization {
+    fn default() -> Normalization {
+        Normalization::Peak(-1.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuralizationSettings {
+    /// sample rate of the output, defaults to the impulse response's sample rate
+    pub sample_rate: Option<u32>,
+    pub normalization: Normalization,
+    /// partition length of the FFT convolution in samples
+    pub block_size: usize,
+}
+
+impl Default for AuralizationSettings {
+    fn default() -> AuralizationSettings {
+        AuralizationSettings {
+            sample_rate: None,
+            normalization: Normalization::default(),
+            block_size: 1024,
+        }
+    }
+}
+
+/// Reads a WAV file into de-interleaved channels of samples in the range -1..1
+pub fn read_wav(file_name: &str) -> Result<Audio, Box<dyn Error>> {
+    let mut reader = hound::WavReader::open(file_name)?;
+    let spec = reader.spec();
+    let interleaved: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<f32>, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = f32::powi(2.0, spec.bits_per_sample as i32 - 1);
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|x| x as f32 / scale))
+                .collect::<Result<Vec<f32>, _>>()?
+        }
+    };
+    let channels = spec.channels as usize;
+    let mut samples: Vec<Vec<f32>> = vec![Vec::with_capacity(interleaved.len() / channels); channels];
+    for (i, x) in interleaved.iter().enumerate() {
+        samples[i % channels].push(*x);
+    }
+    Ok((spec.sample_rate, samples))
+}
+
+/// Writes de-interleaved channels to a 32 bit float WAV file
+pub fn write_wav(file_name: &str, sample_rate: u32, samples: &[Vec<f32>]) -> Result<(), Box<dyn Error>> {
+    let spec = hound::WavSpec {
+        channels: samples.len() as u16,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(file_name, spec)?;
+    let length = samples.iter().map(|channel| channel.len()).max().unwrap_or(0);
+    for i in 0..length {
+        for channel in samples.iter() {
+            writer.write_sample(*channel.get(i).unwrap_or(&0.0))?;
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+fn rms(samples: &[Vec<f32>]) -> f32 {
+    let count: usize = samples.iter().map(|channel| channel.len()).sum();
+    let sum: f32 = samples.iter().flatten().map(|x| x * x).sum();
+    f32::sqrt(sum / usize::max(1, count) as f32)
+}
+
+/// Convolves a dry signal with a (mono, stereo or multichannel) impulse response
+///
+/// If the signal has as many channels as the impulse response they are convolved pairwise,
+/// otherwise the signal is mixed down to mono and convolved with every impulse response channel.
+/// Both are resampled to the output sample rate first.
+pub fn auralize(
+    signal: Audio,
+    impulse_response: Audio,
+    settings: &AuralizationSettings,
+) -> Result<Audio, Box<dyn Error>> {
+    let (signal_rate, mut signal) = signal;
+    let (ir_rate, impulse_response) = impulse_response;
+    if signal.is_empty() || impulse_response.is_empty() {
+        return Err("the signal and impulse response need at least one channel".into());
+    }
+    let sample_rate = settings.sample_rate.unwrap_or(ir_rate);
+
+    if signal.len() != impulse_response.len() {
+        let length = signal[0].len();
+        let gain = 1.0 / signal.len() as f32;
+        let mut mono = vec![0_f32; length];
+        for channel in signal.iter() {
+            for (i, x) in channel.iter().enumerate() {
+                mono[i] += x * gain;
+            }
+        }
+        signal = vec![mono; impulse_response.len()];
+    }
+    let signal: Vec<Vec<f32>> = signal.iter().map(|channel| resample(channel, signal_rate, sample_rate)).collect();
+    let impulse_response: Vec<Vec<f32>> = impulse_response.iter().map(|channel| resample(channel, ir_rate, sample_rate)).collect();
+
+    let mut output: Vec<Vec<f32>> = signal
+        .iter()
+        .zip(impulse_response.iter())
+        .map(|(x, h)| convolve(x, h, settings.block_size))
+        .collect();
+
+    let gain = match settings.normalization {
+        Normalization::None => 1.0,
+        Normalization::Peak(level) => {
+            let peak = output.iter().flatten().fold(0_f32, |max, x| f32::max(max, x.abs()));
+            if peak > 0.0 { f32::powf(10.0, level / 20.0) / peak } else { 1.0 }
+        }
+        Normalization::MatchInput => {
+            let output_rms = rms(&output);
+            if output_rms > 0.0 { rms(&signal) / output_rms } else { 1.0 }
+        }
+    };
+    for channel in output.iter_mut() {
+        for x in channel.iter_mut() {
+            *x *= gain;
+        }
+    }
+
+    Ok((sample_rate, output))
+}
+
+/// Convolves a dry WAV recording with a rendered impulse response and writes the result
+///
+/// # Arguments
+///
+/// * `input` - dry (anechoic) recording (.wav)
+/// * `impulse_response` - impulse response rendered by raya (.wav)
+/// * `output` - path of the auralized signal (.wav)
+/// * `settings` - sample rate, normalization and partition size
+///
+pub fn auralize_files(input: &str, impulse_response: &str, output: &str, settings: &AuralizationSettings) -> Result<(), Box<dyn Error>> {
+    let signal = read_wav(input)?;
+    let impulse_response = read_wav(impulse_response)?;
+    let (sample_rate, samples) = auralize(signal, impulse_response, settings)?;
+    write_wav(output, sample_rate, &samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(normalization: Normalization) -> AuralizationSettings {
+        AuralizationSettings { sample_rate: None, normalization, block_size: 16 }
+    }
+
+    #[test]
+    fn matching_channels_are_convolved_pairwise() {
+        let signal = (44100, vec![vec![1.0, 0.0, 0.5], vec![0.0, 2.0, 0.0]]);
+        let impulse_response = (44100, vec![vec![1.0, 0.5], vec![0.0, 1.0]]);
+        let (sample_rate, output) = auralize(signal, impulse_response, &settings(Normalization::None)).unwrap();
+        assert_eq!(sample_rate, 44100);
+        let expected = [vec![1.0, 0.5, 0.5, 0.25], vec![0.0, 0.0, 2.0, 0.0]];
+        for (channel, expected) in output.iter().zip(expected.iter()) {
+            assert_eq!(channel.len(), expected.len());
+            assert!(channel.iter().zip(expected.iter()).all(|(x, e)| (x - e).abs() < 1e-5), "{:?}", channel);
+        }
+    }
+
+    #[test]
+    fn other_channel_counts_are_mixed_down() {
+        // a stereo signal through a mono impulse response is mixed down to mono first
+        let stereo = (44100, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+        let (_, output) = auralize(stereo, (44100, vec![vec![1.0, 1.0]]), &settings(Normalization::None)).unwrap();
+        assert_eq!(output.len(), 1);
+        assert!(output[0].iter().zip([0.5, 1.0, 0.5].iter()).all(|(x, e)| (x - e).abs() < 1e-5), "{:?}", output[0]);
+
+        // a mono signal through a stereo impulse response reaches both channels
+        let mono = (44100, vec![vec![1.0, 0.0]]);
+        let (_, output) = auralize(mono, (44100, vec![vec![1.0], vec![0.0, -1.0]]), &settings(Normalization::None)).unwrap();
+        assert_eq!(output.len(), 2);
+        assert!((output[0][0] - 1.0).abs() < 1e-5 && (output[1][1] + 1.0).abs() < 1e-5);
+
+        assert!(auralize((44100, vec![]), (44100, vec![vec![1.0]]), &settings(Normalization::None)).is_err());
+    }
+
+    #[test]
+    fn output_is_normalized() {
+        let signal = || (44100, vec![vec![0.5, -0.25, 0.1, 0.0]]);
+        let impulse_response = || (44100, vec![vec![2.0, 1.0, 0.5]]);
+
+        let (_, output) = auralize(signal(), impulse_response(), &settings(Normalization::Peak(-6.0))).unwrap();
+        let peak = output.iter().flatten().fold(0_f32, |max, x| max.max(x.abs()));
+        assert!((peak - f32::powf(10.0, -6.0 / 20.0)).abs() < 1e-5);
+
+        let (_, output) = auralize(signal(), impulse_response(), &settings(Normalization::MatchInput)).unwrap();
+        assert!((rms(&output) - rms(&signal().1)).abs() < 1e-5);
+
+        let (_, output) = auralize(signal(), impulse_response(), &settings(Normalization::None)).unwrap();
+        assert!((output[0][0] - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn output_is_resampled() {
+        let output_settings = AuralizationSettings { sample_rate: Some(22050), ..settings(Normalization::None) };
+        let (sample_rate, output) = auralize((44100, vec![vec![0.0; 1000]]), (48000, vec![vec![1.0; 480]]), &output_settings).unwrap();
+        assert_eq!(sample_rate, 22050);
+        // 500 samples of signal and 221 of impulse response at the new rate
+        assert_eq!(output[0].len(), 500 + 221 - 1);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::geometry::{Mesh, MeshEdge};
+use std::collections::HashMap;
+
+/// Faces whose area is below this share of their longest edge squared are degenerate
+const DEGENERATE_RATIO: f32 = 1e-6;
+
+/// Problems in the topology of a mesh that let rays escape or hit surfaces from the wrong side
+#[derive(Debug, Clone, Default)]
+pub struct MeshDiagnostics {
+    /// edges used by a single face, where the surface has a gap or an open border
+    pub boundary_edges: Vec<MeshEdge>,
+    /// edges shared by more than two faces
+    pub non_manifold_edges: Vec<MeshEdge>,
+    /// edges whose two faces run along them in the same direction, so one of the faces is
+    /// wound the other way round
+    pub inconsistent_edges: Vec<MeshEdge>,
+    /// pairs of faces with the same corners, regardless of their winding
+    pub duplicate_faces: Vec<[usize; 2]>,
+    /// faces with no area, e.g. with two corners at the same position
+    pub degenerate_faces: Vec<usize>,
+}
+
+impl MeshDiagnostics {
+    /// Whether every edge is shared by exactly two faces
+    pub fn is_watertight(&self) -> bool {
+        self.boundary_edges.is_empty() && self.non_manifold_edges.is_empty()
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.is_watertight()
+            && self.inconsistent_edges.is_empty()
+            && self.duplicate_faces.is_empty()
+            && self.degenerate_faces.is_empty()
+    }
+}
+
+impl Mesh {
+    /// Checks the mesh for boundary, non-manifold and inconsistently wound edges, and for
+    /// duplicate and degenerate faces. Vertices at the same position are treated as one.
+    pub fn diagnostics(&self) -> MeshDiagnostics {
+        let welded = self.welded_vertices();
+        let mut diagnostics = MeshDiagnostics::default();
+
+        let mut corners: HashMap<[usize; 3], usize> = HashMap::new();
+        for (index, face) in self.faces.iter().enumerate() {
+            let [a, b, c] = [self.vertices[face[0]], self.vertices[face[1]], self.vertices[face[2]]];
+            let area = (b - a).cross(&(c - a)).magnitude() / 2.0;
+            let longest = (b - a).magnitude().max((c - b).magnitude()).max((a - c).magnitude());
+            let mut key = [welded[face[0]], welded[face[1]], welded[face[2]]];
+            if key[0] == key[1] || key[1] == key[2] || key[2] == key[0] || area <= DEGENERATE_RATIO * longest * longest {
+                diagnostics.degenerate_faces.push(index);
+                continue;
+            }
+            key.sort_unstable();
+            if let Some(first) = corners.get(&key) {
+                diagnostics.duplicate_faces.push([*first, index]);
+            } else {
+                corners.insert(key, index);
+            }
+        }
+
+        // whether a face runs along an edge from its first to its second vertex
+        let forward = |face: usize, edge: &MeshEdge| {
+            let face = self.faces[face];
+            (0..3).any(|i| welded[face[i]] == edge.vertices[0] && welded[face[(i + 1) % 3]] == edge.vertices[1])
+        };
+        for edge in self.edges() {
+            match edge.faces.len() {
+                1 => diagnostics.boundary_edges.push(edge),
+                2 => {
+                    if forward(edge.faces[0], &edge) == forward(edge.faces[1], &edge) {
+                        diagnostics.inconsistent_edges.push(edge);
+                    }
+                }
+                _ => diagnostics.non_manifold_edges.push(edge),
+            }
+        }
+        diagnostics
+    }
+}
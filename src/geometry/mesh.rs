@@ -1,23 +1,56 @@
+use crate::geometry::{ObjFile, read_ply, read_stl};
 use nalgebra::Vector3;
 use nalgebra::vector;
+use std::collections::HashMap;
 use std::error::Error;
 use std::f32;
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::BufReader;
 use std::iter::Iterator;
+use std::path::Path;
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct Mesh {
     pub vertices: Vec<Vector3<f32>>,
     // Each face represents three indexes in the vertices vec
     pub faces: Vec<[usize; 3]>,
+    // The index of each face's material in the owning node's materials, empty if the whole
+    // mesh uses the node's material
+    pub face_materials: Vec<usize>,
+    // The names of the materials the face material indexes refer to (e.g. OBJ usemtl names)
+    pub material_names: Vec<String>,
 
     // Track a bounding box to improve performance
     pub aabb_corner: Vector3<f32>,
     pub aabb_size: Vector3<f32>,
 }
 
+/// A named part of a model file, e.g. an OBJ group or an STL solid
+#[derive(Debug, Clone)]
+pub struct MeshObject {
+    pub name: String,
+    // The object's faces, with face materials referring to the mesh's material names
+    pub mesh: Mesh,
+}
+
+/// An edge of a mesh and the faces sharing it
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeshEdge {
+    // The indexes of the edge's end points in the vertices vec
+    pub vertices: [usize; 2],
+    // The indexes of the faces that contain the edge
+    pub faces: Vec<usize>,
+}
+
+/// The index of a material name in a list of names, adding the name if it is new
+pub(crate) fn material_slot(material_names: &mut Vec<String>, name: &str) -> usize {
+    match material_names.iter().position(|n| n == name) {
+        Some(index) => index,
+        None => {
+            material_names.push(name.to_string());
+            material_names.len() - 1
+        }
+    }
+}
+
 // Generate a bounding box for a set of vertices
 fn generate_bounding_box(vertices: &[Vector3<f32>]) -> (Vector3<f32>, Vector3<f32>) {
     let mut min = vector![f32::MAX, f32::MAX, f32::MAX];
@@ -53,61 +86,90 @@ impl Mesh {
         Mesh {
             vertices,
             faces,
+            face_materials: Vec::new(),
+            material_names: Vec::new(),
             aabb_corner,
             aabb_size,
         }
     }
-    // Load a mesh from a file
-    pub fn from_file(file_name: &str) -> Result<Mesh, Box<dyn Error>> {
-        let mut vertices = vec![];
-        let mut faces = vec![];
-        let file_reader = BufReader::new(File::open(file_name)?);
+    /// The material index of a face, `None` if the face uses the node's material
+    pub fn face_material(&self, face: usize) -> Option<usize> {
+        self.face_materials.get(face).copied()
+    }
+
+    /// Maps every vertex to the first vertex at the same position, so faces that were split
+    /// apart by the exporter (e.g. for flat shading) still share their edges
+    pub fn welded_vertices(&self) -> Vec<usize> {
+        let mut first_at_position: HashMap<[i64; 3], usize> = HashMap::new();
+        self.vertices
+            .iter()
+            .enumerate()
+            .map(|(index, vertex)| {
+                let key = [
+                    (vertex.x * 1e5).round() as i64,
+                    (vertex.y * 1e5).round() as i64,
+                    (vertex.z * 1e5).round() as i64,
+                ];
+                *first_at_position.entry(key).or_insert(index)
+            })
+            .collect()
+    }
 
-        for l in file_reader.lines() {
-            let line = l?;
-            let mut parts = line.split_whitespace();
-            let first = parts.next();
-            if first.is_some() {
-                match first.unwrap() {
-                    "v" => {
-                        let x: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
-                        let y: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
-                        let z: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
-                        vertices.push(vector![x, y, z]);
-                    }
-                    "f" => {
-                        let av: Vec<&str> = parts
-                            .next()
-                            .ok_or("Incorrect file format")?
-                            .split("/")
-                            .collect();
-                        let bv: Vec<&str> = parts
-                            .next()
-                            .ok_or("Incorrect file format")?
-                            .split("/")
-                            .collect();
-                        let cv: Vec<&str> = parts
-                            .next()
-                            .ok_or("Incorrect file format")?
-                            .split("/")
-                            .collect();
-                        let a: usize = av[0].parse()?;
-                        let b: usize = bv[0].parse()?;
-                        let c: usize = cv[0].parse()?;
-                        faces.push([a - 1, b - 1, c - 1]);
-                    }
-                    // Ignore all other lines
-                    _ => {}
+    /// Returns the edges of the mesh with the faces sharing each of them. Vertices at the same
+    /// position are treated as one, and the edges are listed in the order they are first found.
+    pub fn edges(&self) -> Vec<MeshEdge> {
+        let welded = self.welded_vertices();
+        let mut edges: Vec<MeshEdge> = Vec::new();
+        let mut edge_index: HashMap<[usize; 2], usize> = HashMap::new();
+        for (face_index, face) in self.faces.iter().enumerate() {
+            for i in 0..3 {
+                let a = welded[face[i]];
+                let b = welded[face[(i + 1) % 3]];
+                if a == b {
+                    continue;
                 }
+                let key = if a < b { [a, b] } else { [b, a] };
+                let index = *edge_index.entry(key).or_insert_with(|| {
+                    edges.push(MeshEdge { vertices: key, faces: Vec::new() });
+                    edges.len() - 1
+                });
+                edges[index].faces.push(face_index);
             }
         }
+        edges
+    }
 
-        let (aabb_corner, aabb_size) = generate_bounding_box(&vertices);
-        Ok(Mesh {
-            vertices,
-            faces,
-            aabb_corner,
-            aabb_size,
-        })
+    /// Combines the meshes of several objects into one, joining materials with the same name
+    pub fn merged(objects: &[MeshObject]) -> Mesh {
+        let mut vertices = Vec::new();
+        let mut faces = Vec::new();
+        let mut material_names: Vec<String> = Vec::new();
+        let mut face_materials = Vec::new();
+        let any_materials = objects.iter().any(|object| !object.mesh.material_names.is_empty());
+        for object in objects.iter() {
+            let offset = vertices.len();
+            vertices.extend(object.mesh.vertices.iter());
+            for (index, face) in object.mesh.faces.iter().enumerate() {
+                faces.push([face[0] + offset, face[1] + offset, face[2] + offset]);
+                if any_materials {
+                    let name = object.mesh.face_material(index).map(|m| object.mesh.material_names[m].as_str()).unwrap_or("");
+                    face_materials.push(material_slot(&mut material_names, name));
+                }
+            }
+        }
+        let mut mesh = Mesh::new(vertices, faces);
+        mesh.face_materials = face_materials;
+        mesh.material_names = material_names;
+        mesh
+    }
+
+    // Load a mesh from an OBJ, STL or PLY file, joining all of its objects
+    pub fn from_file(file_name: &str) -> Result<Mesh, Box<dyn Error>> {
+        let objects = match Path::new(file_name).extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("stl") => read_stl(file_name)?,
+            Some(extension) if extension.eq_ignore_ascii_case("ply") => read_ply(file_name)?,
+            _ => ObjFile::from_file(file_name)?.objects,
+        };
+        Ok(Mesh::merged(&objects))
     }
 }
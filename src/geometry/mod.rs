@@ -1,10 +1,19 @@
+mod diagnostics;
 mod mesh;
+mod obj;
+mod ply;
 mod primitive;
 mod ray;
+mod stl;
 
-pub use self::mesh::Mesh;
+pub use self::diagnostics::MeshDiagnostics;
+pub use self::mesh::{Mesh, MeshEdge, MeshObject};
+pub use self::obj::{MtlMaterial, ObjFile, read_mtl};
+pub use self::ply::read_ply;
 pub use self::primitive::Primitive;
+pub(crate) use self::primitive::triangle_collides;
 pub use self::ray::Ray;
+pub use self::stl::read_stl;
 
 use nalgebra::{Vector3, vector};
 use roots::Roots;
@@ -0,0 +1,347 @@
+use crate::geometry::{Mesh, MeshObject};
+use crate::geometry::mesh::material_slot;
+use nalgebra::Vector3;
+use nalgebra::vector;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// The geometry of a Wavefront OBJ file, split into its objects and groups
+#[derive(Debug, Clone)]
+pub struct ObjFile {
+    pub objects: Vec<MeshObject>,
+    // The .mtl files named by mtllib statements
+    pub material_libraries: Vec<String>,
+}
+
+/// Faces of an object under construction, referring to the file's vertex list
+struct ObjectBuilder {
+    name: String,
+    faces: Vec<[usize; 3]>,
+    // The material name of each face, None before the first usemtl statement
+    face_materials: Vec<Option<String>>,
+}
+
+impl ObjectBuilder {
+    fn new(name: String) -> ObjectBuilder {
+        ObjectBuilder {
+            name,
+            faces: Vec::new(),
+            face_materials: Vec::new(),
+        }
+    }
+
+    /// Builds a mesh holding only the vertices the faces use
+    fn build(self, vertices: &[Vector3<f32>]) -> MeshObject {
+        let mut index_map: HashMap<usize, usize> = HashMap::new();
+        let mut mesh_vertices = Vec::new();
+        let faces: Vec<[usize; 3]> = self
+            .faces
+            .iter()
+            .map(|face| {
+                let mut mapped = [0; 3];
+                for (i, index) in face.iter().enumerate() {
+                    mapped[i] = *index_map.entry(*index).or_insert_with(|| {
+                        mesh_vertices.push(vertices[*index]);
+                        mesh_vertices.len() - 1
+                    });
+                }
+                mapped
+            })
+            .collect();
+
+        let mut material_names: Vec<String> = Vec::new();
+        let mut face_materials = Vec::new();
+        if self.face_materials.iter().any(|material| material.is_some()) {
+            // faces without a usemtl statement get a material slot with an empty name
+            for material in self.face_materials.iter() {
+                face_materials.push(material_slot(&mut material_names, material.as_deref().unwrap_or("")));
+            }
+        }
+
+        let mut mesh = Mesh::new(mesh_vertices, faces);
+        mesh.face_materials = face_materials;
+        mesh.material_names = material_names;
+        MeshObject { name: self.name, mesh }
+    }
+}
+
+/// Parses a vertex reference of a face (`v`, `v/vt`, `v//vn` or `v/vt/vn`), where negative
+/// indices count back from the last vertex read
+fn vertex_index(token: &str, vertex_count: usize) -> Result<usize, Box<dyn Error>> {
+    let index: i64 = token.split('/').next().ok_or("Incorrect file format")?.parse()?;
+    let resolved = if index > 0 {
+        index - 1
+    } else {
+        vertex_count as i64 + index
+    };
+    if index == 0 || resolved < 0 || resolved >= vertex_count as i64 {
+        return Err(format!("vertex index {} out of range", index).into());
+    }
+    Ok(resolved as usize)
+}
+
+/// Splits a planar polygon into triangles by ear clipping, keeping the polygon's winding.
+/// Returns indexes into `points`.
+pub fn triangulate(points: &[Vector3<f32>]) -> Vec<[usize; 3]> {
+    let n = points.len();
+    if n < 3 {
+        return Vec::new();
+    }
+    if n == 3 {
+        return vec![[0, 1, 2]];
+    }
+
+    // project onto the plane of the polygon's dominant normal axis (Newell's method)
+    let mut normal = Vector3::zeros();
+    for i in 0..n {
+        let (a, b) = (points[i], points[(i + 1) % n]);
+        normal += vector![(a.y - b.y) * (a.z + b.z), (a.z - b.z) * (a.x + b.x), (a.x - b.x) * (a.y + b.y)];
+    }
+    let axis = normal.iamax();
+    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+    let orientation = normal[axis].signum();
+    let cross = |a: usize, b: usize, c: usize| {
+        let (pa, pb, pc) = (points[a], points[b], points[c]);
+        ((pb[u] - pa[u]) * (pc[v] - pa[v]) - (pb[v] - pa[v]) * (pc[u] - pa[u])) * orientation
+    };
+
+    let mut remaining: Vec<usize> = (0..n).collect();
+    let mut triangles = Vec::with_capacity(n - 2);
+    while remaining.len() > 3 {
+        let m = remaining.len();
+        let ear = (0..m).find(|&i| {
+            let (a, b, c) = (remaining[(i + m - 1) % m], remaining[i], remaining[(i + 1) % m]);
+            if cross(a, b, c) <= 0.0 {
+                return false;
+            }
+            // no other vertex may lie inside the ear
+            remaining
+                .iter()
+                .filter(|&&p| p != a && p != b && p != c)
+                .all(|&p| cross(a, b, p) < 0.0 || cross(b, c, p) < 0.0 || cross(c, a, p) < 0.0)
+        });
+        match ear {
+            Some(i) => {
+                triangles.push([remaining[(i + m - 1) % m], remaining[i], remaining[(i + 1) % m]]);
+                remaining.remove(i);
+            }
+            // degenerate polygon, fall back to a fan
+            None => break,
+        }
+    }
+    for i in 1..remaining.len() - 1 {
+        triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+    }
+    triangles
+}
+
+impl ObjFile {
+    pub fn from_file(file_name: &str) -> Result<ObjFile, Box<dyn Error>> {
+        let file_reader = BufReader::new(File::open(file_name)?);
+        let mut vertices: Vec<Vector3<f32>> = vec![];
+        let mut objects: Vec<MeshObject> = vec![];
+        let mut material_libraries: Vec<String> = vec![];
+        let mut current = ObjectBuilder::new("default".to_string());
+        let mut material: Option<String> = None;
+
+        for l in file_reader.lines() {
+            let line = l?;
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("v") => {
+                    let x: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
+                    let y: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
+                    let z: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
+                    vertices.push(vector![x, y, z]);
+                }
+                Some("f") => {
+                    let polygon = parts
+                        .map(|token| vertex_index(token, vertices.len()))
+                        .collect::<Result<Vec<usize>, _>>()?;
+                    if polygon.len() < 3 {
+                        return Err("Incorrect file format, a face needs at least three vertices".into());
+                    }
+                    let points: Vec<Vector3<f32>> = polygon.iter().map(|index| vertices[*index]).collect();
+                    for triangle in triangulate(&points) {
+                        current.faces.push([polygon[triangle[0]], polygon[triangle[1]], polygon[triangle[2]]]);
+                        current.face_materials.push(material.clone());
+                    }
+                }
+                Some("o") | Some("g") => {
+                    let name = parts.collect::<Vec<&str>>().join(" ");
+                    let previous = std::mem::replace(&mut current, ObjectBuilder::new(name));
+                    if !previous.faces.is_empty() {
+                        objects.push(previous.build(&vertices));
+                    }
+                }
+                Some("usemtl") => {
+                    material = Some(parts.collect::<Vec<&str>>().join(" "));
+                }
+                Some("mtllib") => {
+                    material_libraries.extend(parts.map(|name| name.to_string()));
+                }
+                // Ignore all other lines
+                _ => {}
+            }
+        }
+        if !current.faces.is_empty() {
+            objects.push(current.build(&vertices));
+        }
+
+        Ok(ObjFile {
+            objects,
+            material_libraries,
+        })
+    }
+
+    /// Combines all objects into a single mesh
+    pub fn merged(&self) -> Mesh {
+        Mesh::merged(&self.objects)
+    }
+}
+
+/// A named material of an MTL file with its keys and values
+pub type MtlMaterial = (String, Map<String, Value>);
+
+/// Reads the materials of an MTL file as maps from keys to values, which can hold the same
+/// acoustic keys as glTF material extras (`abs63` ... `abs8000`, `scattering`, `tl63` ...,
+/// `material`). A single number becomes a number, several numbers an array, anything else a string.
+pub fn read_mtl(file_name: &str) -> Result<Vec<MtlMaterial>, Box<dyn Error>> {
+    let file_reader = BufReader::new(File::open(file_name)?);
+    let mut materials: Vec<MtlMaterial> = vec![];
+
+    for l in file_reader.lines() {
+        let line = l?;
+        let mut parts = line.split_whitespace();
+        let key = match parts.next() {
+            Some(key) if !key.starts_with('#') => key,
+            _ => continue,
+        };
+        let tokens: Vec<&str> = parts.collect();
+        if key == "newmtl" {
+            materials.push((tokens.join(" "), Map::new()));
+            continue;
+        }
+        let values: Option<Vec<f64>> = tokens.iter().map(|token| token.parse().ok()).collect();
+        let value = match values {
+            Some(values) if values.len() == 1 => Value::from(values[0]),
+            Some(values) if values.len() > 1 => Value::from(values),
+            _ => Value::from(tokens.join(" ")),
+        };
+        match materials.last_mut() {
+            Some((_, material)) => {
+                material.insert(key.to_string(), value);
+            }
+            None => return Err("Incorrect file format, expected newmtl".into()),
+        }
+    }
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_vertex_indices() {
+        assert_eq!(vertex_index("1", 4).unwrap(), 0);
+        assert_eq!(vertex_index("4/2/1", 4).unwrap(), 3);
+        assert_eq!(vertex_index("2//3", 4).unwrap(), 1);
+        // negative indices count back from the last vertex read
+        assert_eq!(vertex_index("-1", 4).unwrap(), 3);
+        assert_eq!(vertex_index("-4/1", 4).unwrap(), 0);
+        assert!(vertex_index("0", 4).is_err());
+        assert!(vertex_index("5", 4).is_err());
+        assert!(vertex_index("-5", 4).is_err());
+        assert!(vertex_index("a", 4).is_err());
+    }
+
+    // twice the signed area of a triangle in the xy plane
+    fn area(points: &[Vector3<f32>], triangle: &[usize; 3]) -> f32 {
+        let (a, b, c) = (points[triangle[0]], points[triangle[1]], points[triangle[2]]);
+        (b - a).cross(&(c - a)).z
+    }
+
+    #[test]
+    fn triangulates_concave_polygon() {
+        // an L shape, whose reflex corner (3) rules out a fan from vertex 0 or 4
+        let points = vec![
+            vector![0.0, 0.0, 0.0],
+            vector![2.0, 0.0, 0.0],
+            vector![2.0, 1.0, 0.0],
+            vector![1.0, 1.0, 0.0],
+            vector![1.0, 2.0, 0.0],
+            vector![0.0, 2.0, 0.0],
+        ];
+        let triangles = triangulate(&points);
+        assert_eq!(triangles.len(), 4);
+        // every triangle keeps the polygon's counterclockwise winding and together they cover it
+        assert!(triangles.iter().all(|triangle| area(&points, triangle) > 0.0));
+        let total: f32 = triangles.iter().map(|triangle| area(&points, triangle)).sum();
+        assert!((total - 6.0).abs() < 1e-5);
+
+        // the winding of a clockwise polygon is kept too
+        let reversed: Vec<Vector3<f32>> = points.iter().rev().cloned().collect();
+        let triangles = triangulate(&reversed);
+        assert!(triangles.iter().all(|triangle| area(&reversed, triangle) < 0.0));
+        let total: f32 = triangles.iter().map(|triangle| area(&reversed, triangle)).sum();
+        assert!((total + 6.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn triangulates_polygon_in_any_plane() {
+        // a pentagon in the yz plane
+        let points = vec![
+            vector![1.0, 0.0, 0.0],
+            vector![1.0, 2.0, 0.0],
+            vector![1.0, 2.0, 2.0],
+            vector![1.0, 1.0, 3.0],
+            vector![1.0, 0.0, 2.0],
+        ];
+        let triangles = triangulate(&points);
+        assert_eq!(triangles.len(), 3);
+        let total: f32 = triangles
+            .iter()
+            .map(|t| (points[t[1]] - points[t[0]]).cross(&(points[t[2]] - points[t[0]])).x)
+            .sum();
+        assert!((total - 10.0).abs() < 1e-5);
+        assert!(triangulate(&points[..2]).is_empty());
+    }
+
+    #[test]
+    fn reads_negative_indices_and_ngons() {
+        let file_name = std::env::temp_dir().join(format!("raya_obj_test_{}.obj", std::process::id()));
+        let contents = "v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+o floor
+usemtl concrete
+f -4 -3 -2 -1
+o ceiling
+v 0 0 1
+v 1 0 1
+v 1 1 1
+f -3/1 -2/2/2 -1//3
+";
+        std::fs::write(&file_name, contents).unwrap();
+        let obj = ObjFile::from_file(file_name.to_str().unwrap());
+        std::fs::remove_file(&file_name).unwrap();
+        let obj = obj.unwrap();
+
+        assert_eq!(obj.objects.len(), 2);
+        let floor = &obj.objects[0];
+        assert_eq!(floor.name, "floor");
+        assert_eq!(floor.mesh.vertices.len(), 4);
+        assert_eq!(floor.mesh.faces.len(), 2);
+        assert_eq!(floor.mesh.material_names, vec!["concrete".to_string()]);
+        assert_eq!(floor.mesh.face_materials, vec![0, 0]);
+        let ceiling = &obj.objects[1];
+        assert_eq!(ceiling.name, "ceiling");
+        assert_eq!(ceiling.mesh.vertices, vec![vector![0.0, 0.0, 1.0], vector![1.0, 0.0, 1.0], vector![1.0, 1.0, 1.0]]);
+        assert_eq!(ceiling.mesh.faces, vec![[0, 1, 2]]);
+    }
+}
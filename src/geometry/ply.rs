@@ -0,0 +1,339 @@
+use crate::geometry::{Mesh, MeshObject};
+use crate::geometry::mesh::material_slot;
+use crate::geometry::obj::triangulate;
+use nalgebra::Vector3;
+use nalgebra::vector;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Face properties that hold the material ID of a face
+const MATERIAL_PROPERTIES: [&str; 3] = ["material_id", "material_index", "material"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Scalar {
+    Int8,
+    Uint8,
+    Int16,
+    Uint16,
+    Int32,
+    Uint32,
+    Float32,
+    Float64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Scalar, Box<dyn Error>> {
+        Ok(match name {
+            "char" | "int8" => Scalar::Int8,
+            "uchar" | "uint8" => Scalar::Uint8,
+            "short" | "int16" => Scalar::Int16,
+            "ushort" | "uint16" => Scalar::Uint16,
+            "int" | "int32" => Scalar::Int32,
+            "uint" | "uint32" => Scalar::Uint32,
+            "float" | "float32" => Scalar::Float32,
+            "double" | "float64" => Scalar::Float64,
+            _ => return Err(format!("unknown PLY property type {}", name).into()),
+        })
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Scalar::Int8 | Scalar::Uint8 => 1,
+            Scalar::Int16 | Scalar::Uint16 => 2,
+            Scalar::Int32 | Scalar::Uint32 | Scalar::Float32 => 4,
+            Scalar::Float64 => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum PropertyType {
+    Scalar(Scalar),
+    // A list with the type of its length and of its items
+    List(Scalar, Scalar),
+}
+
+#[derive(Debug, Clone)]
+struct Property {
+    name: String,
+    kind: PropertyType,
+}
+
+#[derive(Debug, Clone)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/// Reads the values of the file body, as text or in binary
+struct BodyReader<'a> {
+    format: Format,
+    data: &'a [u8],
+    position: usize,
+    tokens: std::str::SplitAsciiWhitespace<'a>,
+}
+
+impl<'a> BodyReader<'a> {
+    fn read(&mut self, scalar: Scalar) -> Result<f64, Box<dyn Error>> {
+        if self.format == Format::Ascii {
+            return Ok(self.tokens.next().ok_or("Incorrect file format, unexpected end of file")?.parse()?);
+        }
+        let size = scalar.size();
+        let mut bytes = [0u8; 8];
+        bytes[..size].copy_from_slice(
+            self.data
+                .get(self.position..self.position + size)
+                .ok_or("Incorrect file format, unexpected end of file")?,
+        );
+        self.position += size;
+        if self.format == Format::BinaryBigEndian {
+            bytes[..size].reverse();
+        }
+        Ok(match scalar {
+            Scalar::Int8 => i8::from_le_bytes([bytes[0]]) as f64,
+            Scalar::Uint8 => bytes[0] as f64,
+            Scalar::Int16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+            Scalar::Uint16 => u16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+            Scalar::Int32 => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            Scalar::Uint32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            Scalar::Float32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            Scalar::Float64 => f64::from_le_bytes(bytes),
+        })
+    }
+
+    /// Reads all values of a property, a list gives its items
+    fn read_property(&mut self, kind: &PropertyType) -> Result<Vec<f64>, Box<dyn Error>> {
+        match kind {
+            PropertyType::Scalar(scalar) => Ok(vec![self.read(*scalar)?]),
+            PropertyType::List(length, item) => {
+                let length = self.read(*length)? as usize;
+                (0..length).map(|_| self.read(*item)).collect()
+            }
+        }
+    }
+}
+
+/// Reads a mesh from an ASCII or binary PLY file. The material of each face is read from its
+/// `material_id` (or `material_index`, `material`) property; faces get materials named after
+/// the ID, which settings files can assign acoustic materials to.
+pub fn read_ply(file_name: &str) -> Result<Vec<MeshObject>, Box<dyn Error>> {
+    let contents = fs::read(file_name)?;
+    let name = Path::new(file_name).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    parse_ply(&contents, &name)
+}
+
+fn parse_ply(contents: &[u8], name: &str) -> Result<Vec<MeshObject>, Box<dyn Error>> {
+    let header_end = contents
+        .windows(10)
+        .position(|window| window == b"end_header")
+        .ok_or("Incorrect file format, PLY header has no end_header")?;
+    let header = String::from_utf8_lossy(&contents[..header_end]);
+    // the body starts after the line break that ends the header
+    let mut body_start = header_end + 10;
+    while body_start < contents.len() && contents[body_start] != b'\n' {
+        body_start += 1;
+    }
+    body_start += 1;
+
+    let mut lines = header.lines();
+    if lines.next().map(|line| line.trim()) != Some("ply") {
+        return Err("Incorrect file format, not a PLY file".into());
+    }
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts.as_slice() {
+            ["format", "ascii", ..] => format = Some(Format::Ascii),
+            ["format", "binary_little_endian", ..] => format = Some(Format::BinaryLittleEndian),
+            ["format", "binary_big_endian", ..] => format = Some(Format::BinaryBigEndian),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse()?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", length, item, name] => elements
+                .last_mut()
+                .ok_or("Incorrect file format, property outside of an element")?
+                .properties
+                .push(Property {
+                    name: name.to_string(),
+                    kind: PropertyType::List(Scalar::parse(length)?, Scalar::parse(item)?),
+                }),
+            ["property", scalar, name] => elements
+                .last_mut()
+                .ok_or("Incorrect file format, property outside of an element")?
+                .properties
+                .push(Property {
+                    name: name.to_string(),
+                    kind: PropertyType::Scalar(Scalar::parse(scalar)?),
+                }),
+            // Ignore comments and obj_info
+            _ => {}
+        }
+    }
+    let format = format.ok_or("Incorrect file format, PLY header has no format")?;
+
+    let data = &contents[body_start.min(contents.len())..];
+    let text = if format == Format::Ascii { std::str::from_utf8(data)? } else { "" };
+    let mut reader = BodyReader {
+        format,
+        data,
+        position: 0,
+        tokens: text.split_ascii_whitespace(),
+    };
+
+    let mut vertices: Vec<Vector3<f32>> = Vec::new();
+    let mut faces: Vec<[usize; 3]> = Vec::new();
+    let mut face_materials: Vec<usize> = Vec::new();
+    let mut material_names: Vec<String> = Vec::new();
+    for element in elements.iter() {
+        for _ in 0..element.count {
+            let mut position = [0.0f32; 3];
+            let mut polygon: Vec<usize> = Vec::new();
+            let mut material: Option<i64> = None;
+            for property in element.properties.iter() {
+                let values = reader.read_property(&property.kind)?;
+                match (element.name.as_str(), property.name.as_str()) {
+                    ("vertex", "x") => position[0] = values[0] as f32,
+                    ("vertex", "y") => position[1] = values[0] as f32,
+                    ("vertex", "z") => position[2] = values[0] as f32,
+                    ("face", "vertex_indices") | ("face", "vertex_index") => {
+                        polygon = values.iter().map(|index| *index as usize).collect();
+                    }
+                    ("face", name) if MATERIAL_PROPERTIES.contains(&name) => material = values.first().map(|id| *id as i64),
+                    _ => {}
+                }
+            }
+            match element.name.as_str() {
+                "vertex" => vertices.push(vector![position[0], position[1], position[2]]),
+                "face" => {
+                    if polygon.iter().any(|index| *index >= vertices.len()) {
+                        return Err("Incorrect file format, face refers to a missing vertex".into());
+                    }
+                    let points: Vec<Vector3<f32>> = polygon.iter().map(|index| vertices[*index]).collect();
+                    for triangle in triangulate(&points) {
+                        faces.push([polygon[triangle[0]], polygon[triangle[1]], polygon[triangle[2]]]);
+                        if let Some(id) = material {
+                            face_materials.push(material_slot(&mut material_names, &id.to_string()));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut mesh = Mesh::new(vertices, faces);
+    if face_materials.len() == mesh.faces.len() {
+        mesh.face_materials = face_materials;
+        mesh.material_names = material_names;
+    }
+    Ok(vec![MeshObject { name: name.to_string(), mesh }])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_ascii_polygons_and_materials() {
+        let contents = b"ply
+format ascii 1.0
+comment a quad and a triangle
+element vertex 5
+property float x
+property float y
+property float z
+element face 2
+property list uchar int vertex_indices
+property int material_id
+end_header
+0 0 0
+2 0 0
+2 1 0
+0 1 0
+1 0.5 2
+4 0 1 2 3 7
+3 0 1 4 2
+";
+        let objects = parse_ply(contents, "room").unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].name, "room");
+        let mesh = &objects[0].mesh;
+        assert_eq!(mesh.vertices.len(), 5);
+        assert_eq!(mesh.vertices[4], vector![1.0, 0.5, 2.0]);
+        // the quad is split into two triangles
+        assert_eq!(mesh.faces.len(), 3);
+        assert_eq!(mesh.faces[2], [0, 1, 4]);
+        assert_eq!(mesh.material_names, vec!["7".to_string(), "2".to_string()]);
+        assert_eq!(mesh.face_materials, vec![0, 0, 1]);
+    }
+
+    fn binary_ply(format: &str, to_bytes: fn(f32) -> [u8; 4], index_bytes: fn(i32) -> [u8; 4]) -> Vec<u8> {
+        let mut contents = format!(
+            "ply\nformat {} 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\n\
+             element face 1\nproperty list uchar int vertex_indices\nend_header\n",
+            format
+        )
+        .into_bytes();
+        for coordinate in [0.0, 0.0, 0.0, 1.5, 0.0, 0.0, 0.0, -2.0, 0.25] {
+            contents.extend_from_slice(&to_bytes(coordinate));
+        }
+        contents.push(3);
+        for index in [0, 1, 2] {
+            contents.extend_from_slice(&index_bytes(index));
+        }
+        contents
+    }
+
+    #[test]
+    fn reads_binary_little_and_big_endian() {
+        for contents in [
+            binary_ply("binary_little_endian", f32::to_le_bytes, i32::to_le_bytes),
+            binary_ply("binary_big_endian", f32::to_be_bytes, i32::to_be_bytes),
+        ] {
+            let objects = parse_ply(&contents, "part").unwrap();
+            let mesh = &objects[0].mesh;
+            assert_eq!(mesh.vertices[1], vector![1.5, 0.0, 0.0]);
+            assert_eq!(mesh.vertices[2], vector![0.0, -2.0, 0.25]);
+            assert_eq!(mesh.faces, vec![[0, 1, 2]]);
+            assert!(mesh.material_names.is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_faces_with_missing_vertices() {
+        let contents = b"ply
+format ascii 1.0
+element vertex 3
+property float x
+property float y
+property float z
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0
+1 0 0
+0 1 0
+3 0 1 3
+";
+        assert!(parse_ply(contents, "broken").is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_binary_body() {
+        let mut contents = binary_ply("binary_little_endian", f32::to_le_bytes, i32::to_le_bytes);
+        contents.truncate(contents.len() - 2);
+        assert!(parse_ply(&contents, "part").is_err());
+    }
+}
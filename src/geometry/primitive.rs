@@ -28,13 +28,15 @@ impl Primitive {
         t_value: &mut f32,
         normal: &mut Vector3<f32>,
         uv: &mut [f32; 2],
+        material: &mut Option<usize>,
     ) -> bool {
+        *material = None;
         match self {
             Primitive::Sphere => sphere_collides(ray, t_value, normal),
             Primitive::Cylinder => cylinder_collides(ray, t_value, normal, uv),
             Primitive::Cone => cone_collides(ray, t_value, normal),
             Primitive::Cube => cube_collides(ray, t_value, normal),
-            Primitive::Mesh(mesh) => mesh_collides(ray, mesh, t_value, normal, uv),
+            Primitive::Mesh(mesh) => mesh_collides(ray, mesh, t_value, normal, uv, material),
             _ => false,
         }
     }
@@ -102,7 +104,7 @@ fn sphere_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>) -> b
     }
 }
 
-fn triangle_collides(
+pub(crate) fn triangle_collides(
     ray: &Ray,
     triangle: &[Vector3<f32>; 3],
     t_value: &mut f32,
@@ -262,6 +264,7 @@ fn mesh_collides(
     t_value: &mut f32,
     normal: &mut Vector3<f32>,
     uv: &mut [f32; 2],
+    material: &mut Option<usize>,
 ) -> bool {
     if aabb_collision(ray, &mesh.aabb_corner, &mesh.aabb_size) == Roots::No([]) {
         return false;
@@ -269,9 +272,10 @@ fn mesh_collides(
 
     let mut smallest_t = f32::MAX;
     let mut smallest_normal = vector![0.0f32, 0.0f32, 0.0f32];
+    let mut smallest_face = 0;
     let mut triangle = [smallest_normal, smallest_normal, smallest_normal];
 
-    for face in mesh.faces.iter() {
+    for (index, face) in mesh.faces.iter().enumerate() {
         triangle[0] = mesh.vertices[face[0]];
         triangle[1] = mesh.vertices[face[1]];
         triangle[2] = mesh.vertices[face[2]];
@@ -280,6 +284,7 @@ fn mesh_collides(
             if *t_value < smallest_t {
                 smallest_t = *t_value;
                 smallest_normal = *normal;
+                smallest_face = index;
             }
         }
     }
@@ -292,6 +297,7 @@ fn mesh_collides(
             uv[0] = intersect.x;
         }
         uv[1] = intersect.z;
+        *material = mesh.face_material(smallest_face);
     }
 
     *normal = smallest_normal;
@@ -0,0 +1,231 @@
+use crate::geometry::{Mesh, MeshObject};
+use nalgebra::Vector3;
+use nalgebra::vector;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Collects the triangles of an STL solid, joining vertices at identical positions
+struct SolidBuilder {
+    name: String,
+    vertices: Vec<Vector3<f32>>,
+    faces: Vec<[usize; 3]>,
+    vertex_index: HashMap<[u32; 3], usize>,
+}
+
+impl SolidBuilder {
+    fn new(name: String) -> SolidBuilder {
+        SolidBuilder {
+            name,
+            vertices: Vec::new(),
+            faces: Vec::new(),
+            vertex_index: HashMap::new(),
+        }
+    }
+
+    fn add_vertex(&mut self, vertex: Vector3<f32>) -> usize {
+        let key = [vertex.x.to_bits(), vertex.y.to_bits(), vertex.z.to_bits()];
+        let vertices = &mut self.vertices;
+        *self.vertex_index.entry(key).or_insert_with(|| {
+            vertices.push(vertex);
+            vertices.len() - 1
+        })
+    }
+
+    fn add_triangle(&mut self, triangle: [Vector3<f32>; 3]) {
+        let face = [self.add_vertex(triangle[0]), self.add_vertex(triangle[1]), self.add_vertex(triangle[2])];
+        self.faces.push(face);
+    }
+
+    /// Builds the solid's mesh. Named solids get a material of the same name, so that they can
+    /// be assigned acoustic materials.
+    fn build(self) -> MeshObject {
+        let mut mesh = Mesh::new(self.vertices, self.faces);
+        if !self.name.is_empty() {
+            mesh.face_materials = vec![0; mesh.faces.len()];
+            mesh.material_names = vec![self.name.clone()];
+        }
+        MeshObject { name: self.name, mesh }
+    }
+}
+
+fn read_ascii_stl(contents: &str) -> Result<Vec<MeshObject>, Box<dyn Error>> {
+    let mut objects = Vec::new();
+    let mut solid: Option<SolidBuilder> = None;
+    let mut triangle: Vec<Vector3<f32>> = Vec::with_capacity(3);
+
+    for line in contents.lines() {
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some("solid") => {
+                solid = Some(SolidBuilder::new(parts.collect::<Vec<&str>>().join(" ")));
+            }
+            Some("vertex") => {
+                let x: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
+                let y: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
+                let z: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
+                triangle.push(vector![x, y, z]);
+            }
+            Some("endloop") => {
+                if triangle.len() != 3 {
+                    return Err("Incorrect file format, a facet needs three vertices".into());
+                }
+                solid
+                    .as_mut()
+                    .ok_or("Incorrect file format, facet outside of a solid")?
+                    .add_triangle([triangle[0], triangle[1], triangle[2]]);
+                triangle.clear();
+            }
+            Some("endsolid") => {
+                if let Some(builder) = solid.take() {
+                    objects.push(builder.build());
+                }
+            }
+            // facet normals are recalculated from the vertices
+            _ => {}
+        }
+    }
+    if let Some(builder) = solid {
+        objects.push(builder.build());
+    }
+    Ok(objects)
+}
+
+fn read_binary_stl(contents: &[u8], name: &str) -> Result<Vec<MeshObject>, Box<dyn Error>> {
+    if contents.len() < 84 {
+        return Err("Incorrect file format, STL file is too short".into());
+    }
+    let count = u32::from_le_bytes([contents[80], contents[81], contents[82], contents[83]]) as usize;
+    if contents.len() < 84 + count * 50 {
+        return Err("Incorrect file format, STL file is too short for its triangle count".into());
+    }
+    let float = |offset: usize| f32::from_le_bytes([contents[offset], contents[offset + 1], contents[offset + 2], contents[offset + 3]]);
+
+    let mut solid = SolidBuilder::new(String::new());
+    for i in 0..count {
+        // each triangle has a normal, three vertices and a 2 byte attribute
+        let offset = 84 + i * 50 + 12;
+        let vertex = |v: usize| vector![float(offset + v * 12), float(offset + v * 12 + 4), float(offset + v * 12 + 8)];
+        solid.add_triangle([vertex(0), vertex(1), vertex(2)]);
+    }
+    let mut object = solid.build();
+    object.name = name.to_string();
+    Ok(vec![object])
+}
+
+/// Reads the solids of an ASCII or binary STL file. Binary files have a single solid named
+/// after the file.
+pub fn read_stl(file_name: &str) -> Result<Vec<MeshObject>, Box<dyn Error>> {
+    let contents = fs::read(file_name)?;
+    // binary files may start with "solid" too, so their size is checked first
+    let binary_size = if contents.len() >= 84 {
+        84 + 50 * u32::from_le_bytes([contents[80], contents[81], contents[82], contents[83]]) as usize
+    } else {
+        0
+    };
+    if contents.len() != binary_size && contents.starts_with(b"solid") {
+        read_ascii_stl(&String::from_utf8_lossy(&contents))
+    } else {
+        let name = Path::new(file_name).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+        read_binary_stl(&contents, &name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_ascii_solids() {
+        let contents = "solid floor
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 0
+      vertex 1 1 0
+    endloop
+  endfacet
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 1 0
+      vertex 0 1 0
+    endloop
+  endfacet
+endsolid floor
+solid
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 1
+      vertex 1 0 1
+      vertex 1 1 1
+    endloop
+  endfacet
+endsolid
+";
+        let objects = read_ascii_stl(contents).unwrap();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0].name, "floor");
+        // the shared vertices of the two facets are merged
+        assert_eq!(objects[0].mesh.vertices.len(), 4);
+        assert_eq!(objects[0].mesh.faces, vec![[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(objects[0].mesh.material_names, vec!["floor".to_string()]);
+        assert_eq!(objects[0].mesh.face_materials, vec![0, 0]);
+        assert!(objects[1].mesh.material_names.is_empty());
+        assert_eq!(objects[1].mesh.vertices[2], vector![1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn rejects_ascii_facets_without_three_vertices() {
+        let contents = "solid broken\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nendloop\nendsolid broken\n";
+        assert!(read_ascii_stl(contents).is_err());
+    }
+
+    fn binary_stl(triangles: &[[[f32; 3]; 3]]) -> Vec<u8> {
+        // binary files may start with "solid" too
+        let mut contents = b"solid exported as binary".to_vec();
+        contents.resize(80, 0);
+        contents.extend_from_slice(&(triangles.len() as u32).to_le_bytes());
+        for triangle in triangles {
+            contents.extend_from_slice(&[0; 12]);
+            for coordinate in triangle.iter().flatten() {
+                contents.extend_from_slice(&coordinate.to_le_bytes());
+            }
+            contents.extend_from_slice(&[0; 2]);
+        }
+        contents
+    }
+
+    #[test]
+    fn reads_binary_solid() {
+        let contents = binary_stl(&[
+            [[0.0, 0.0, 0.0], [2.0, 0.0, 0.0], [2.0, 3.0, 0.0]],
+            [[0.0, 0.0, 0.0], [2.0, 3.0, 0.0], [0.0, 3.0, -1.5]],
+        ]);
+        let objects = read_binary_stl(&contents, "part").unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].name, "part");
+        assert_eq!(objects[0].mesh.vertices.len(), 4);
+        assert_eq!(objects[0].mesh.faces, vec![[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(objects[0].mesh.vertices[3], vector![0.0, 3.0, -1.5]);
+    }
+
+    #[test]
+    fn rejects_truncated_binary_solid() {
+        let mut contents = binary_stl(&[[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]]]);
+        contents.truncate(contents.len() - 10);
+        assert!(read_binary_stl(&contents, "part").is_err());
+    }
+
+    #[test]
+    fn detects_binary_files_starting_with_solid() {
+        let file_name = std::env::temp_dir().join(format!("raya_stl_test_{}.stl", std::process::id()));
+        fs::write(&file_name, binary_stl(&[[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]]])).unwrap();
+        let objects = read_stl(file_name.to_str().unwrap());
+        fs::remove_file(&file_name).unwrap();
+        let objects = objects.unwrap();
+        assert_eq!(objects[0].name, format!("raya_stl_test_{}", std::process::id()));
+        assert_eq!(objects[0].mesh.faces.len(), 1);
+    }
+}
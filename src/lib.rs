@@ -2,9 +2,24 @@ pub mod geometry;
 pub mod scene;
 pub mod utils;
 pub mod signals;
+pub mod auralization;
 
 mod acoustic_raytrace;
-pub use crate::acoustic_raytrace::AcousticRaytracer;
+mod acoustic_radiosity;
+mod acoustic_diffraction;
+mod acoustic_diagnostics;
+mod acoustic_statistics;
+mod acoustic_cache;
+mod acoustic_resynthesis;
+mod acoustic_accumulator;
+pub use crate::acoustic_raytrace::{AcousticRaytracer, MaterialHits, OutputFormat, RayPath, SceneSettings, COS_THETA_BINS};
+pub use crate::acoustic_radiosity::{EnergyTimeCurve, RadiositySettings};
+pub use crate::acoustic_diffraction::{DiffractingEdge, Diffraction};
+pub use crate::acoustic_diagnostics::{Leak, LeakCause, LeakProbe, SceneDiagnostics};
+pub use crate::acoustic_statistics::{MaterialArea, RoomStatistics, SurfaceArea};
+pub use crate::acoustic_cache::{CachedNode, RayPathCache};
+pub use crate::acoustic_resynthesis::SceneMaterial;
+pub use crate::acoustic_accumulator::ArrivalAccumulator;
 
 use nalgebra::{Point3, Transform3, Vector3};
 
@@ -1,16 +1,175 @@
 extern crate clap;
-use raya::AcousticRaytracer;
-use clap::{Arg, App};
+use raya::{AcousticRaytracer, LeakCause, OutputFormat, RadiositySettings};
+use raya::geometry::MeshEdge;
+use raya::signals::binaural::{HrirInterpolation, HrirSet};
+use raya::signals::reconstruction_filter::FilterBank;
+use raya::signals::fractional_delay::FractionalDelay;
+use raya::auralization::{auralize_files, AuralizationSettings, Normalization};
+use raya::scene::MaterialLibrary;
+use raya::utils::bands;
+use clap::{Arg, App, AppSettings, SubCommand, ArgMatches};
+use std::error::Error;
+
+fn auralize(matches: &ArgMatches) {
+    let input = matches.value_of("input").unwrap();
+    let impulse_response = matches.value_of("impulse-response").unwrap();
+    let output = matches.value_of("output").unwrap();
+
+    let mut settings = AuralizationSettings::default();
+    if let Some(sample_rate) = matches.value_of("sample-rate") {
+        settings.sample_rate = Some(sample_rate.parse::<u32>().expect("sample rate is a positive integer"));
+    }
+    let peak = matches.value_of("peak").unwrap().parse::<f32>().expect("peak level is a number");
+    settings.normalization = match matches.value_of("normalize").unwrap() {
+        "none" => Normalization::None,
+        "input" => Normalization::MatchInput,
+        _ => Normalization::Peak(peak),
+    };
+
+    match auralize_files(input, impulse_response, output, &settings) {
+        Ok(()) => println!("wrote {}", output),
+        Err(error) => println!("There was a problem auralizing {}: {}", input, error),
+    }
+}
+
+/// The built-in material library extended with the libraries given on the command line
+fn material_library(matches: &ArgMatches) -> Result<MaterialLibrary, Box<dyn Error>> {
+    let mut library = MaterialLibrary::builtin();
+    if let Some(files) = matches.values_of("material-library") {
+        for file in files {
+            let user_library = MaterialLibrary::from_file(file).map_err(|error| format!("could not read the material library {}: {}", file, error))?;
+            library.extend(user_library);
+        }
+    }
+    Ok(library)
+}
+
+/// Loads the model, with the settings file for models other than glTF
+fn load_scene(matches: &ArgMatches) -> Result<AcousticRaytracer, Box<dyn Error>> {
+    let model = matches.value_of("model").unwrap();
+    let library = material_library(matches)?;
+    let lowercase_model = model.to_lowercase();
+    if lowercase_model.ends_with(".gltf") || lowercase_model.ends_with(".glb") {
+        AcousticRaytracer::from_gltf_with_library(model, &library)
+    } else {
+        let default_settings = std::path::Path::new(model).with_extension("json");
+        let settings = matches.value_of("settings").map(|settings| settings.to_string()).unwrap_or_else(|| default_settings.to_string_lossy().to_string());
+        AcousticRaytracer::from_mesh_file_with_library(model, &settings, &library)
+    }
+}
+
+fn check(matches: &ArgMatches) {
+    let mut acoustic_raytracer = match load_scene(matches) {
+        Ok(acoustic_raytracer) => acoustic_raytracer,
+        Err(error) => {
+            println!("There was a problem loading the model: {}", error);
+            return;
+        }
+    };
+    let rays = matches.value_of("rays").unwrap().parse::<u64>().expect("ray count is a positive integer");
+    let probe = acoustic_raytracer.probe_leaks(rays);
+    let scene = acoustic_raytracer.geometry_diagnostics();
+    let node_name = |id: u32| acoustic_raytracer.root_node.find_child_by_id(id).map(|node| node.name.clone()).unwrap_or_default();
+    let edge_location = |edge: &MeshEdge| {
+        let position = (scene.mesh.vertices[edge.vertices[0]] + scene.mesh.vertices[edge.vertices[1]]) / 2.0;
+        format!("({:.2}, {:.2}, {:.2})", position.x, position.y, position.z)
+    };
+    let face_location = |face: usize| {
+        let triangle = &scene.triangles[face];
+        let centroid = triangle.centroid();
+        format!("{} face {} at ({:.2}, {:.2}, {:.2})", node_name(triangle.node), triangle.face, centroid.x, centroid.y, centroid.z)
+    };
+    let limit = matches.value_of("limit").unwrap().parse::<usize>().expect("limit is a positive integer");
+
+    let diagnostics = &scene.diagnostics;
+    println!("{} faces", scene.mesh.faces.len());
+    for (description, edges) in [
+        ("boundary edges", &diagnostics.boundary_edges),
+        ("non-manifold edges", &diagnostics.non_manifold_edges),
+        ("edges between faces with opposite winding", &diagnostics.inconsistent_edges),
+    ] {
+        println!("{} {}", edges.len(), description);
+        for edge in edges.iter().take(limit) {
+            println!("    {}", edge_location(edge));
+        }
+    }
+    println!("{} duplicate faces", diagnostics.duplicate_faces.len());
+    for [_, duplicate] in diagnostics.duplicate_faces.iter().take(limit) {
+        println!("    {}", face_location(*duplicate));
+    }
+    println!("{} degenerate faces", diagnostics.degenerate_faces.len());
+    for face in diagnostics.degenerate_faces.iter().take(limit) {
+        println!("    {}", face_location(*face));
+    }
+
+    println!("{} of {} probe rays ({:.1}%) escaped the model", probe.escaped, probe.rays, probe.escaped_share() * 100.0);
+    for leak in probe.leaks.iter().take(limit) {
+        let cause = match leak.cause {
+            LeakCause::BackFace { node, face } => format!("through the back of {} face {}", node_name(node), face),
+            LeakCause::Gap { opening } => format!("through opening {}", opening),
+            LeakCause::Open => "without passing a face".to_string(),
+        };
+        println!("    {} rays at ({:.2}, {:.2}, {:.2}) {}", leak.rays, leak.position.x, leak.position.y, leak.position.z, cause);
+    }
+}
+
+fn estimate(matches: &ArgMatches) {
+    let acoustic_raytracer = match load_scene(matches) {
+        Ok(acoustic_raytracer) => acoustic_raytracer,
+        Err(error) => {
+            println!("There was a problem loading the model: {}", error);
+            return;
+        }
+    };
+    if !acoustic_raytracer.geometry_diagnostics().diagnostics.is_watertight() {
+        println!("warning: the model is not closed, its volume is not meaningful (see raya check)");
+    }
+    let statistics = acoustic_raytracer.room_statistics();
+    println!("volume       {:>10.1} m³", statistics.volume);
+    println!("surface area {:>10.1} m²", statistics.surface_area);
+    for material in statistics.materials.iter() {
+        println!("    {:<30} {:>8.1} m²", material.name, material.area);
+    }
+
+    let row = |name: &str, values: &[f32], precision: usize| {
+        let values: Vec<String> = values.iter().map(|value| format!("{:>8.*}", precision, value)).collect();
+        println!("{:<14}{}", name, values.join(""));
+    };
+    row("band (Hz)", &statistics.frequencies, 0);
+    row("absorption", &statistics.mean_absorption, 2);
+    row("Sabine", &statistics.sabine, 2);
+    row("Eyring", &statistics.eyring, 2);
+    row("Arau-Puchades", &statistics.arau_puchades, 2);
+}
+
+fn materials(matches: &ArgMatches) {
+    let library = match material_library(matches) {
+        Ok(library) => library,
+        Err(error) => {
+            println!("There was a problem loading the materials: {}", error);
+            return;
+        }
+    };
+    let entries = match matches.value_of("query") {
+        Some(query) => library.search(query),
+        None => library.materials.iter().collect(),
+    };
+    for entry in entries {
+        let absorption: Vec<String> = entry.absorption_bands().iter().map(|band| format!("{:.2}", band[1])).collect();
+        println!("{:<30} {:<50} [{}] {}", entry.uuid, entry.name, entry.tags.join(", "), absorption.join(" "));
+    }
+}
 
 fn main() {
     let matches = App::new("Raya")
         .about("Acoustic raytracer written in rust")
         .version("0.1.1")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("model")
             .short("m")
             .long("model")
             .value_name("FILE")
-            .help("The 3d model file used (.gltf)")
+            .help("The 3d model file used (.gltf, .obj, .stl or .ply)")
             .takes_value(true)
             .required(true))
         .arg(Arg::with_name("output")
@@ -20,18 +179,292 @@ fn main() {
             .help("The file path for the calculated impulse response (.wav)")
             .takes_value(true)
             .required(true))
+        .arg(Arg::with_name("settings")
+            .long("settings")
+            .value_name("FILE")
+            .help("The source, receiver and material settings of an .obj, .stl or .ply model (.json), defaults to the model's path with a .json extension")
+            .takes_value(true))
+        .arg(Arg::with_name("ambisonic-order")
+            .short("a")
+            .long("ambisonic-order")
+            .value_name("ORDER")
+            .help("Render an ambisonic (ACN/SN3D) impulse response of the given order")
+            .takes_value(true))
+        .arg(Arg::with_name("sofa")
+            .short("s")
+            .long("sofa")
+            .value_name("FILE")
+            .help("Render a binaural impulse response with the HRIRs in a SOFA file")
+            .takes_value(true)
+            .conflicts_with("ambisonic-order"))
+        .arg(Arg::with_name("interpolate-hrir")
+            .long("interpolate-hrir")
+            .help("Interpolate between the nearest measured HRIRs instead of using the closest one")
+            .requires("sofa"))
+        .arg(Arg::with_name("diffuse-rain")
+            .long("diffuse-rain")
+            .help("Send a shadow ray to the receiver at every reflection to reduce the variance of the late tail"))
+        .arg(Arg::with_name("decay-tail")
+            .long("decay-tail")
+            .help("Extend the traced arrivals, which the maximum order truncates, with a synthesized tail following their decay"))
+        .arg(Arg::with_name("poisson-tail")
+            .long("poisson-tail")
+            .help("Replace the arrivals after the mixing time with a Poisson distributed Dirac sequence carrying their energy"))
+        .arg(Arg::with_name("stream")
+            .long("stream")
+            .help("Add each traced path to the impulse response right away instead of storing it, so memory does not grow with the ray count")
+            .conflicts_with_all(&["save-paths", "load-paths"]))
+        .arg(Arg::with_name("filter-bank")
+            .long("filter-bank")
+            .value_name("PHASE")
+            .help("The phase of the filters that split the impulse response into bands, zero phase before the default became linear")
+            .possible_values(&["zero", "linear", "minimum"])
+            .default_value("linear"))
+        .arg(Arg::with_name("fractional-delay")
+            .long("fractional-delay")
+            .value_name("INTERPOLATION")
+            .help("How arrivals are placed between samples, on the sample before them or interpolated")
+            .possible_values(&["none", "lagrange", "sinc"])
+            .default_value("none"))
+        .arg(Arg::with_name("sample-rate")
+            .long("sample-rate")
+            .value_name("RATE")
+            .help("The sample rate of the impulse response in Hz")
+            .takes_value(true)
+            .default_value("44100"))
+        .arg(Arg::with_name("bands")
+            .long("bands")
+            .value_name("BANDS")
+            .help("The frequency bands the impulse response is synthesized in, from 63 Hz to 8 kHz")
+            .possible_values(&["octave", "third-octave"])
+            .default_value("octave"))
+        .arg(Arg::with_name("radiosity")
+            .long("radiosity")
+            .help("Use the acoustic radiosity solver instead of tracing rays")
+            .conflicts_with_all(&["load-paths", "diffraction-order"]))
+        .arg(Arg::with_name("material-library")
+            .short("l")
+            .long("material-library")
+            .value_name("FILE")
+            .help("A material library (.json) whose materials the model can refer to, may be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("save-paths")
+            .long("save-paths")
+            .value_name("FILE")
+            .help("Save the traced ray paths, as JSON if the file name ends in .json and in binary otherwise")
+            .takes_value(true))
+        .arg(Arg::with_name("load-paths")
+            .long("load-paths")
+            .value_name("FILE")
+            .help("Synthesize the impulse response from ray paths saved with --save-paths instead of tracing")
+            .takes_value(true))
+        .arg(Arg::with_name("diffraction-order")
+            .long("diffraction-order")
+            .value_name("ORDER")
+            .help("Add paths diffracted around up to ORDER (1 or 2) edges")
+            .possible_values(&["0", "1", "2"])
+            .takes_value(true))
+        .subcommand(SubCommand::with_name("check")
+            .about("Checks a model for gaps, flipped faces and other problems that let rays escape")
+            .arg(Arg::with_name("model")
+                .short("m")
+                .long("model")
+                .value_name("FILE")
+                .help("The 3d model file to check (.gltf, .obj, .stl or .ply)")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("material-library")
+                .short("l")
+                .long("material-library")
+                .value_name("FILE")
+                .help("A material library (.json) whose materials the model can refer to, may be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("settings")
+                .long("settings")
+                .value_name("FILE")
+                .help("The settings of an .obj, .stl or .ply model (.json), defaults to the model's path with a .json extension")
+                .takes_value(true))
+            .arg(Arg::with_name("rays")
+                .long("rays")
+                .value_name("COUNT")
+                .help("The number of rays traced from the source to find leaks")
+                .default_value("10000")
+                .takes_value(true))
+            .arg(Arg::with_name("limit")
+                .long("limit")
+                .value_name("COUNT")
+                .help("The number of locations listed for each problem")
+                .default_value("10")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("estimate")
+            .about("Predicts the reverberation time per octave band with Sabine's, Eyring's and Arau-Puchades' formulas")
+            .arg(Arg::with_name("model")
+                .short("m")
+                .long("model")
+                .value_name("FILE")
+                .help("The 3d model file used (.gltf, .obj, .stl or .ply)")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("settings")
+                .long("settings")
+                .value_name("FILE")
+                .help("The settings of an .obj, .stl or .ply model (.json), defaults to the model's path with a .json extension")
+                .takes_value(true))
+            .arg(Arg::with_name("material-library")
+                .short("l")
+                .long("material-library")
+                .value_name("FILE")
+                .help("A material library (.json) whose materials the model can refer to, may be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)))
+        .subcommand(SubCommand::with_name("materials")
+            .about("Lists the materials of the material library")
+            .arg(Arg::with_name("query")
+                .value_name("QUERY")
+                .help("Only list materials whose name, kind, manufacturer, ID or tags contain QUERY"))
+            .arg(Arg::with_name("material-library")
+                .short("l")
+                .long("material-library")
+                .value_name("FILE")
+                .help("A material library (.json) to list along with the built-in materials, may be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)))
+        .subcommand(SubCommand::with_name("auralize")
+            .about("Convolves a dry recording with a rendered impulse response")
+            .arg(Arg::with_name("input")
+                .short("i")
+                .long("input")
+                .value_name("FILE")
+                .help("The dry (anechoic) recording (.wav)")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("impulse-response")
+                .short("r")
+                .long("impulse-response")
+                .value_name("FILE")
+                .help("The impulse response to convolve with (.wav)")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .help("The file path for the auralized signal (.wav)")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("sample-rate")
+                .long("sample-rate")
+                .value_name("HZ")
+                .help("The output sample rate, defaults to the impulse response's")
+                .takes_value(true))
+            .arg(Arg::with_name("normalize")
+                .short("n")
+                .long("normalize")
+                .value_name("MODE")
+                .help("How the output is normalized")
+                .possible_values(&["peak", "input", "none"])
+                .default_value("peak")
+                .takes_value(true))
+            .arg(Arg::with_name("peak")
+                .long("peak")
+                .value_name("DBFS")
+                .help("The peak level used by --normalize peak")
+                .default_value("-1")
+                .allow_hyphen_values(true)
+                .takes_value(true)))
         .get_matches();
+
+    if let Some(auralize_matches) = matches.subcommand_matches("auralize") {
+        auralize(auralize_matches);
+        return;
+    }
+    if let Some(check_matches) = matches.subcommand_matches("check") {
+        check(check_matches);
+        return;
+    }
+    if let Some(estimate_matches) = matches.subcommand_matches("estimate") {
+        estimate(estimate_matches);
+        return;
+    }
+    if let Some(materials_matches) = matches.subcommand_matches("materials") {
+        materials(materials_matches);
+        return;
+    }
     
-    let model = matches.value_of("model").unwrap();
     let output = matches.value_of("output").unwrap();
     
 
-    match AcousticRaytracer::from_gltf(model) {
+    match load_scene(&matches) {
         Ok(mut acoustic_raytracer) => {
+            acoustic_raytracer.diffuse_rain = matches.is_present("diffuse-rain");
+            acoustic_raytracer.decay_tail = matches.is_present("decay-tail");
+            acoustic_raytracer.poisson_tail = matches.is_present("poisson-tail");
+            acoustic_raytracer.stream_arrivals = matches.is_present("stream");
+            acoustic_raytracer.filter_bank = match matches.value_of("filter-bank").unwrap() {
+                "zero" => FilterBank::ZeroPhase,
+                "minimum" => FilterBank::MinimumPhase,
+                _ => FilterBank::LinearPhase,
+            };
+            acoustic_raytracer.fractional_delay = match matches.value_of("fractional-delay").unwrap() {
+                "lagrange" => FractionalDelay::Lagrange,
+                "sinc" => FractionalDelay::WindowedSinc,
+                _ => FractionalDelay::None,
+            };
+            acoustic_raytracer.sample_rate = match matches.value_of("sample-rate").unwrap().parse::<u32>() {
+                Ok(sample_rate) if sample_rate > 0 => sample_rate,
+                _ => {
+                    println!("The sample rate must be a positive integer");
+                    return;
+                }
+            };
+            acoustic_raytracer.frequencies = match matches.value_of("bands").unwrap() {
+                "third-octave" => bands::third_octave(63.0, 8000.0),
+                _ => bands::octave(63.0, 8000.0),
+            };
+            if matches.is_present("radiosity") {
+                acoustic_raytracer.radiosity = Some(RadiositySettings::default());
+                acoustic_raytracer.ray_count = 0;
+            }
+            if let Some(order) = matches.value_of("diffraction-order") {
+                acoustic_raytracer.diffraction_order = order.parse::<u32>().expect("diffraction order is 0, 1 or 2");
+            }
+            if let Some(order) = matches.value_of("ambisonic-order") {
+                let order = order.parse::<u32>().expect("ambisonic order is a positive integer");
+                acoustic_raytracer.output_format = OutputFormat::Ambisonic(order);
+            }
+            if let Some(sofa) = matches.value_of("sofa") {
+                // without the sofa feature, loading fails with a note on how to enable it
+                let hrir_set = match HrirSet::from_sofa(sofa) {
+                    Ok(hrir_set) => hrir_set,
+                    Err(error) => {
+                        println!("There was a problem loading the SOFA file {}: {}", sofa, error);
+                        return;
+                    }
+                };
+                let interpolation = if matches.is_present("interpolate-hrir") {
+                    HrirInterpolation::Interpolated
+                } else {
+                    HrirInterpolation::Nearest
+                };
+                acoustic_raytracer.hrir_set = Some(hrir_set);
+                acoustic_raytracer.output_format = OutputFormat::Binaural(interpolation);
+            }
+            if let Some(paths) = matches.value_of("load-paths") {
+                acoustic_raytracer.load_ray_paths(paths).expect("There was a problem loading the ray paths");
+            }
             acoustic_raytracer.render(output.to_string()).expect("There was a problem rendering the scene");
+            if let Some(paths) = matches.value_of("save-paths") {
+                acoustic_raytracer.save_ray_paths(paths).expect("There was a problem saving the ray paths");
+            }
         },
-        Err(_) => {
-            println!("There was a problem setting up the acoustic raytracer");
+        Err(error) => {
+            println!("There was a problem setting up the acoustic raytracer: {}", error);
         }
     };
 }
\ No newline at end of file
@@ -1,12 +1,99 @@
 use super::super::utils::bands::octave;
-use super::super::utils::math::interpolate_log;
+use super::super::utils::math::{interpolate_alpha, interpolate_log};
+use crate::acoustic_raytrace::SPEED_OF_SOUND;
+use rustfft::num_complex::Complex;
 use serde::{Deserialize, Serialize};
 
+/// density of air in kg/m³
+const AIR_DENSITY: f32 = 1.21;
+
+/// The surface impedance of a locally reacting material
+#[derive(Debug, Clone, PartialEq)]
+pub enum SurfaceImpedance {
+  /// impedance normalized by the characteristic impedance of air as (frequency, real, imaginary)
+  Table(Vec<[f32; 3]>),
+  /// porous layer on a rigid backing, with the Delany-Bazley model
+  DelanyBazley {
+    /// flow resistivity in Pa·s/m²
+    flow_resistivity: f32,
+    /// thickness of the layer in m
+    thickness: f32,
+  },
+  /// porous layer on a rigid backing, with Miki's model
+  Miki {
+    /// flow resistivity in Pa·s/m²
+    flow_resistivity: f32,
+    /// thickness of the layer in m
+    thickness: f32,
+  },
+}
+
+/// Surface impedance of a porous layer on a rigid backing, Zs = -j Zc cot(kc d), from the
+/// layer's normalized characteristic impedance `zc` and complex wavenumber `kc`
+fn porous_layer(thickness: f32, zc: Complex<f32>, kc: Complex<f32>) -> Complex<f32> {
+  let kd = kc * thickness;
+  -Complex::<f32>::i() * zc * kd.cos() / kd.sin()
+}
+
+impl SurfaceImpedance {
+  /// The surface impedance normalized by the characteristic impedance of air at a frequency
+  pub fn normalized(&self, frequency: f32) -> Complex<f32> {
+    let k = 2.0 * std::f32::consts::PI * frequency / SPEED_OF_SOUND;
+    match self {
+      SurfaceImpedance::Table(table) => {
+        let frequencies: Vec<f32> = table.iter().map(|point| point[0]).collect();
+        let clamped = frequency.clamp(frequencies[0], frequencies[frequencies.len() - 1]);
+        let re = interpolate_alpha(table.iter().map(|point| point[1]).collect(), frequencies.clone())(clamped);
+        let im = interpolate_alpha(table.iter().map(|point| point[2]).collect(), frequencies)(clamped);
+        Complex::new(re, im)
+      }
+      SurfaceImpedance::DelanyBazley { flow_resistivity, thickness } => {
+        let x = AIR_DENSITY * frequency / flow_resistivity;
+        let zc = Complex::new(1.0 + 0.0571 * x.powf(-0.754), -0.087 * x.powf(-0.732));
+        let kc = Complex::new(1.0 + 0.0978 * x.powf(-0.700), -0.189 * x.powf(-0.595)) * k;
+        porous_layer(*thickness, zc, kc)
+      }
+      SurfaceImpedance::Miki { flow_resistivity, thickness } => {
+        let x = frequency / flow_resistivity;
+        let zc = Complex::new(1.0 + 0.0699 * x.powf(-0.632), -0.107 * x.powf(-0.632));
+        let kc = Complex::new(1.0 + 0.109 * x.powf(-0.618), -0.160 * x.powf(-0.618)) * k;
+        porous_layer(*thickness, zc, kc)
+      }
+    }
+  }
+
+  /// The absorption coefficient of a plane wave arriving at an angle from the normal,
+  /// given as its cosine
+  pub fn absorption(&self, frequency: f32, cos_theta: f32) -> f32 {
+    let z = self.normalized(frequency) * cos_theta.abs();
+    let reflection = (z - 1.0) / (z + 1.0);
+    (1.0 - reflection.norm_sqr()).clamp(0.0, 1.0)
+  }
+
+  /// The random incidence absorption coefficient (Paris' formula)
+  pub fn random_incidence_absorption(&self, frequency: f32) -> f32 {
+    const STEPS: usize = 90;
+    let step = std::f32::consts::FRAC_PI_2 / STEPS as f32;
+    (0..STEPS)
+      .map(|i| {
+        let theta = (i as f32 + 0.5) * step;
+        self.absorption(frequency, theta.cos()) * f32::sin(2.0 * theta) * step
+      })
+      .sum()
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct AcousticMaterial {
   /// octave band absorption coefficients (63hz to 8000hz)
   absorption: Vec<f32>,
   frequencies: Vec<f32>,
+  /// fraction of the reflected energy that is scattered diffusely
+  pub scattering: f32,
+  /// transmission loss in dB (frequency, loss), empty for surfaces that do not transmit sound
+  transmission_loss: Vec<[f32; 2]>,
+  /// surface impedance, used instead of the absorption coefficients when it is given
+  pub impedance: Option<SurfaceImpedance>,
 }
 
 
@@ -23,7 +110,10 @@ impl AcousticMaterial {
   pub fn new(absorption: Vec<f32>) -> Self {
       Self {
           absorption,
-          frequencies: octave(63.0, 8000.0)
+          frequencies: octave(63.0, 8000.0),
+          scattering: 0.1,
+          transmission_loss: Vec::new(),
+          impedance: None,
       }
   }
   pub fn from_absorption_data(data: AbsorptionData) -> Self {
@@ -36,11 +126,18 @@ impl AcousticMaterial {
     Self {
       absorption,
       frequencies,
+      scattering: 0.1,
+      transmission_loss: Vec::new(),
+      impedance: None,
     }
   }
+  /// The random incidence absorption coefficient at a frequency
   pub fn absorption_function(&self, frequency: f32) -> f32 {
+    if let Some(impedance) = &self.impedance {
+      return impedance.random_incidence_absorption(frequency);
+    }
     let mut i = 0;
-    while frequency > self.frequencies[i] && i < self.frequencies.len() {
+    while i < self.frequencies.len() && frequency > self.frequencies[i] {
       i+=1;
     }
     if i > 0 && i < self.frequencies.len() {
@@ -59,6 +156,61 @@ impl AcousticMaterial {
       }
     }
   }
+
+  /// The absorption coefficient for sound arriving at an angle from the surface normal, given as
+  /// its cosine. Materials without a surface impedance absorb the same at every angle.
+  pub fn absorption_at_angle(&self, frequency: f32, cos_theta: f32) -> f32 {
+    match &self.impedance {
+      Some(impedance) => impedance.absorption(frequency, cos_theta),
+      None => self.absorption_function(frequency),
+    }
+  }
+
+  /// Sets the transmission loss as (frequency, dB) pairs sorted by frequency
+  pub fn set_transmission_loss(&mut self, transmission_loss: Vec<[f32; 2]>) {
+    self.transmission_loss = transmission_loss;
+  }
+
+  pub fn is_transmissive(&self) -> bool {
+    !self.transmission_loss.is_empty()
+  }
+
+  /// The fraction of the incident energy the transmission loss lets through at a frequency
+  fn transmission_coefficient(&self, frequency: f32) -> f32 {
+    if self.transmission_loss.is_empty() {
+      return 0.0;
+    }
+    let frequencies: Vec<f32> = self.transmission_loss.iter().map(|point| point[0]).collect();
+    let loss: Vec<f32> = self.transmission_loss.iter().map(|point| point[1]).collect();
+    let clamped = frequency.clamp(frequencies[0], frequencies[frequencies.len() - 1]);
+    f32::powf(10.0, -interpolate_alpha(loss, frequencies)(clamped) / 10.0)
+  }
+
+  /// The fraction of the incident energy transmitted through the surface at a frequency, for
+  /// random incidence. Transmitted energy is part of the energy the absorption coefficient
+  /// removes from the reflection, so it is limited to the absorption coefficient.
+  pub fn transmission_function(&self, frequency: f32) -> f32 {
+    f32::min(self.transmission_coefficient(frequency), self.absorption_function(frequency))
+  }
+
+  /// The fraction of the incident energy transmitted through the surface for sound arriving at
+  /// an angle from the surface normal, limited to the absorption coefficient at that angle so
+  /// the reflected and transmitted energy never exceed the incident energy
+  pub fn transmission_at_angle(&self, frequency: f32, cos_theta: f32) -> f32 {
+    f32::min(self.transmission_coefficient(frequency), self.absorption_at_angle(frequency, cos_theta))
+  }
+
+  /// The probability of a ray being transmitted rather than reflected at the surface, the ratio
+  /// of the mean transmitted energy to the mean energy that leaves the surface
+  pub fn transmission_probability(&self) -> f32 {
+    if self.transmission_loss.is_empty() {
+      return 0.0;
+    }
+    let frequencies = octave(63.0, 8000.0);
+    let transmitted: f32 = frequencies.iter().map(|f| self.transmission_function(*f)).sum();
+    let reflected: f32 = frequencies.iter().map(|f| 1.0 - self.absorption_function(*f)).sum();
+    if transmitted + reflected > 0.0 { transmitted / (transmitted + reflected) } else { 0.0 }
+  }
 }
 
 
@@ -66,7 +218,72 @@ impl Default for AcousticMaterial {
   fn default() -> AcousticMaterial {
       AcousticMaterial {
           absorption: vec![0.01, 0.01, 0.01, 0.01, 0.01, 0.01, 0.01, 0.01],
-          frequencies: octave(63.0, 8000.0)
+          frequencies: octave(63.0, 8000.0),
+          scattering: 0.1,
+          transmission_loss: Vec::new(),
+          impedance: None,
       }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // a 50 mm layer with a flow resistivity of 10 kPa·s/m², at 1 kHz
+  const LAYER: (f32, f32) = (10000.0, 0.05);
+
+  #[test]
+  fn delany_bazley_layer() {
+    let (flow_resistivity, thickness) = LAYER;
+    let impedance = SurfaceImpedance::DelanyBazley { flow_resistivity, thickness };
+    let z = impedance.normalized(1000.0);
+    assert!((z - Complex::new(0.654, -0.470)).norm() < 0.005, "{}", z);
+    assert!((impedance.absorption(1000.0, 1.0) - 0.885).abs() < 0.005);
+  }
+
+  #[test]
+  fn miki_layer() {
+    let (flow_resistivity, thickness) = LAYER;
+    let impedance = SurfaceImpedance::Miki { flow_resistivity, thickness };
+    let z = impedance.normalized(1000.0);
+    assert!((z - Complex::new(0.657, -0.480)).norm() < 0.005, "{}", z);
+    assert!((impedance.absorption(1000.0, 1.0) - 0.883).abs() < 0.005);
+  }
+
+  #[test]
+  fn table_impedance_is_interpolated() {
+    let impedance = SurfaceImpedance::Table(vec![[500.0, 1.0, 0.0], [1000.0, 2.0, 0.0], [2000.0, 3.0, -1.0]]);
+    assert_eq!(impedance.normalized(1000.0), Complex::new(2.0, 0.0));
+    // frequencies outside the table use its ends
+    assert_eq!(impedance.normalized(100.0), Complex::new(1.0, 0.0));
+    assert_eq!(impedance.normalized(8000.0), Complex::new(3.0, -1.0));
+    let between = impedance.normalized(750.0).re;
+    assert!(between > 1.0 && between < 2.0);
+    // a matched surface absorbs everything at normal incidence
+    assert!((impedance.absorption(500.0, 1.0) - 1.0).abs() < 1e-6);
+    assert!((impedance.absorption(1000.0, 1.0) - 8.0 / 9.0).abs() < 1e-6);
+  }
+
+  #[test]
+  fn grazing_incidence_is_not_absorbed() {
+    let (flow_resistivity, thickness) = LAYER;
+    let impedances = [
+      SurfaceImpedance::DelanyBazley { flow_resistivity, thickness },
+      SurfaceImpedance::Miki { flow_resistivity, thickness },
+      SurfaceImpedance::Table(vec![[1000.0, 1.0, 0.0]]),
+    ];
+    for impedance in impedances.iter() {
+      assert_eq!(impedance.absorption(1000.0, 0.0), 0.0);
+      assert!(impedance.absorption(1000.0, 0.05) < impedance.absorption(1000.0, 0.5));
+    }
+  }
+
+  #[test]
+  fn paris_integral_of_a_matched_surface() {
+    // α(θ) = 4 cos θ / (1 + cos θ)² integrates to 8 (3/2 - 2 ln 2)
+    let impedance = SurfaceImpedance::Table(vec![[1000.0, 1.0, 0.0]]);
+    let expected = 8.0 * (1.5 - 2.0 * f32::ln(2.0));
+    assert!((impedance.random_incidence_absorption(1000.0) - expected).abs() < 1e-3);
+  }
+}
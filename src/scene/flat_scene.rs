@@ -0,0 +1,308 @@
+use crate::geometry::{aabb_collision, triangle_collides, Primitive, Ray};
+use crate::scene::{NonRefIntersection, SceneNode};
+use nalgebra::{Affine3, Point3, Vector3};
+use roots::Roots;
+use std::ops::Range;
+
+/// A mesh face in scene coordinates
+#[derive(Debug, Clone)]
+struct FlatTriangle {
+    vertices: [Vector3<f32>; 3],
+    // The index of the face's material in the node's materials, None for the node's material
+    material: Option<usize>,
+    // The index of the face's material in the scene's material table
+    material_index: usize,
+}
+
+/// The most faces a leaf of a mesh's bounding volume hierarchy holds
+const LEAF_TRIANGLES: usize = 4;
+
+/// A box of a mesh's bounding volume hierarchy, in scene coordinates
+#[derive(Debug, Clone)]
+struct BvhNode {
+    aabb_corner: Vector3<f32>,
+    aabb_size: Vector3<f32>,
+    contents: BvhContents,
+}
+
+#[derive(Debug, Clone)]
+enum BvhContents {
+    // The faces in the box, in the scene's triangles
+    Leaf(Range<usize>),
+    // The two boxes splitting the faces, in the scene's bvh nodes
+    Split(usize, usize),
+}
+
+/// The faces of a node's mesh in scene coordinates, hit through their bounding volume hierarchy
+#[derive(Debug, Clone)]
+struct FlatMesh {
+    node: u32,
+    // The root of the mesh's hierarchy in the scene's bvh nodes
+    root: usize,
+}
+
+/// A sphere, cube, cylinder or cone, which is hit in its own coordinates
+#[derive(Debug, Clone)]
+struct FlatPrimitive {
+    node: u32,
+    primitive: Primitive,
+    // The transform from the primitive's coordinates into scene coordinates
+    transform: Affine3<f32>,
+    inv_transform: Affine3<f32>,
+    material_index: usize,
+}
+
+/// The scene tree compiled into flat lists of mesh faces in scene coordinates and of the other
+/// primitives with their transform into scene coordinates, each with its material's index in
+/// the `MaterialTable`. Rays are traced against it, while the tree remains the format scenes
+/// are loaded and edited in, so it has to be compiled again after the tree changes. Each mesh's
+/// faces are sorted into a bounding volume hierarchy, so a ray only tests the faces in the boxes
+/// it passes through; the primitives are few and are all tested.
+#[derive(Debug, Clone)]
+pub struct FlatScene {
+    triangles: Vec<FlatTriangle>,
+    bvh_nodes: Vec<BvhNode>,
+    meshes: Vec<FlatMesh>,
+    primitives: Vec<FlatPrimitive>,
+    /// The centre and radius of the receiver sphere in scene coordinates
+    receiver: (Point3<f32>, f32),
+}
+
+impl FlatScene {
+    /// Compiles a scene tree, whose material indexes were assigned by `MaterialTable::new`, with
+    /// the node of the receiver sphere
+    pub fn new(root_node: &SceneNode, receiver: u32) -> FlatScene {
+        let mut scene = FlatScene {
+            triangles: Vec::new(),
+            bvh_nodes: Vec::new(),
+            meshes: Vec::new(),
+            primitives: Vec::new(),
+            receiver: (Point3::origin(), 0.0),
+        };
+        for (node, transform) in root_node.world_nodes() {
+            if node.id == receiver {
+                scene.receiver = (transform * Point3::origin(), (transform * Vector3::x()).magnitude());
+            }
+            match &node.primitive {
+                Primitive::Mesh(mesh) => {
+                    if mesh.faces.is_empty() {
+                        continue;
+                    }
+                    let start = scene.triangles.len();
+                    for (index, face) in mesh.faces.iter().enumerate() {
+                        let vertices = face.map(|vertex| (transform * Point3::from(mesh.vertices[vertex])).coords);
+                        let material = mesh.face_material(index);
+                        scene.triangles.push(FlatTriangle {
+                            vertices,
+                            material,
+                            material_index: node.material_table_index(material),
+                        });
+                    }
+                    let root = build_bvh(&mut scene.triangles[start..], start, &mut scene.bvh_nodes);
+                    scene.meshes.push(FlatMesh { node: node.id, root });
+                }
+                Primitive::None => {}
+                primitive => scene.primitives.push(FlatPrimitive {
+                    node: node.id,
+                    primitive: primitive.clone(),
+                    transform,
+                    inv_transform: transform.inverse(),
+                    material_index: node.material_table_index(None),
+                }),
+            }
+        }
+        scene
+    }
+
+    /// The centre and radius of the receiver sphere in scene coordinates
+    pub fn receiver_sphere(&self) -> (Point3<f32>, f32) {
+        self.receiver
+    }
+
+    /// The hit closest to the ray's source, whose `t_value` is its distance from the source
+    pub fn intersects(&self, ray: &Ray) -> Option<NonRefIntersection> {
+        let mut closest: Option<NonRefIntersection> = None;
+        let mut t_value: f32 = 0.0;
+        let mut normal = Vector3::zeros();
+        let mut stack = Vec::new();
+
+        for mesh in self.meshes.iter() {
+            stack.push(mesh.root);
+            while let Some(index) = stack.pop() {
+                let bvh_node = &self.bvh_nodes[index];
+                let entry = match aabb_collision(ray, &bvh_node.aabb_corner, &bvh_node.aabb_size) {
+                    Roots::No(_) => continue,
+                    Roots::Two([entry, _]) => entry,
+                    _ => 0.0,
+                };
+                // boxes entered beyond the closest hit so far cannot hold a closer one
+                if closest.is_some_and(|hit| entry > hit.t_value) {
+                    continue;
+                }
+                let triangles = match &bvh_node.contents {
+                    BvhContents::Leaf(triangles) => triangles.clone(),
+                    BvhContents::Split(first, second) => {
+                        stack.push(*first);
+                        stack.push(*second);
+                        continue;
+                    }
+                };
+                for triangle in self.triangles[triangles].iter() {
+                    if !triangle_collides(ray, &triangle.vertices, &mut t_value, &mut normal) {
+                        continue;
+                    }
+                    if closest.is_none_or(|hit| t_value < hit.t_value) {
+                        let point = ray.src + t_value * ray.dir;
+                        closest = Some(NonRefIntersection {
+                            t_value,
+                            point,
+                            node: mesh.node,
+                            normal,
+                            u_value: if point.x < 0.0 { 1.0 - point.x } else { point.x },
+                            v_value: point.z,
+                            material: triangle.material,
+                            material_index: triangle.material_index,
+                        });
+                    }
+                }
+            }
+        }
+
+        for primitive in self.primitives.iter() {
+            let local_ray = primitive.inv_transform * *ray;
+            let mut uv = [0.0, 0.0];
+            let mut material = None;
+            if !primitive.primitive.collides(&local_ray, &mut t_value, &mut normal, &mut uv, &mut material) {
+                continue;
+            }
+            let point = primitive.transform * (local_ray.src + t_value * local_ray.dir);
+            let distance = (point - ray.src).magnitude();
+            if closest.is_none_or(|hit| distance < hit.t_value) {
+                // normals are transformed with the inverse transpose
+                let inv_mat3_transpose = primitive.inv_transform.matrix().fixed_resize::<3, 3>(0.0f32).transpose();
+                closest = Some(NonRefIntersection {
+                    t_value: distance,
+                    point,
+                    node: primitive.node,
+                    normal: (inv_mat3_transpose * normal).normalize(),
+                    u_value: uv[0],
+                    v_value: uv[1],
+                    material: None,
+                    material_index: primitive.material_index,
+                });
+            }
+        }
+        closest
+    }
+}
+
+/// Sorts a mesh's faces, which start at `offset` in the scene's triangles, into a bounding volume
+/// hierarchy by splitting them in halves along the longest side of their centres' bounding box,
+/// and returns the index of its root in the bvh nodes
+fn build_bvh(triangles: &mut [FlatTriangle], offset: usize, bvh_nodes: &mut Vec<BvhNode>) -> usize {
+    let mut min = Vector3::repeat(f32::MAX);
+    let mut max = Vector3::repeat(f32::MIN);
+    let mut centre_min = Vector3::repeat(f32::MAX);
+    let mut centre_max = Vector3::repeat(f32::MIN);
+    for triangle in triangles.iter() {
+        for vertex in triangle.vertices.iter() {
+            min = min.inf(vertex);
+            max = max.sup(vertex);
+        }
+        let centre = centroid(triangle);
+        centre_min = centre_min.inf(&centre);
+        centre_max = centre_max.sup(&centre);
+    }
+    let index = bvh_nodes.len();
+    bvh_nodes.push(BvhNode {
+        aabb_corner: min,
+        aabb_size: max - min,
+        contents: BvhContents::Leaf(offset..offset + triangles.len()),
+    });
+    if triangles.len() <= LEAF_TRIANGLES {
+        return index;
+    }
+
+    let axis = (centre_max - centre_min).imax();
+    let half = triangles.len() / 2;
+    triangles.select_nth_unstable_by(half, |a, b| centroid(a)[axis].total_cmp(&centroid(b)[axis]));
+    let (first, second) = triangles.split_at_mut(half);
+    let first = build_bvh(first, offset, bvh_nodes);
+    let second = build_bvh(second, offset + half, bvh_nodes);
+    bvh_nodes[index].contents = BvhContents::Split(first, second);
+    index
+}
+
+fn centroid(triangle: &FlatTriangle) -> Vector3<f32> {
+    (triangle.vertices[0] + triangle.vertices[1] + triangle.vertices[2]) / 3.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Mesh;
+    use nalgebra::{point, vector};
+    use std::f32::consts::PI;
+
+    #[test]
+    fn hierarchy_finds_the_closest_face() {
+        // a room shaped like a sphere of 2 m radius from 32 x 64 quads, facing inwards, with a ring
+        // of smaller balls inside it facing outwards
+        let sphere = |centre: Vector3<f32>, radius: f32, inward: bool| {
+            let (rings, segments) = (32, 64);
+            let mut vertices = Vec::new();
+            for ring in 0..=rings {
+                let polar = PI * ring as f32 / rings as f32;
+                for segment in 0..segments {
+                    let azimuth = 2.0 * PI * segment as f32 / segments as f32;
+                    vertices.push(centre + radius * vector![polar.sin() * azimuth.cos(), polar.sin() * azimuth.sin(), polar.cos()]);
+                }
+            }
+            let mut faces = Vec::new();
+            for ring in 0..rings {
+                for segment in 0..segments {
+                    let corner = |r: usize, s: usize| r * segments + s % segments;
+                    let mut quad = [
+                        [corner(ring, segment), corner(ring + 1, segment), corner(ring + 1, segment + 1)],
+                        [corner(ring, segment), corner(ring + 1, segment + 1), corner(ring, segment + 1)],
+                    ];
+                    if inward {
+                        quad.iter_mut().for_each(|face| face.swap(1, 2));
+                    }
+                    faces.extend_from_slice(&quad);
+                }
+            }
+            Mesh::new(vertices, faces)
+        };
+        let mut root = SceneNode::new(0, "root".to_string());
+        root.primitive = Primitive::Mesh(sphere(Vector3::zeros(), 2.0, true));
+        for id in 1..8 {
+            let angle = 2.0 * PI * id as f32 / 7.0;
+            let mut node = SceneNode::new(id, format!("ball {}", id));
+            node.primitive = Primitive::Mesh(sphere(vector![angle.cos(), angle.sin(), 0.0], 0.3, false));
+            root.add_child(node);
+        }
+        let scene = FlatScene::new(&root, 0);
+        assert_eq!(scene.triangles.len(), 8 * 32 * 64 * 2);
+
+        let mut normal = Vector3::zeros();
+        let mut t_value = 0.0;
+        for n in 0..500 {
+            // directions spread over the sphere along a spiral
+            let z = 1.0 - 2.0 * (n as f32 + 0.5) / 500.0;
+            let azimuth = n as f32 * PI * (3.0 - f32::sqrt(5.0));
+            let ray = Ray {
+                src: point![0.1, -0.2, 0.05],
+                dir: vector![(1.0 - z * z).sqrt() * azimuth.cos(), (1.0 - z * z).sqrt() * azimuth.sin(), z],
+            };
+            let mut closest = f32::MAX;
+            for triangle in scene.triangles.iter() {
+                if triangle_collides(&ray, &triangle.vertices, &mut t_value, &mut normal) {
+                    closest = closest.min(t_value);
+                }
+            }
+            let hit = scene.intersects(&ray).expect("every ray leaves through the outer sphere");
+            assert!((hit.t_value - closest).abs() < 1e-5, "ray {}: hit at {} instead of {}", n, hit.t_value, closest);
+        }
+    }
+}
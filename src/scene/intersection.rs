@@ -1,8 +1,9 @@
-use crate::scene::SceneNode;
+use crate::scene::{AcousticMaterial, SceneNode};
 use nalgebra::{Affine3, Point3, Vector3};
 use std::cmp::{Ordering, PartialEq, PartialOrd};
+use serde::{Deserialize, Serialize};
 use std::fmt;
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct NonRefIntersection {
     // The t value for the ray where this collision occured. Can be used to calculate the intersection point
     pub t_value: f32,
@@ -11,6 +12,10 @@ pub struct NonRefIntersection {
     pub normal: Vector3<f32>,
     pub u_value: f32,
     pub v_value: f32,
+    // The index of the hit face's material in the node's materials, None for the node's material
+    pub material: Option<usize>,
+    // The index of the hit face's material in the scene's material table
+    pub material_index: usize,
 }
 
 
@@ -45,6 +50,8 @@ pub struct Intersection<'a> {
     pub normal: Vector3<f32>,
     pub u_value: f32,
     pub v_value: f32,
+    // The index of the hit face's material in the node's materials, None for the node's material
+    pub material: Option<usize>,
 }
 
 impl<'a> PartialEq for Intersection<'a> {
@@ -67,6 +74,7 @@ impl<'a> Intersection<'a> {
         normal: Vector3<f32>,
         u_value: f32,
         v_value: f32,
+        material: Option<usize>,
     ) -> Intersection {
         Intersection {
             t_value,
@@ -75,9 +83,15 @@ impl<'a> Intersection<'a> {
             normal,
             u_value,
             v_value,
+            material,
         }
     }
 
+    /// The acoustic material of the hit face
+    pub fn acoustic_material(&self) -> &AcousticMaterial {
+        self.node.material(self.material)
+    }
+
     pub fn get_non_ref(&self) -> NonRefIntersection {
         NonRefIntersection {
             t_value: self.t_value,
@@ -86,6 +100,8 @@ impl<'a> Intersection<'a> {
             normal: self.normal,
             u_value: self.u_value,
             v_value: self.v_value,
+            material: self.material,
+            material_index: self.node.material_table_index(self.material),
         }
     }
 
@@ -107,6 +123,7 @@ impl<'a> Intersection<'a> {
             normal: transformed_normal,
             u_value: self.u_value,
             v_value: self.v_value,
+            material: self.material,
         }
     }
 }
@@ -0,0 +1,233 @@
+use super::acoustic_material::{AbsorptionData, AcousticMaterial, SurfaceImpedance};
+use super::super::utils::bands::octave;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+
+/// An acoustic material in a library, in the same layout as CRAM's `acousticMaterial`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaterialEntry {
+  pub uuid: String,
+  pub name: String,
+  /// the kind of material, e.g. "Carpet"
+  #[serde(default)]
+  pub material: String,
+  #[serde(default)]
+  pub tags: Vec<String>,
+  #[serde(default)]
+  pub manufacturer: String,
+  /// where the data comes from
+  #[serde(default)]
+  pub source: String,
+  #[serde(default)]
+  pub description: String,
+  /// absorption coefficients keyed by band centre frequency
+  pub absorption: BTreeMap<String, f32>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub scattering: Option<f32>,
+  /// transmission loss in dB keyed by band centre frequency
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub transmission_loss: Option<BTreeMap<String, f32>>,
+}
+
+/// Converts a map keyed by frequency strings to (frequency, value) pairs sorted by frequency
+fn frequency_pairs(values: &BTreeMap<String, f32>) -> Vec<[f32; 2]> {
+  let mut pairs: Vec<[f32; 2]> = values
+    .iter()
+    .filter_map(|(frequency, value)| frequency.parse::<f32>().ok().map(|f| [f, *value]))
+    .collect();
+  pairs.sort_by(|a, b| a[0].partial_cmp(&b[0]).unwrap());
+  pairs
+}
+
+impl MaterialEntry {
+  /// The absorption coefficients as (frequency, coefficient) pairs sorted by frequency
+  pub fn absorption_bands(&self) -> Vec<[f32; 2]> {
+    frequency_pairs(&self.absorption)
+  }
+
+  pub fn acoustic_material(&self) -> AcousticMaterial {
+    let mut material = AcousticMaterial::from_absorption_data(AbsorptionData::new(self.absorption_bands()));
+    if let Some(scattering) = self.scattering {
+      material.scattering = scattering;
+    }
+    if let Some(transmission_loss) = &self.transmission_loss {
+      material.set_transmission_loss(frequency_pairs(transmission_loss));
+    }
+    material
+  }
+
+  fn matches(&self, query: &str) -> bool {
+    let query = query.to_lowercase();
+    [&self.name, &self.material, &self.manufacturer, &self.uuid]
+      .iter()
+      .copied()
+      .chain(self.tags.iter())
+      .any(|field| field.to_lowercase().contains(&query))
+  }
+}
+
+/// Reads a surface impedance from material extras: either a porous absorber model
+/// (`impedance_model` "delany-bazley" or "miki" with `flow_resistivity` and `thickness`) or
+/// normalized complex impedances per octave band (`impedance63`: [re, im], ...)
+fn surface_impedance(extras_object: &Map<String, Value>) -> Option<SurfaceImpedance> {
+  let number = |key: &str| extras_object.get(key).and_then(|value| value.as_f64()).map(|value| value as f32);
+  match extras_object.get("impedance_model").and_then(|value| value.as_str()) {
+    Some("delany-bazley") => Some(SurfaceImpedance::DelanyBazley {
+      flow_resistivity: number("flow_resistivity")?,
+      thickness: number("thickness")?,
+    }),
+    Some("miki") => Some(SurfaceImpedance::Miki {
+      flow_resistivity: number("flow_resistivity")?,
+      thickness: number("thickness")?,
+    }),
+    Some(model) => {
+      println!("unknown impedance model {}, using the absorption coefficients", model);
+      None
+    }
+    None => {
+      let table: Vec<[f32; 3]> = octave(63.0, 8000.0)
+        .iter()
+        .filter_map(|f| {
+          let value = extras_object.get(&format!("impedance{}", f.round() as u32))?.as_array()?;
+          Some([*f, value.first()?.as_f64()? as f32, value.get(1)?.as_f64()? as f32])
+        })
+        .collect();
+      if table.is_empty() { None } else { Some(SurfaceImpedance::Table(table)) }
+    }
+  }
+}
+
+/// A collection of acoustic materials that glTF materials can refer to by name or ID
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MaterialLibrary {
+  pub materials: Vec<MaterialEntry>,
+}
+
+impl Default for MaterialLibrary {
+  fn default() -> MaterialLibrary {
+    MaterialLibrary::builtin()
+  }
+}
+
+impl MaterialLibrary {
+  /// The materials shipped with raya: typical coefficients of common building materials
+  pub fn builtin() -> MaterialLibrary {
+    let library: MaterialLibrary = serde_json::from_str(include_str!("materials.json")).expect("built-in material library is valid");
+    library.validate().expect("built-in material library is valid");
+    library
+  }
+
+  /// Loads a library from a JSON file holding an array of materials
+  pub fn from_file(file_name: &str) -> Result<MaterialLibrary, Box<dyn Error>> {
+    let contents = fs::read_to_string(file_name)?;
+    let library: MaterialLibrary = serde_json::from_str(&contents)?;
+    library.validate()?;
+    Ok(library)
+  }
+
+  /// Checks that every material has an absorption coefficient for at least one frequency
+  pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+    match self.materials.iter().find(|entry| entry.absorption_bands().is_empty()) {
+      Some(entry) => Err(format!("material {} ({}) has no absorption coefficients keyed by frequency", entry.name, entry.uuid).into()),
+      None => Ok(()),
+    }
+  }
+
+  /// Adds the materials of another library, which take precedence over the existing ones
+  pub fn extend(&mut self, library: MaterialLibrary) {
+    let mut materials = library.materials;
+    materials.append(&mut self.materials);
+    self.materials = materials;
+  }
+
+  /// Finds a material by its ID or (case insensitive) name
+  pub fn get(&self, key: &str) -> Option<&MaterialEntry> {
+    self.materials
+      .iter()
+      .find(|entry| entry.uuid == key)
+      .or_else(|| self.materials.iter().find(|entry| entry.name.eq_ignore_ascii_case(key)))
+  }
+
+  /// Lists the materials whose name, kind, manufacturer, ID or tags contain the query
+  pub fn search(&self, query: &str) -> Vec<&MaterialEntry> {
+    self.materials.iter().filter(|entry| entry.matches(query)).collect()
+  }
+
+  /// Lists the materials with a tag
+  pub fn with_tag(&self, tag: &str) -> Vec<&MaterialEntry> {
+    self.materials
+      .iter()
+      .filter(|entry| entry.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+      .collect()
+  }
+
+  /// Builds an acoustic material from material extras (glTF material extras or MTL keys). The
+  /// extras either refer to a library entry by name or ID (`material`), or give the absorption
+  /// coefficients per octave band (`abs63` ... `abs16000`). Materials with neither are looked up
+  /// in the library by their own name. Scattering, transmission loss (`tl63` ...) and surface
+  /// impedance keys override the values of library entries.
+  pub fn material_from_extras(&self, name: &str, extras_object: &Map<String, Value>) -> AcousticMaterial {
+    let bands = [63, 125, 250, 500, 1000, 2000, 4000, 8000, 16000];
+    let absorption: Vec<Option<f32>> = bands
+      .iter()
+      .map(|band| extras_object.get(&format!("abs{}", band)).and_then(|value| value.as_f64()).map(|value| value as f32))
+      .collect();
+
+    let mut acoustic_material = if let Some(key) = extras_object.get("material").and_then(|value| value.as_str()) {
+      match self.get(key) {
+        Some(entry) => entry.acoustic_material(),
+        None => {
+          println!("warning: material {} refers to {}, which is not in the material library, using the default material", name, key);
+          AcousticMaterial::default()
+        }
+      }
+    } else if absorption.iter().any(|value| value.is_some()) {
+      // the coefficients of missing bands are interpolated from the neighbouring bands
+      let missing: Vec<String> = bands[..8]
+        .iter()
+        .zip(absorption.iter())
+        .filter(|(_, value)| value.is_none())
+        .map(|(band, _)| format!("abs{}", band))
+        .collect();
+      if !missing.is_empty() {
+        println!("warning: material {} has no {}, interpolating from the other bands", name, missing.join(", "));
+      }
+      let absorption_data = AbsorptionData::new(
+        bands
+          .iter()
+          .zip(absorption.iter())
+          .filter_map(|(band, value)| value.map(|value| [*band as f32, value]))
+          .collect(),
+      );
+      AcousticMaterial::from_absorption_data(absorption_data)
+    } else if let Some(entry) = self.get(name) {
+      entry.acoustic_material()
+    } else {
+      println!("warning: material {} has no absorption coefficients and is not in the material library, using the default material", name);
+      AcousticMaterial::default()
+    };
+
+    if let Some(scattering) = extras_object.get("scattering").and_then(|value| value.as_f64()) {
+      acoustic_material.scattering = scattering as f32;
+    }
+    // transmission loss in dB per octave band, surfaces without it do not transmit sound
+    let transmission_loss: Vec<[f32; 2]> = octave(63.0, 8000.0)
+      .iter()
+      .filter_map(|f| {
+        let key = format!("tl{}", f.round() as u32);
+        extras_object.get(&key).and_then(|value| value.as_f64()).map(|tl| [*f, tl as f32])
+      })
+      .collect();
+    if !transmission_loss.is_empty() {
+      acoustic_material.set_transmission_loss(transmission_loss);
+    }
+    if let Some(impedance) = surface_impedance(extras_object) {
+      acoustic_material.impedance = Some(impedance);
+    }
+    acoustic_material
+  }
+}
@@ -0,0 +1,46 @@
+use crate::scene::{AcousticMaterial, SceneNode};
+use std::collections::HashMap;
+
+/// The materials of every node of a scene in one list, each node's material followed by its
+/// face materials, so hits can refer to their material by index instead of searching the scene
+#[derive(Debug, Clone, Default)]
+pub struct MaterialTable {
+    pub materials: Vec<AcousticMaterial>,
+    /// the transmission probability of each material, which the tracer needs at every hit
+    transmission_probabilities: Vec<f32>,
+    /// the index of each node's material and its number of face materials, by node ID
+    nodes: HashMap<u32, (usize, usize)>,
+}
+
+impl MaterialTable {
+    /// Collects the materials of a scene, and stores the index of each node's material in the node
+    pub fn new(root_node: &mut SceneNode) -> MaterialTable {
+        let mut table = MaterialTable::default();
+        table.add_node(root_node);
+        table.transmission_probabilities = table.materials.iter().map(|material| material.transmission_probability()).collect();
+        table
+    }
+
+    fn add_node(&mut self, node: &mut SceneNode) {
+        node.material_index = self.materials.len();
+        self.nodes.insert(node.id, (node.material_index, node.materials.len()));
+        self.materials.push(node.acoustic_material.clone());
+        self.materials.extend(node.materials.iter().cloned());
+        for child in node.children.iter_mut() {
+            self.add_node(child);
+        }
+    }
+
+    /// The probability of a ray being transmitted rather than reflected by a material, see
+    /// `AcousticMaterial::transmission_probability`
+    pub fn transmission_probability(&self, index: usize) -> f32 {
+        self.transmission_probabilities[index]
+    }
+
+    /// The index of a node's material for `None`, or of one of its face materials. Indexes past
+    /// the node's face materials give the node's material, like `SceneNode::material`.
+    pub fn index(&self, node: u32, material: Option<usize>) -> Option<usize> {
+        let (index, count) = *self.nodes.get(&node)?;
+        Some(index + material.filter(|material| *material < count).map_or(0, |material| material + 1))
+    }
+}
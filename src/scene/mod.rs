@@ -1,7 +1,13 @@
 mod intersection;
 mod node;
 pub mod acoustic_material;
+pub mod material_library;
+mod material_table;
+mod flat_scene;
 
 pub use self::intersection::{Intersection, NonRefIntersection};
-pub use self::node::{Intersect, SceneNode};
-pub use self::acoustic_material::AcousticMaterial;
+pub use self::node::{Intersect, SceneNode, WorldTriangle};
+pub use self::acoustic_material::{AcousticMaterial, SurfaceImpedance};
+pub use self::material_library::{MaterialEntry, MaterialLibrary};
+pub use self::material_table::MaterialTable;
+pub use self::flat_scene::FlatScene;
@@ -1,8 +1,33 @@
 use crate::geometry::{Primitive, Ray};
 use crate::scene::{Intersection, AcousticMaterial};
-use nalgebra::{Affine3, Matrix4, Vector3, distance_squared, vector};
+use nalgebra::{Affine3, Matrix4, Point3, UnitQuaternion, Vector3, distance_squared, vector};
 
 
+/// A mesh face in scene coordinates
+#[derive(Debug, Clone, Copy)]
+pub struct WorldTriangle {
+    pub vertices: [Point3<f32>; 3],
+    // The id of the node the face belongs to
+    pub node: u32,
+    // The index of the face in the node's mesh
+    pub face: usize,
+}
+
+impl WorldTriangle {
+    /// The unit normal of the side the face can be hit from
+    pub fn normal(&self) -> Vector3<f32> {
+        (self.vertices[1] - self.vertices[0]).cross(&(self.vertices[2] - self.vertices[0])).normalize()
+    }
+
+    pub fn area(&self) -> f32 {
+        (self.vertices[1] - self.vertices[0]).cross(&(self.vertices[2] - self.vertices[0])).magnitude() / 2.0
+    }
+
+    pub fn centroid(&self) -> Point3<f32> {
+        Point3::from((self.vertices[0].coords + self.vertices[1].coords + self.vertices[2].coords) / 3.0)
+    }
+}
+
 
 #[derive(Debug, Clone)]
 pub struct SceneNode {
@@ -12,7 +37,11 @@ pub struct SceneNode {
     pub inv_transform: Affine3<f32>,
     pub name: String,
     pub acoustic_material: AcousticMaterial,
+    /// materials of individual mesh faces, indexed by the mesh's face materials
+    pub materials: Vec<AcousticMaterial>,
     pub primitive: Primitive,
+    /// index of the node's material in the scene's `MaterialTable`, its face materials follow it
+    pub material_index: usize,
 }
 
 impl SceneNode {
@@ -24,7 +53,9 @@ impl SceneNode {
             inv_transform: Affine3::identity(),
             name,
             acoustic_material: AcousticMaterial::default(),
+            materials: Vec::new(),
             primitive: Primitive::None,
+            material_index: 0,
         }
     }
 
@@ -42,10 +73,71 @@ impl SceneNode {
         None
     }
 
+    pub fn find_child_by_id_mut(&mut self, id: u32) -> Option<&mut SceneNode> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.children.iter_mut().find_map(|child| child.find_child_by_id_mut(id))
+    }
+
+    /// The material with an index in the node's materials, or the node's material for `None`
+    pub fn material(&self, index: Option<usize>) -> &AcousticMaterial {
+        index.and_then(|index| self.materials.get(index)).unwrap_or(&self.acoustic_material)
+    }
+
+    /// The index in the scene's `MaterialTable` of the material `material` would return
+    pub fn material_table_index(&self, index: Option<usize>) -> usize {
+        self.material_index + index.filter(|index| *index < self.materials.len()).map_or(0, |index| index + 1)
+    }
+
+    /// The material of a face of the node's mesh
+    pub fn face_material(&self, face: usize) -> &AcousticMaterial {
+        match &self.primitive {
+            Primitive::Mesh(mesh) => self.material(mesh.face_material(face)),
+            _ => &self.acoustic_material,
+        }
+    }
+
     pub fn add_child(&mut self, child: SceneNode) {
         self.children.push(child);
     }
 
+    /// Lists every node in the tree together with its transform into scene coordinates
+    pub fn world_nodes(&self) -> Vec<(&SceneNode, Affine3<f32>)> {
+        let mut nodes = Vec::new();
+        self.collect_world_nodes(&Affine3::identity(), &mut nodes);
+        nodes
+    }
+
+    fn collect_world_nodes<'a>(&'a self, parent_transform: &Affine3<f32>, nodes: &mut Vec<(&'a SceneNode, Affine3<f32>)>) {
+        let transform = parent_transform * self.transform;
+        nodes.push((self, transform));
+        for child in self.children.iter() {
+            child.collect_world_nodes(&transform, nodes);
+        }
+    }
+
+    /// Collects the faces of every mesh in the tree, transformed into scene coordinates
+    pub fn world_triangles(&self) -> Vec<WorldTriangle> {
+        let mut triangles = Vec::new();
+        for (node, transform) in self.world_nodes() {
+            if let Primitive::Mesh(mesh) = &node.primitive {
+                for (index, face) in mesh.faces.iter().enumerate() {
+                    triangles.push(WorldTriangle {
+                        vertices: [
+                            transform * Point3::from(mesh.vertices[face[0]]),
+                            transform * Point3::from(mesh.vertices[face[1]]),
+                            transform * Point3::from(mesh.vertices[face[2]]),
+                        ],
+                        node: node.id,
+                        face: index,
+                    });
+                }
+            }
+        }
+        triangles
+    }
+
 
     pub fn scale(&mut self, x: f32, y: f32, z: f32) {
         println!("Applying scaling to {} of ({}, {}, {})", self.name, x, y, z);
@@ -80,6 +172,15 @@ impl SceneNode {
     }
 
 
+    pub fn rotate_quaternion(&mut self, rotation: UnitQuaternion<f32>) {
+        println!(
+            "Applying rotation to {} of ({})",
+            self.name, rotation
+        );
+        self.apply_transform(rotation.to_homogeneous());
+    }
+
+
     fn apply_transform(&mut self, t: Matrix4<f32>) {
         let ta: Affine3<f32> = Affine3::from_matrix_unchecked(t);
         self.transform = ta * self.transform;
@@ -94,10 +195,11 @@ impl Intersect for SceneNode {
         let mut t_value: f32 = 0.0;
         let mut normal = vector![0.0f32, 0.0, 0.0];
         let mut uv = [0.0, 0.0];
+        let mut material = None;
         let self_collides =
             if self
                 .primitive
-                .collides(&transformed_ray, &mut t_value, &mut normal, &mut uv)
+                .collides(&transformed_ray, &mut t_value, &mut normal, &mut uv, &mut material)
             {
                 Some(Intersection::new(
                     t_value,
@@ -106,6 +208,7 @@ impl Intersect for SceneNode {
                     normal,
                     uv[0],
                     uv[1],
+                    material,
                 ))
             } else {
                 None
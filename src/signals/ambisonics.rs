@@ -0,0 +1,81 @@
+use nalgebra::Vector3;
+
+/// Returns the number of ambisonic channels for a given order
+///
+/// # Arguments
+///
+/// * `order` - ambisonic order
+///
+pub fn channel_count(order: u32) -> usize {
+  ((order + 1) * (order + 1)) as usize
+}
+
+/// Returns the ACN channel index for spherical harmonic degree `l` and index `m`
+pub fn acn(l: u32, m: i32) -> usize {
+  ((l * l + l) as i32 + m) as usize
+}
+
+fn factorial(n: u32) -> f64 {
+  (1..=n).fold(1.0, |acc, x| acc * x as f64)
+}
+
+/// Associated Legendre polynomial P_l^m(x), without the Condon-Shortley phase
+fn associated_legendre(l: u32, m: u32, x: f64) -> f64 {
+  // P_m^m
+  let mut pmm = 1.0;
+  let somx2 = f64::sqrt(f64::max(0.0, 1.0 - x * x));
+  for i in 0..m {
+    pmm *= (2 * i + 1) as f64 * somx2;
+  }
+  if l == m {
+    return pmm;
+  }
+  // P_{m+1}^m
+  let mut pmmp1 = x * (2 * m + 1) as f64 * pmm;
+  if l == m + 1 {
+    return pmmp1;
+  }
+  let mut pll = 0.0;
+  for ll in (m + 2)..=l {
+    pll = (x * (2 * ll - 1) as f64 * pmmp1 - (ll + m - 1) as f64 * pmm) / (ll - m) as f64;
+    pmm = pmmp1;
+    pmmp1 = pll;
+  }
+  pll
+}
+
+/// SN3D normalization factor for degree `l` and index `m`
+fn sn3d(l: u32, m: u32) -> f64 {
+  let delta = if m == 0 { 1.0 } else { 2.0 };
+  f64::sqrt(delta * factorial(l - m) / factorial(l + m))
+}
+
+/// Encodes a direction into real spherical harmonic gains (ACN channel order, SN3D normalization)
+///
+/// The direction is given in the ambisonic frame: x points forward, y to the left and z up.
+///
+/// # Arguments
+///
+/// * `direction` - direction the sound is arriving from
+/// * `order` - ambisonic order
+///
+pub fn encode(direction: &Vector3<f32>, order: u32) -> Vec<f32> {
+  let mut gains = vec![0_f32; channel_count(order)];
+  let d = direction.normalize();
+  let azimuth = f64::atan2(d.y as f64, d.x as f64);
+  let sin_elevation = (d.z as f64).clamp(-1.0, 1.0);
+
+  for l in 0..=order {
+    for m in -(l as i32)..=(l as i32) {
+      let abs_m = m.unsigned_abs();
+      let legendre = sn3d(l, abs_m) * associated_legendre(l, abs_m, sin_elevation);
+      let angular = if m >= 0 {
+        f64::cos(abs_m as f64 * azimuth)
+      } else {
+        f64::sin(abs_m as f64 * azimuth)
+      };
+      gains[acn(l, m)] = (legendre * angular) as f32;
+    }
+  }
+  gains
+}
@@ -0,0 +1,196 @@
+use crate::signals::resample::resample;
+use nalgebra::{Vector3, vector};
+use std::error::Error;
+
+/// How an HRIR is chosen for a direction that was not measured
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum HrirInterpolation {
+  /// use the closest measured direction
+  #[default]
+  Nearest,
+  /// blend the three closest measured directions, weighted by their angular distance
+  Interpolated,
+}
+
+/// A set of head-related impulse responses measured over a sphere of directions
+#[derive(Debug, Clone)]
+pub struct HrirSet {
+  pub sample_rate: u32,
+  /// unit vectors in the listener frame (x forward, y left, z up)
+  pub directions: Vec<Vector3<f32>>,
+  pub left: Vec<Vec<f32>>,
+  pub right: Vec<Vec<f32>>,
+}
+
+/// Converts a spherical coordinate (azimuth and elevation in degrees, counter-clockwise from the front) to a unit vector
+pub fn spherical_to_cartesian(azimuth: f32, elevation: f32) -> Vector3<f32> {
+  let az = azimuth.to_radians();
+  let el = elevation.to_radians();
+  vector![f32::cos(el) * f32::cos(az), f32::cos(el) * f32::sin(az), f32::sin(el)]
+}
+
+impl HrirSet {
+  pub fn new(sample_rate: u32, directions: Vec<Vector3<f32>>, left: Vec<Vec<f32>>, right: Vec<Vec<f32>>) -> Self {
+    Self {
+      sample_rate,
+      directions: directions.iter().map(|d| d.normalize()).collect(),
+      left,
+      right,
+    }
+  }
+
+  /// Loads an HRIR set from a SOFA file following the `SimpleFreeFieldHRIR` convention
+  #[cfg(feature = "sofa")]
+  pub fn from_sofa(file_name: &str) -> Result<HrirSet, Box<dyn Error>> {
+    let file = netcdf::open(file_name)?;
+
+    let sample_rate_var = file.variable("Data.SamplingRate").ok_or("missing Data.SamplingRate")?;
+    let sample_rate = sample_rate_var.get_values::<f64, _>(..)?[0] as u32;
+
+    let ir_var = file.variable("Data.IR").ok_or("missing Data.IR")?;
+    let dims: Vec<usize> = ir_var.dimensions().iter().map(|d| d.len()).collect();
+    if dims.len() != 3 || dims[1] != 2 {
+      return Err("Data.IR must have the dimensions [M, 2, N]".into());
+    }
+    let (measurements, taps) = (dims[0], dims[2]);
+    if measurements == 0 || taps == 0 {
+      return Err("Data.IR has no measurements or empty impulse responses".into());
+    }
+    let ir = ir_var.get_values::<f64, _>(..)?;
+
+    let position_var = file.variable("SourcePosition").ok_or("missing SourcePosition")?;
+    let spherical = match position_var.attribute_value("Type") {
+      Some(Ok(netcdf::AttributeValue::Str(kind))) => kind.to_lowercase() != "cartesian",
+      _ => true,
+    };
+    let positions = position_var.get_values::<f64, _>(..)?;
+    // positions are either given per measurement or once for all of them
+    let position_stride = if positions.len() >= measurements * 3 { 3 } else { 0 };
+
+    let mut directions = Vec::with_capacity(measurements);
+    let mut left = Vec::with_capacity(measurements);
+    let mut right = Vec::with_capacity(measurements);
+    for m in 0..measurements {
+      let p = &positions[m * position_stride..m * position_stride + 3];
+      directions.push(if spherical {
+        spherical_to_cartesian(p[0] as f32, p[1] as f32)
+      } else {
+        vector![p[0] as f32, p[1] as f32, p[2] as f32]
+      });
+      let offset = m * 2 * taps;
+      left.push(ir[offset..offset + taps].iter().map(|x| *x as f32).collect());
+      right.push(ir[offset + taps..offset + 2 * taps].iter().map(|x| *x as f32).collect());
+    }
+
+    Ok(HrirSet::new(sample_rate, directions, left, right))
+  }
+
+  /// Loads an HRIR set from a SOFA file following the `SimpleFreeFieldHRIR` convention
+  #[cfg(not(feature = "sofa"))]
+  pub fn from_sofa(_file_name: &str) -> Result<HrirSet, Box<dyn Error>> {
+    Err("raya was built without SOFA support (enable the `sofa` feature)".into())
+  }
+
+  /// Returns a copy of the set resampled to a new sample rate
+  pub fn resampled(&self, sample_rate: u32) -> HrirSet {
+    HrirSet {
+      sample_rate,
+      directions: self.directions.clone(),
+      left: self.left.iter().map(|ir| resample(ir, self.sample_rate, sample_rate)).collect(),
+      right: self.right.iter().map(|ir| resample(ir, self.sample_rate, sample_rate)).collect(),
+    }
+  }
+
+  /// Returns the measurement indices and weights used to render a direction, none if the set
+  /// has no measurements
+  pub fn weights(&self, direction: &Vector3<f32>, interpolation: HrirInterpolation) -> Vec<(usize, f32)> {
+    if self.directions.is_empty() {
+      return Vec::new();
+    }
+    let d = direction.normalize();
+    // sort the measured directions by their angular distance to the requested direction
+    let mut angles: Vec<(usize, f32)> = self.directions.iter()
+      .enumerate()
+      .map(|(i, m)| (i, f32::acos(m.dot(&d).clamp(-1.0, 1.0))))
+      .collect();
+    angles.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+    match interpolation {
+      HrirInterpolation::Nearest => vec![(angles[0].0, 1.0)],
+      HrirInterpolation::Interpolated => {
+        if angles[0].1 < 1e-4 {
+          return vec![(angles[0].0, 1.0)];
+        }
+        let nearest = &angles[..usize::min(3, angles.len())];
+        let total: f32 = nearest.iter().map(|(_, angle)| 1.0 / angle).sum();
+        nearest.iter().map(|(i, angle)| (*i, (1.0 / angle) / total)).collect()
+      }
+    }
+  }
+
+  /// Returns the left and right impulse responses for a direction in the listener frame
+  pub fn hrir(&self, direction: &Vector3<f32>, interpolation: HrirInterpolation) -> [Vec<f32>; 2] {
+    let taps = self.left.iter().map(|ir| ir.len()).max().unwrap_or(0);
+    let mut left = vec![0_f32; taps];
+    let mut right = vec![0_f32; taps];
+    for (i, weight) in self.weights(direction, interpolation) {
+      for (j, x) in self.left[i].iter().enumerate() {
+        left[j] += x * weight;
+      }
+      for (j, x) in self.right[i].iter().enumerate() {
+        right[j] += x * weight;
+      }
+    }
+    [left, right]
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A set measured in the six directions along the axes, whose left impulse response is a
+  /// single sample holding the index of the direction
+  fn axes() -> HrirSet {
+    let directions = vec![
+      vector![1.0, 0.0, 0.0],
+      vector![-1.0, 0.0, 0.0],
+      vector![0.0, 1.0, 0.0],
+      vector![0.0, -1.0, 0.0],
+      vector![0.0, 0.0, 1.0],
+      vector![0.0, 0.0, -1.0],
+    ];
+    let left = (0..6).map(|i| vec![i as f32]).collect();
+    HrirSet::new(48000, directions, left, vec![vec![1.0]; 6])
+  }
+
+  #[test]
+  fn nearest_uses_the_closest_direction() {
+    let set = axes();
+    assert_eq!(set.weights(&vector![0.2, 0.9, 0.1], HrirInterpolation::Nearest), vec![(2, 1.0)]);
+    assert_eq!(set.hrir(&vector![0.0, 0.0, -3.0], HrirInterpolation::Nearest), [vec![5.0], vec![1.0]]);
+  }
+
+  #[test]
+  fn interpolated_weights_sum_to_one() {
+    let set = axes();
+    let weights = set.weights(&vector![1.0, 2.0, 3.0], HrirInterpolation::Interpolated);
+    // the three nearest directions, the closest with the largest weight
+    let indices: Vec<usize> = weights.iter().map(|(i, _)| *i).collect();
+    assert_eq!(indices, vec![4, 2, 0]);
+    assert!(weights[0].1 > weights[1].1 && weights[1].1 > weights[2].1);
+    assert!((weights.iter().map(|(_, weight)| weight).sum::<f32>() - 1.0).abs() < 1e-6);
+    // the right impulse responses are all the same, so blending keeps them
+    assert!((set.hrir(&vector![1.0, 2.0, 3.0], HrirInterpolation::Interpolated)[1][0] - 1.0).abs() < 1e-6);
+
+    // a measured direction is used as it is
+    assert_eq!(set.weights(&vector![0.0, -2.0, 0.0], HrirInterpolation::Interpolated), vec![(3, 1.0)]);
+  }
+
+  #[test]
+  fn empty_set_has_no_weights() {
+    let set = HrirSet::new(48000, Vec::new(), Vec::new(), Vec::new());
+    assert!(set.weights(&vector![1.0, 0.0, 0.0], HrirInterpolation::Interpolated).is_empty());
+    assert_eq!(set.hrir(&vector![1.0, 0.0, 0.0], HrirInterpolation::Nearest), [Vec::<f32>::new(), Vec::new()]);
+  }
+}
@@ -0,0 +1,131 @@
+use rustfft::{FftPlanner, num_complex::Complex};
+
+/// Convolves a signal with an impulse response using uniformly partitioned FFT convolution
+///
+/// The impulse response is split into partitions of `block_size` samples whose spectra are
+/// combined with a delay line of input block spectra, so long impulse responses are processed
+/// with short FFTs. The result has `signal.len() + impulse_response.len() - 1` samples.
+///
+/// # Arguments
+///
+/// * `signal` - input samples
+/// * `impulse_response` - impulse response samples
+/// * `block_size` - partition length in samples
+///
+pub fn convolve(signal: &[f32], impulse_response: &[f32], block_size: usize) -> Vec<f32> {
+  if signal.is_empty() || impulse_response.is_empty() {
+    return Vec::new();
+  }
+  let block_size = usize::max(1, block_size);
+  let fft_size = 2 * block_size;
+  let mut planner = FftPlanner::<f32>::new();
+  let fft = planner.plan_fft_forward(fft_size);
+  let inv_fft = planner.plan_fft_inverse(fft_size);
+
+  // spectra of the zero-padded impulse response partitions
+  let partitions: Vec<Vec<Complex<f32>>> = impulse_response
+    .chunks(block_size)
+    .map(|chunk| {
+      let mut buffer = vec![Complex { re: 0.0, im: 0.0 }; fft_size];
+      for (i, x) in chunk.iter().enumerate() {
+        buffer[i].re = *x;
+      }
+      fft.process(&mut buffer);
+      buffer
+    })
+    .collect();
+
+  let output_length = signal.len() + impulse_response.len() - 1;
+  let blocks = output_length.div_ceil(block_size);
+  let mut output = vec![0_f32; blocks * block_size + block_size];
+
+  // frequency domain delay line, the most recent input block spectrum is at `head`
+  let mut delay_line = vec![vec![Complex { re: 0.0, im: 0.0 }; fft_size]; partitions.len()];
+  let mut head = 0;
+  let mut accumulator = vec![Complex { re: 0.0, im: 0.0 }; fft_size];
+
+  for block in 0..blocks {
+    head = (head + delay_line.len() - 1) % delay_line.len();
+    let input = &mut delay_line[head];
+    for (i, x) in input.iter_mut().enumerate() {
+      let n = block * block_size + i;
+      x.re = if i < block_size && n < signal.len() { signal[n] } else { 0.0 };
+      x.im = 0.0;
+    }
+    fft.process(input);
+
+    for x in accumulator.iter_mut() {
+      *x = Complex { re: 0.0, im: 0.0 };
+    }
+    for (p, partition) in partitions.iter().enumerate() {
+      let spectrum = &delay_line[(head + p) % delay_line.len()];
+      for j in 0..fft_size {
+        accumulator[j] += spectrum[j] * partition[j];
+      }
+    }
+    inv_fft.process(&mut accumulator);
+
+    // overlap-add, rustfft does not normalize the inverse transform
+    let offset = block * block_size;
+    for (j, x) in accumulator.iter().enumerate() {
+      output[offset + j] += x.re / fft_size as f32;
+    }
+  }
+
+  output.truncate(output_length);
+  output
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn direct(signal: &[f32], impulse_response: &[f32]) -> Vec<f32> {
+    let mut output = vec![0_f32; signal.len() + impulse_response.len() - 1];
+    for (i, x) in signal.iter().enumerate() {
+      for (j, h) in impulse_response.iter().enumerate() {
+        output[i + j] += x * h;
+      }
+    }
+    output
+  }
+
+  fn noise(length: usize, seed: u32) -> Vec<f32> {
+    // a linear congruential generator, so the test is repeatable
+    let mut state = seed;
+    (0..length)
+      .map(|_| {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        (state >> 8) as f32 / (1 << 23) as f32 - 1.0
+      })
+      .collect()
+  }
+
+  fn assert_matches_direct(signal: &[f32], impulse_response: &[f32], block_size: usize) {
+    let expected = direct(signal, impulse_response);
+    let output = convolve(signal, impulse_response, block_size);
+    assert_eq!(output.len(), expected.len());
+    for (i, (y, e)) in output.iter().zip(expected.iter()).enumerate() {
+      assert!((y - e).abs() < 1e-4, "sample {}: {} instead of {}", i, y, e);
+    }
+  }
+
+  #[test]
+  fn impulse_response_longer_than_a_partition() {
+    assert_matches_direct(&noise(300, 1), &noise(1000, 2), 64);
+    // the impulse response ends in the middle of a partition
+    assert_matches_direct(&noise(513, 3), &noise(130, 4), 32);
+  }
+
+  #[test]
+  fn signal_shorter_than_a_block() {
+    assert_matches_direct(&noise(10, 5), &noise(200, 6), 64);
+    assert_matches_direct(&noise(1, 7), &noise(5, 8), 256);
+  }
+
+  #[test]
+  fn empty_input_gives_no_output() {
+    assert!(convolve(&[], &[1.0], 64).is_empty());
+    assert!(convolve(&[1.0], &[], 64).is_empty());
+  }
+}
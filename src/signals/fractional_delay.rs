@@ -0,0 +1,105 @@
+use std::f32::consts::PI;
+
+/// Number of taps on each side of the windowed sinc interpolator
+const SINC_HALF_TAPS: usize = 8;
+
+/// How impulses are placed between the samples of a signal
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FractionalDelay {
+  /// On the sample before the impulse's time
+  #[default]
+  None,
+  /// Spread over four samples with third order Lagrange interpolation
+  Lagrange,
+  /// Spread over 16 samples with a Hann windowed sinc
+  WindowedSinc,
+}
+
+impl FractionalDelay {
+  /// Returns the taps that place an impulse a `fraction` of a sample after a sample, and the
+  /// offset of the first tap from that sample
+  ///
+  /// # Arguments
+  ///
+  /// * `fraction` - the delay after the sample, from 0 to 1
+  ///
+  pub fn taps(&self, fraction: f32) -> (isize, Vec<f32>) {
+    match self {
+      FractionalDelay::None => (0, vec![1.0]),
+      FractionalDelay::Lagrange => {
+        // the taps are at -1, 0, 1 and 2, so the delay from the first tap is between 1 and 2
+        let delay = fraction + 1.0;
+        let taps = (0..4)
+          .map(|k| {
+            (0..4)
+              .filter(|m| *m != k)
+              .map(|m| (delay - m as f32) / (k as f32 - m as f32))
+              .product()
+          })
+          .collect();
+        (-1, taps)
+      }
+      FractionalDelay::WindowedSinc => {
+        let half = SINC_HALF_TAPS as isize;
+        let taps: Vec<f32> = (1 - half..=half)
+          .map(|k| {
+            let x = k as f32 - fraction;
+            let sinc = if x.abs() < 1e-6 { 1.0 } else { f32::sin(PI * x) / (PI * x) };
+            let window = 0.5 + 0.5 * f32::cos(PI * x / half as f32);
+            sinc * window
+          })
+          .collect();
+        // normalize so the impulse keeps its low frequency gain
+        let sum: f32 = taps.iter().sum();
+        (1 - half, taps.iter().map(|tap| tap / sum).collect())
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FRACTIONS: [f32; 6] = [0.0, 0.1, 0.25, 0.5, 0.75, 0.9];
+
+  /// The sum of the taps and the delay of their centroid from the sample
+  fn gain_and_delay(delay: FractionalDelay, fraction: f32) -> (f32, f32) {
+    let (offset, taps) = delay.taps(fraction);
+    let gain: f32 = taps.iter().sum();
+    let centroid: f32 = taps.iter().enumerate().map(|(i, tap)| (offset + i as isize) as f32 * tap).sum();
+    (gain, centroid / gain)
+  }
+
+  #[test]
+  fn lagrange_taps_keep_gain_and_delay() {
+    for fraction in FRACTIONS {
+      let (gain, delay) = gain_and_delay(FractionalDelay::Lagrange, fraction);
+      assert!((gain - 1.0).abs() < 1e-5, "gain {} at {}", gain, fraction);
+      assert!((delay - fraction).abs() < 1e-5, "delay {} at {}", delay, fraction);
+    }
+    assert_eq!(FractionalDelay::Lagrange.taps(0.0), (-1, vec![0.0, 1.0, 0.0, 0.0]));
+  }
+
+  #[test]
+  fn windowed_sinc_taps_keep_gain_and_delay() {
+    for fraction in FRACTIONS {
+      let (offset, taps) = FractionalDelay::WindowedSinc.taps(fraction);
+      assert_eq!((offset, taps.len()), (1 - SINC_HALF_TAPS as isize, 2 * SINC_HALF_TAPS));
+      let (gain, delay) = gain_and_delay(FractionalDelay::WindowedSinc, fraction);
+      assert!((gain - 1.0).abs() < 1e-5, "gain {} at {}", gain, fraction);
+      assert!((delay - fraction).abs() < 0.02, "delay {} at {}", delay, fraction);
+    }
+    // without a fraction the impulse stays on its sample
+    let (offset, taps) = FractionalDelay::WindowedSinc.taps(0.0);
+    for (i, tap) in taps.iter().enumerate() {
+      let expected = if offset + i as isize == 0 { 1.0 } else { 0.0 };
+      assert!((tap - expected).abs() < 1e-6);
+    }
+  }
+
+  #[test]
+  fn no_fractional_delay_keeps_the_sample() {
+    assert_eq!(FractionalDelay::None.taps(0.7), (0, vec![1.0]));
+  }
+}
@@ -1 +1,6 @@
-pub mod reconstruction_filter;
\ No newline at end of file
+pub mod reconstruction_filter;
+pub mod ambisonics;
+pub mod binaural;
+pub mod convolution;
+pub mod resample;
+pub mod fractional_delay;
\ No newline at end of file
@@ -1,17 +1,13 @@
 use std::f32::consts::PI;
-use nalgebra::Normed;
-use rustfft::{FftPlanner, num_complex::Complex};
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+use std::sync::Arc;
+use crate::utils::bands::band_edges;
 
 fn max_width_factor(r: [f32; 2], step: f32) -> f32 {
   let base = f32::powf(f32::max(r[0], r[1]) / f32::min(r[0], r[1]), step);
   (base - 1.0) / (base + 1.0)
 }
 
-fn width_factor(r: [f32; 2], bands: f32, overlap: f32) -> f32 {
-  assert!(overlap >= 0.0 && overlap <= 1.0, "Overlap must be in the range 0-1.");
-  max_width_factor(r, 1.0 / bands) * overlap
-}
-
 
 // p = relative frequency
 // P = relative width
@@ -48,17 +44,6 @@ fn upper_band_edge(relative_frequency: f32, relative_width: f32, l: f32) -> f32
     return f32::powf(f32::cos(PI * band_edge_impl(relative_frequency, relative_width, l) / 2.0), 2.0);
 }
 
-fn band_edge_frequency(band: f32, bands: f32, r: [f32; 2]) -> f32 {
-    let r0 = f32::min(r[0], r[1]);
-    let r1 = f32::max(r[0], r[1]);
-    return r0 * f32::powf(r1 / r0, band / bands);
-}
-
-#[allow(dead_code)]
-fn band_centre_frequency(band: f32, bands: f32, r: [f32; 2]) -> f32 {
-    return band_edge_frequency(band * 2.0 + 1.0, bands * 2.0, r);
-}
-
 fn compute_bandpass_magnitude(frequency: f32, r: [f32; 2], width_factor: f32, l: f32) -> f32 {
   assert!(width_factor >= 0.0 && width_factor <= 1.0, "width_factor must be in the range 0-1.");
     return compute_lopass_magnitude(frequency, f32::max(r[0], r[1]), width_factor, l) * compute_hipass_magnitude(frequency, f32::min(r[0], r[1]), width_factor, l);
@@ -107,67 +92,297 @@ fn dirac_delta(length: u32, offset: u32) -> Vec<f32> {
 
 
 
-/// 
-/// Perfect reconstruction filter for banded signals
-/// @param samples banded signals
-/// @returns 
-/// 
-pub fn filter_signals(samples: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
-    let mut planner = FftPlanner::<f32>::new();
-    let fft = planner.plan_fft_forward(samples[0].len());
-    let mut inv_planner = FftPlanner::<f32>::new();
-    let inv_fft = inv_planner.plan_fft_inverse(samples[0].len());
-
-    let bands = samples.len();
-    let samplerate = 44100;
-    let minf = 63.0;
-    let maxf = 16000.0;
-    let len = bands + 1;
-    
-    let mut band_edges: Vec<f32> = vec![0.0; len];
-    for band in 0..len {
-      band_edges[band] = band_edge_frequency(band as f32, bands as f32, [minf, maxf]);
-    }
+/// Number of taps of the linear and minimum phase band filters, odd so the latency of the linear
+/// phase filters is a whole number of samples
+const FILTER_TAPS: usize = 8191;
+/// Size of the frequency grid the band filters are designed on
+const DESIGN_LENGTH: usize = 16384;
+/// Size of the frequency grid of the minimum phase design, finer so the cepstrum does not alias
+const CEPSTRUM_LENGTH: usize = 65536;
+/// The magnitude the stop bands are raised to before taking their logarithm (-100 dB)
+const MIN_MAGNITUDE: f32 = 1e-5;
 
-    let lower_edges = band_edges.get_mut(0..(len-1)).expect("to slice the array").to_vec();
-    let upper_edges = band_edges.get_mut(1..(len)).expect("to slice the array").to_vec();
-    
-    let wf = width_factor([minf, maxf], bands as f32, 1.0);
-    
-    let mut frequencies: Vec<f32> = vec![0.0; samples[0].len()];
-    for i in 0..frequencies.len() {
-      frequencies[i] = (i as f32) * (samplerate as f32) / (samples[0].len() as f32);
-    }
+/// The phase of the band filters that split the impulse response into its bands
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FilterBank {
+  /// The bands' magnitude responses without any delay; their ringing spreads to both sides of an
+  /// arrival
+  ZeroPhase,
+  /// Windowed FIR filters with a constant delay, which is removed after filtering, so the bands add
+  /// up to the input
+  #[default]
+  LinearPhase,
+  /// Causal filters that only ring after an arrival, at the cost of the bands' phases differing
+  /// where they overlap
+  MinimumPhase,
+}
 
-    let mut filters: Vec<Vec<f32>> = Vec::new();
-    for i in 0..bands {
-        let mut filter: Vec<f32> = frequencies.iter().map(|f| compute_bandpass_magnitude(*f, [lower_edges[i], upper_edges[i]], wf, 0.0)).collect();
-        let half_len = f32::trunc(filter.len() as f32 / 2.0) as usize;
-        let mut c = half_len;
-        for j in (half_len+1)..(filter.len()) {
-          filter[j] = filter[c];
-          c-=1;
-        }
-        filters.push(filter)
+/// The impulse response of one band filter
+#[derive(Debug, Clone)]
+pub struct FilterResponse {
+  /// centre frequency of the band
+  pub frequency: f32,
+  pub impulse_response: Vec<f32>,
+  /// the delay in samples that `filter_signals` removes from the filtered band
+  pub latency: usize,
+}
+
+impl FilterResponse {
+  /// The filter's magnitude and phase (in radians, without the latency) at a frequency
+  pub fn response_at(&self, frequency: f32, samplerate: f32) -> (f32, f32) {
+    let omega = 2.0 * PI * frequency / samplerate;
+    let response: Complex<f32> = self.impulse_response
+      .iter()
+      .enumerate()
+      .map(|(n, tap)| Complex::from_polar(*tap, -omega * (n as f32 - self.latency as f32)))
+      .sum();
+    (response.norm(), response.arg())
+  }
+}
+
+/// The smallest FFT length of at least `length` whose only prime factors are 2, 3 and 5
+fn fft_length(length: usize) -> usize {
+  let mut best = length.next_power_of_two();
+  let mut fives = 1;
+  while fives < best {
+    let mut threes = fives;
+    while threes < best {
+      let mut candidate = threes;
+      while candidate < length {
+        candidate *= 2;
+      }
+      best = usize::min(best, candidate);
+      threes *= 3;
     }
+    fives *= 5;
+  }
+  best
+}
+
+/// The magnitude responses of the bands on a frequency grid of `length` bins, mirrored around
+/// the Nyquist frequency so their impulse responses are real
+fn band_magnitudes(frequencies: &[f32], samplerate: f32, length: usize) -> Vec<Vec<f32>> {
+  let edges = band_edges(frequencies);
+  // the widest transitions that keep the two edges of every band apart
+  let wf = edges.windows(2).map(|pair| max_width_factor([pair[0], pair[1]], 1.0)).fold(1.0, f32::min);
+  edges
+    .windows(2)
+    .map(|pair| {
+      (0..length)
+        .map(|j| {
+          let bin = usize::min(j, length - j);
+          let frequency = bin as f32 * samplerate / length as f32;
+          compute_bandpass_magnitude(frequency, [pair[0], pair[1]], wf, 0.0)
+        })
+        .collect()
+    })
+    .collect()
+}
+
+/// The zero phase impulse response of a magnitude response, rotated so it starts at the middle
+/// of the grid
+fn zero_phase_response(magnitude: &[f32], planner: &mut FftPlanner<f32>) -> Vec<f32> {
+  let length = magnitude.len();
+  let mut response: Vec<Complex<f32>> = magnitude.iter().map(|m| Complex { re: *m, im: 0.0 }).collect();
+  planner.plan_fft_inverse(length).process(&mut response);
+  (0..length).map(|k| response[(k + length / 2) % length].re / length as f32).collect()
+}
+
+/// The minimum phase impulse response of a magnitude response, found by folding its real
+/// cepstrum onto positive quefrencies
+fn minimum_phase_response(magnitude: &[f32], planner: &mut FftPlanner<f32>) -> Vec<f32> {
+  let length = magnitude.len();
+  let fft = planner.plan_fft_forward(length);
+  let inv_fft = planner.plan_fft_inverse(length);
+  let mut cepstrum: Vec<Complex<f32>> = magnitude
+    .iter()
+    .map(|m| Complex { re: f32::max(*m, MIN_MAGNITUDE).ln(), im: 0.0 })
+    .collect();
+  inv_fft.process(&mut cepstrum);
+  for (n, c) in cepstrum.iter_mut().enumerate() {
+    let fold = match n {
+      0 => 1.0,
+      n if n < length / 2 => 2.0,
+      n if n == length / 2 => 1.0,
+      _ => 0.0,
+    };
+    *c *= fold / length as f32;
+  }
+  fft.process(&mut cepstrum);
+  let mut response: Vec<Complex<f32>> = cepstrum.iter().map(|c| c.exp()).collect();
+  inv_fft.process(&mut response);
+  response.iter().map(|c| c.re / length as f32).collect()
+}
 
-    let mut filtered_samples: Vec<Vec<f32>> = Vec::new();
+/// Designs the band filters of a filter bank for bands with the given centre frequencies
+///
+/// The linear and minimum phase filters are `FILTER_TAPS` long and faded out with a Hann window,
+/// the zero phase filters are the bands' magnitude responses sampled on `DESIGN_LENGTH` bins.
+pub fn filter_responses(frequencies: &[f32], samplerate: f32, filter_bank: FilterBank) -> Vec<FilterResponse> {
+  let mut planner = FftPlanner::<f32>::new();
+  let design_length = if filter_bank == FilterBank::MinimumPhase { CEPSTRUM_LENGTH } else { DESIGN_LENGTH };
+  band_magnitudes(frequencies, samplerate, design_length)
+    .iter()
+    .zip(frequencies.iter())
+    .map(|(magnitude, frequency)| {
+      let (impulse_response, latency) = match filter_bank {
+        FilterBank::ZeroPhase => (zero_phase_response(magnitude, &mut planner), DESIGN_LENGTH / 2),
+        FilterBank::LinearPhase => {
+          let response = zero_phase_response(magnitude, &mut planner);
+          let half_taps = FILTER_TAPS / 2;
+          let taps = (0..FILTER_TAPS)
+            .map(|k| {
+              let window = 0.5 - 0.5 * f32::cos(2.0 * PI * (k + 1) as f32 / (FILTER_TAPS + 1) as f32);
+              response[DESIGN_LENGTH / 2 - half_taps + k] * window
+            })
+            .collect();
+          (taps, half_taps)
+        }
+        FilterBank::MinimumPhase => {
+          let response = minimum_phase_response(magnitude, &mut planner);
+          // fade out the last quarter with the falling half of a Hann window
+          let fade = FILTER_TAPS / 4;
+          let taps = (0..FILTER_TAPS)
+            .map(|k| {
+              let position = (k + fade + 1).saturating_sub(FILTER_TAPS) as f32 / (fade + 1) as f32;
+              response[k] * (0.5 + 0.5 * f32::cos(PI * position))
+            })
+            .collect();
+          (taps, 0)
+        }
+      };
+      FilterResponse { frequency: *frequency, impulse_response, latency }
+    })
+    .collect()
+}
 
-    let mut complex_samples: Vec<Vec<Complex<f32>>> = samples.iter().map(|arr| arr.iter().map(|x| Complex{ re: *x, im: 0.0 }).collect::<Vec<Complex<f32>>>()).collect();
+/// The spectra of the band filters for signals of one length, designed once and applied to the
+/// bands of every channel of an impulse response
+#[derive(Clone)]
+pub struct BandFilters {
+  /// the length of the signals the filters apply to
+  length: usize,
+  /// the length the signals are zero padded to for the fast convolution
+  padded_length: usize,
+  spectra: Vec<Vec<Complex<f32>>>,
+  latencies: Vec<usize>,
+  fft: Arc<dyn Fft<f32>>,
+  inv_fft: Arc<dyn Fft<f32>>,
+}
 
-    for i in 0..complex_samples.len() {
-      // Perform a forward FFT of size 1234
+impl BandFilters {
+  /// Designs the band filters for signals of `length` samples
+  ///
+  /// # Arguments
+  ///
+  /// * `frequencies` - centre frequencies of the bands
+  /// * `samplerate` - sample rate of the signals
+  /// * `filter_bank` - the phase of the band filters
+  /// * `length` - the length of the signals that will be filtered
+  ///
+  pub fn new(frequencies: &[f32], samplerate: f32, filter_bank: FilterBank, length: usize) -> BandFilters {
+    let filters = filter_responses(frequencies, samplerate, filter_bank);
+    let taps = filters.iter().map(|filter| filter.impulse_response.len()).max().unwrap_or(1);
 
-      fft.process(&mut complex_samples[i]);
+    // zero padding to the length of the full convolution keeps it from wrapping around
+    let padded_length = fft_length(length + taps - 1);
+    let mut planner = FftPlanner::<f32>::new();
+    let fft = planner.plan_fft_forward(padded_length);
+    let inv_fft = planner.plan_fft_inverse(padded_length);
+    let spectra = filters
+      .iter()
+      .map(|filter| {
+        let mut kernel: Vec<Complex<f32>> = vec![Complex { re: 0.0, im: 0.0 }; padded_length];
+        for (k, tap) in kernel.iter_mut().zip(filter.impulse_response.iter()) {
+          k.re = *tap;
+        }
+        fft.process(&mut kernel);
+        kernel
+      })
+      .collect();
+    let latencies = filters.iter().map(|filter| filter.latency).collect();
+    BandFilters { length, padded_length, spectra, latencies, fft, inv_fft }
+  }
 
-        for j in 0..complex_samples[i].len() {
-          complex_samples[i][j].scale_mut(filters[i][j]);
+  /// Filters banded signals, one per band of the filters and as long as the filters were
+  /// designed for, and returns the filtered bands aligned with the input
+  pub fn filter(&self, samples: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    assert_eq!(samples.len(), self.spectra.len(), "There must be a signal for every band.");
+    let (length, padded_length) = (self.length, self.padded_length);
+    samples
+      .iter()
+      .zip(self.spectra.iter().zip(self.latencies.iter()))
+      .map(|(band, (spectrum, latency))| {
+        assert_eq!(band.len(), length, "The signals must have the length the filters were designed for.");
+        let mut signal: Vec<Complex<f32>> = vec![Complex { re: 0.0, im: 0.0 }; padded_length];
+        for (x, sample) in signal.iter_mut().zip(band.iter()) {
+          x.re = *sample;
         }
-      
-      inv_fft.process(&mut complex_samples[i]);
-      filtered_samples.push(complex_samples[i].iter().map(|c| c.re).collect());
-    }
+        self.fft.process(&mut signal);
+        for (x, k) in signal.iter_mut().zip(spectrum.iter()) {
+          *x *= k;
+        }
+        self.inv_fft.process(&mut signal);
+        // remove the filter's latency
+        signal[*latency..*latency + length].iter().map(|c| c.re / padded_length as f32).collect()
+      })
+      .collect()
+  }
+}
 
-    return filtered_samples;
+/// 
+/// Reconstruction filter for banded signals
+/// @param samples banded signals, one per frequency
+/// @param frequencies centre frequencies of the bands
+/// @param samplerate sample rate of the signals
+/// @param filter_bank the phase of the band filters
+/// @returns the filtered bands, as long as the input and aligned with it
+/// 
+pub fn filter_signals(samples: Vec<Vec<f32>>, frequencies: &[f32], samplerate: f32, filter_bank: FilterBank) -> Vec<Vec<f32>> {
+    assert_eq!(samples.len(), frequencies.len(), "There must be a signal for every band.");
+    if samples.is_empty() {
+      return samples;
+    }
+    BandFilters::new(frequencies, samplerate, filter_bank, samples[0].len()).filter(&samples)
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::bands::octave;
+
+  #[test]
+  fn linear_phase_bands_add_up_to_a_delayed_impulse() {
+    let (frequencies, samplerate) = (octave(63.0, 8000.0), 44100.0);
+    let filters = filter_responses(&frequencies, samplerate, FilterBank::LinearPhase);
+    let mut sum = FilterResponse { frequency: 0.0, impulse_response: vec![0.0; FILTER_TAPS], latency: FILTER_TAPS / 2 };
+    for filter in filters.iter() {
+      assert_eq!(filter.latency, sum.latency);
+      for (s, tap) in sum.impulse_response.iter_mut().zip(filter.impulse_response.iter()) {
+        *s += tap;
+      }
+    }
+    // within the bands the sum passes every frequency unchanged but for the latency
+    let mut frequency = 63.0;
+    while frequency <= 8000.0 {
+      let (magnitude, phase) = sum.response_at(frequency, samplerate);
+      assert!((magnitude - 1.0).abs() < 0.005 && phase.abs() < 1e-4, "{} Hz: magnitude {}, phase {}", frequency, magnitude, phase);
+      frequency *= f32::powf(2.0, 1.0 / 12.0);
+    }
+  }
+
+  #[test]
+  fn linear_phase_bands_pass_their_centre_frequency() {
+    let (frequencies, samplerate) = (octave(63.0, 8000.0), 44100.0);
+    for filter in filter_responses(&frequencies, samplerate, FilterBank::LinearPhase).iter() {
+      let (magnitude, phase) = filter.response_at(filter.frequency, samplerate);
+      // the transitions to the neighbouring bands take a little off the centre
+      assert!((magnitude - 1.0).abs() < 0.025 && phase.abs() < 1e-4, "{} Hz: magnitude {}, phase {}", filter.frequency, magnitude, phase);
+    }
+  }
+
+  #[test]
+  fn no_bands_filter_to_nothing() {
+    assert!(filter_signals(Vec::new(), &[], 44100.0, FilterBank::LinearPhase).is_empty());
+  }
+}
@@ -0,0 +1,93 @@
+use std::f32::consts::PI;
+
+/// number of zero crossings of the interpolation kernel on each side of a sample
+const KERNEL_ZERO_CROSSINGS: f32 = 32.0;
+
+fn sinc(x: f32) -> f32 {
+  if x.abs() < 1e-6 {
+    1.0
+  } else {
+    f32::sin(PI * x) / (PI * x)
+  }
+}
+
+/// Blackman window evaluated at `x` in the range [-1, 1]
+fn blackman(x: f32) -> f32 {
+  if x.abs() >= 1.0 {
+    return 0.0;
+  }
+  let n = (x + 1.0) / 2.0;
+  0.42 - 0.5 * f32::cos(2.0 * PI * n) + 0.08 * f32::cos(4.0 * PI * n)
+}
+
+/// Resamples a signal with a band-limited (windowed-sinc) interpolator
+///
+/// # Arguments
+///
+/// * `signal` - input samples
+/// * `from` - sample rate of the input in Hz
+/// * `to` - desired sample rate in Hz
+///
+pub fn resample(signal: &[f32], from: u32, to: u32) -> Vec<f32> {
+  if from == to || signal.is_empty() {
+    return signal.to_vec();
+  }
+  let ratio = to as f64 / from as f64;
+  // when downsampling the kernel is stretched to low-pass below the new nyquist frequency
+  let cutoff = f32::min(1.0, ratio as f32);
+  let half_width = KERNEL_ZERO_CROSSINGS / cutoff;
+  let output_length = f64::ceil(signal.len() as f64 * ratio) as usize;
+
+  let mut output = vec![0_f32; output_length];
+  for (n, y) in output.iter_mut().enumerate() {
+    let position = n as f64 / ratio;
+    let centre = position.floor() as i64;
+    let fraction = (position - centre as f64) as f32;
+    let first = i64::max(0, centre - half_width.ceil() as i64);
+    let last = i64::min(signal.len() as i64 - 1, centre + half_width.ceil() as i64);
+    let mut sum = 0_f32;
+    for k in first..=last {
+      let x = (k - centre) as f32 - fraction;
+      sum += signal[k as usize] * cutoff * sinc(cutoff * x) * blackman(x / half_width);
+    }
+    *y = sum;
+  }
+  output
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sine(frequency: f32, sample_rate: u32, length: usize) -> Vec<f32> {
+    (0..length).map(|n| f32::sin(2.0 * PI * frequency * n as f32 / sample_rate as f32)).collect()
+  }
+
+  #[test]
+  fn same_rate_keeps_the_signal() {
+    let signal = sine(1000.0, 44100, 100);
+    assert_eq!(resample(&signal, 44100, 44100), signal);
+  }
+
+  #[test]
+  fn resampled_sine_keeps_its_frequency() {
+    for (from, to) in [(44100, 48000), (48000, 44100), (44100, 22050)].iter() {
+      let signal = sine(1000.0, *from, 4410);
+      let output = resample(&signal, *from, *to);
+      assert_eq!(output.len(), f64::ceil(4410.0 * *to as f64 / *from as f64) as usize);
+      let expected = sine(1000.0, *to, output.len());
+      // away from the ends, where the kernel runs out of samples
+      for n in 200..output.len() - 200 {
+        assert!((output[n] - expected[n]).abs() < 1e-3, "{} -> {}: sample {} is {} instead of {}", from, to, n, output[n], expected[n]);
+      }
+    }
+  }
+
+  #[test]
+  fn downsampling_removes_frequencies_above_nyquist() {
+    // 15 kHz is above the Nyquist frequency of 11025 Hz at 22050 Hz
+    let output = resample(&sine(15000.0, 44100, 4410), 44100, 22050);
+    let peak = output[200..output.len() - 200].iter().fold(0_f32, |max, x| max.max(x.abs()));
+    assert!(peak < 0.01, "peak {}", peak);
+  }
+}
@@ -10,8 +10,9 @@ use crate::utils::standard;
 /// # Examples
 ///
 /// ```
+/// use raya::utils::bands::octave;
 /// // get octave band frequencies between 63 and 1000
-/// octave(63.0, 4000.0) // [63.0, 125.0, 250.0, 500.0, 1000.0]
+/// assert_eq!(octave(63.0, 1000.0), vec![63.0, 125.0, 250.0, 500.0, 1000.0]);
 /// ```
 pub fn octave(start: f32, end: f32) -> Vec<f32> {
   standard::WHOLE_OCTAVE.iter().filter_map(|x| {
@@ -34,8 +35,9 @@ pub fn octave(start: f32, end: f32) -> Vec<f32> {
 /// # Examples
 ///
 /// ```
+/// use raya::utils::bands::third_octave;
 /// // get third octave band frequencies between 250 and 800
-/// octave(250.0, 800.0) // [250.0, 315.0, 400.0, 500.0, 630.0, 800.0]
+/// assert_eq!(third_octave(250.0, 800.0), vec![250.0, 315.0, 400.0, 500.0, 630.0, 800.0]);
 /// ```
 pub fn third_octave(start: f32, end: f32) -> Vec<f32> {
   standard::THIRD_OCTAVE.iter().filter_map(|x| {
@@ -46,3 +48,34 @@ pub fn third_octave(start: f32, end: f32) -> Vec<f32> {
     }
   }).collect()
 }
+
+
+/// Returns the edges of bands with the given centre frequencies, one more than there are bands
+///
+/// Neighbouring bands meet at the geometric mean of their centre frequencies, and the outer
+/// edges are as far from the outer centre frequencies as the nearest inner edges.
+///
+/// # Arguments
+///
+/// * `frequencies` - ascending centre frequencies
+///
+/// # Examples
+///
+/// ```
+/// use raya::utils::bands::band_edges;
+/// // the octave band around 1000 Hz reaches from 707 to 1414 Hz
+/// let edges = band_edges(&[500.0, 1000.0, 2000.0]);
+/// assert!((edges[1] - 707.1).abs() < 0.1 && (edges[2] - 1414.2).abs() < 0.1);
+/// ```
+pub fn band_edges(frequencies: &[f32]) -> Vec<f32> {
+  if frequencies.len() < 2 {
+    // a single band is assumed to be an octave wide
+    return frequencies.iter().flat_map(|f| vec![f / f32::sqrt(2.0), f * f32::sqrt(2.0)]).collect();
+  }
+  let mut edges: Vec<f32> = frequencies.windows(2).map(|pair| f32::sqrt(pair[0] * pair[1])).collect();
+  let first = frequencies[0] * frequencies[0] / edges[0];
+  let last = frequencies[frequencies.len() - 1] * frequencies[frequencies.len() - 1] / edges[edges.len() - 1];
+  edges.insert(0, first);
+  edges.push(last);
+  edges
+}